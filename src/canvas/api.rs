@@ -0,0 +1,635 @@
+use super::{
+	BlendMode, Canvas, CanvasError, CanvasSnapshot, CanvasStats, OwnerStats, PixelEvent, PixelPayout, PixelView, RateLimit, RegionPurchase,
+	PriceOverflowPolicy, PriceZone, RegionQuote, Rgba8888, SNAPSHOT_CHUNK_LEN, SnapshotChunk,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+use std::cell::RefCell;
+
+thread_local! {
+	// IMPORT_BUFFER 暂存分段导入的快照数据，直到 import_canvas 整体解码；不跨升级保留
+	static IMPORT_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct OwnedPixels {
+	pub total: u64,             // 该地址持有的像素总数（不受分页影响）
+	pub pixels: Vec<(u32, u32)>, // 当前页的像素坐标，按行主序
+}
+
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct CanvasRevenue {
+	pub project_revenue: u128,              // 项目方累计收入
+	pub owner_revenue: Vec<(String, u128)>, // 各原占有者累计应得收入，按地址排序
+}
+
+/// 带认证的像素：`root` 即 canister 的认证数据，由 `certificate` 证明；
+/// 用 `proof` 从像素的叶子哈希逐层计算应得到 `root`（算法见 `merkle` 模块）
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct CertifiedPixel {
+	pub pixel: PixelView,
+	pub proof: Vec<Vec<u8>>,         // 自底向上每层的兄弟节点哈希
+	pub root: Vec<u8>,               // 画布的 Merkle 根
+	pub certificate: Option<Vec<u8>>, // 子网对认证数据的签名证书，仅 query 调用时存在
+}
+
+/// 读取 (x,y) 处的像素，坐标原点位于左上角
+#[query]
+pub fn get_pixel(x: u32, y: u32) -> Result<PixelView, String> {
+	super::with_canvas(|c| {
+		c.pixel(x as usize, y as usize)
+			.map(|p| p.view(x as usize, y as usize))
+			.map_err(|e| e.to_string())
+	})
+}
+
+/// 画布的 Merkle 根，附带证明它是 canister 认证数据的证书
+#[query]
+pub fn get_canvas_merkle_root() -> (Vec<u8>, Option<Vec<u8>>) {
+	(super::merkle_root().to_vec(), ic_cdk::api::data_certificate())
+}
+
+/// 读取 (x,y) 处的像素及其 Merkle 证明，客户端可据此验证像素未被篡改
+#[query]
+pub fn get_certified_pixel(x: u32, y: u32) -> Result<CertifiedPixel, String> {
+	super::with_canvas(|c| {
+		let pixel = c.pixel(x as usize, y as usize)?.view(x as usize, y as usize);
+		let proof = c.merkle_proof(x as usize, y as usize)?;
+		Ok(CertifiedPixel {
+			pixel,
+			proof: proof.iter().map(|h| h.to_vec()).collect(),
+			root: super::merkle_root().to_vec(),
+			certificate: ic_cdk::api::data_certificate(),
+		})
+	})
+	.map_err(|e: CanvasError| e.to_string())
+}
+
+/// 读取 (x,y) 相邻的像素（行主序）：`diagonal` 为 true 时含对角共 8 邻域，否则为 4 邻域
+#[query]
+pub fn get_neighbors(x: u32, y: u32, diagonal: bool) -> Result<Vec<PixelView>, String> {
+	super::with_canvas(|c| {
+		c.pixel(x as usize, y as usize)?;
+		c.neighbors(x as usize, y as usize, diagonal)
+			.into_iter()
+			.map(|(nx, ny)| c.pixel(nx, ny).map(|p| p.view(nx, ny)))
+			.collect::<Result<Vec<_>, _>>()
+	})
+	.map_err(|e: CanvasError| e.to_string())
+}
+
+/// 读取闭区间矩形 [x0,x1]×[y0,y1] 内的像素（行主序），要求 x0<=x1、y0<=y1 且整个矩形在画布内
+#[query]
+pub fn get_region(x0: u32, y0: u32, x1: u32, y1: u32) -> Result<Vec<PixelView>, String> {
+	super::with_canvas(|c| {
+		c.region(x0 as usize, y0 as usize, x1 as usize, y1 as usize)
+			.map_err(|e| e.to_string())
+	})
+}
+
+/// 为闭区间矩形 [x0,x1]×[y0,y1] 报价：总价、最高单价及各像素标价（行主序）
+#[query]
+pub fn quote_region(x0: u32, y0: u32, x1: u32, y1: u32) -> Result<RegionQuote, String> {
+	super::with_canvas(|c| {
+		c.quote_region(x0 as usize, y0 as usize, x1 as usize, y1 as usize)
+			.map_err(|e| e.to_string())
+	})
+}
+
+/// 读取 (x,y) 处像素最近的成交记录，按时间从旧到新
+#[query]
+pub fn get_pixel_history(x: u32, y: u32) -> Result<Vec<PixelEvent>, String> {
+	super::with_canvas(|c| c.pixel_history(x as usize, y as usize).map_err(|e| e.to_string()))
+}
+
+/// 以游程编码读取整张画布的颜色：按行主序的 (0xRRGGBBAA, 连续像素数) 列表，游程可跨行
+#[query]
+pub fn get_canvas_rle() -> Vec<(u32, u32)> {
+	super::with_canvas(|c| c.to_rle().into_iter().map(|(color, len)| (color.0, len)).collect())
+}
+
+/// 查询某个 BTC 地址持有的像素，`offset`/`limit` 用于分页（缺省返回全部）
+#[query]
+pub fn get_owned_pixels(account: String, offset: Option<u64>, limit: Option<u64>) -> OwnedPixels {
+	let owned = super::with_canvas(|c| c.pixels_owned_by(&account));
+	let offset = offset.unwrap_or(0) as usize;
+	let limit = limit.map(|l| l as usize).unwrap_or(usize::MAX);
+	
+	OwnedPixels {
+		total: owned.len() as u64,
+		pixels: owned
+			.into_iter()
+			.skip(offset)
+			.take(limit)
+			.map(|(x, y)| (x as u32, y as u32))
+			.collect(),
+	}
+}
+
+/// 画布汇总统计：像素总数、有主 / 无主像素数、总标价与占有者人数（全表扫描）
+#[query]
+pub fn get_canvas_stats() -> CanvasStats {
+	super::with_canvas(|c| c.stats())
+}
+
+/// 持有像素最多的占有者排行，`limit` 缺省及上限均为 MAX_LEADERBOARD_LEN
+#[query]
+pub fn get_leaderboard(limit: Option<u32>) -> Vec<OwnerStats> {
+	let limit = limit.map(|l| l as usize).unwrap_or(super::MAX_LEADERBOARD_LEN);
+	super::with_canvas(|c| c.leaderboard(limit))
+}
+
+/// 按顺序读取多个像素 (x,y) 的当前标价，单次最多 MAX_PRICE_LOOKUP 个坐标；
+/// 越界坐标只影响其自身对应的结果
+#[query]
+pub fn get_pixel_prices(coords: Vec<(u32, u32)>) -> Result<Vec<Result<u128, String>>, String> {
+	if coords.len() > super::MAX_PRICE_LOOKUP {
+		return Err(format!("Too many coordinates, max {}", super::MAX_PRICE_LOOKUP));
+	}
+	
+	let coords: Vec<(usize, usize)> = coords.into_iter().map(|(x, y)| (x as usize, y as usize)).collect();
+	Ok(super::with_canvas(|c| {
+		c.prices(&coords)
+			.into_iter()
+			.map(|r| r.map_err(|e| e.to_string()))
+			.collect()
+	}))
+}
+
+/// 读取像素成交累计的分账收入，供项目方对账
+#[query]
+pub fn get_canvas_revenue() -> CanvasRevenue {
+	super::with_canvas(|c| CanvasRevenue {
+		project_revenue: c.project_revenue(),
+		owner_revenue: c
+			.owner_revenue()
+			.iter()
+			.map(|(account, amount)| (account.clone(), *amount))
+			.collect(),
+	})
+}
+
+/// 像素过户：由 controller 在确认买家已付款 `amount_paid` 后调用，`color` 为 0xRRGGBBAA。
+///
+/// 返回的 [`PixelPayout`] 指明这笔款项中标价部分应记给原占有者还是项目方，超出标价的部分归项目方。
+/// `exempt` 为 true 时这笔购买不受冷却时间与频率限制，供 controller 自身（如项目方运营）购买时使用；
+/// 只有 controller 能调用本接口，因此豁免也只能由 controller 授予。
+#[update]
+pub fn buy_pixel_tx(
+	x: u32,
+	y: u32,
+	buyer: String,
+	amount_paid: u128,
+	color: u32,
+	exempt: bool,
+) -> Result<PixelPayout, String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	let payout = super::with_canvas_mut(|c| {
+		c.buy_pixel(x as usize, y as usize, buyer.clone(), amount_paid, Rgba8888(color), exempt)
+	})
+	.map_err(|e| e.to_string())?;
+	super::notify::notify_owner_changed(x, y, &buyer, amount_paid);
+	Ok(payout)
+}
+
+/// 批量过户闭区间矩形 [x0,x1]×[y0,y1]：由 controller 在确认买家已付款后调用，`color` 为 0xRRGGBBAA。
+///
+/// `per_pixel_paid` 是买家为单个像素支付的上限，每个像素按其标价成交，
+/// 买家按返回的 `total_required` 付款，差额由调用方退还；`blend`、`exempt` 的含义同
+/// [`set_pixel_color`] 与 [`buy_pixel_tx`]。任意一个像素失败时整个区域保持不变。
+#[update]
+pub fn buy_region_tx(
+	x0: u32,
+	y0: u32,
+	x1: u32,
+	y1: u32,
+	buyer: String,
+	per_pixel_paid: u128,
+	color: u32,
+	blend: Option<BlendMode>,
+	exempt: bool,
+) -> Result<RegionPurchase, String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	let (x0, y0, x1, y1) = (x0 as usize, y0 as usize, x1 as usize, y1 as usize);
+	let mode = blend.unwrap_or_default();
+	let (prices, purchase) = super::with_canvas_mut(|c| {
+		let prices = c.quote_region(x0, y0, x1, y1)?.prices;
+		let purchase = c.buy_region(x0, y0, x1, y1, buyer.clone(), per_pixel_paid, Rgba8888(color), mode, exempt)?;
+		Ok::<_, CanvasError>((prices, purchase))
+	})
+	.map_err(|e| e.to_string())?;
+	// 报价与成交在同一条消息内完成，prices 即各像素的成交价（行主序）
+	let coords = (y0..=y1).flat_map(|y| (x0..=x1).map(move |x| (x as u32, y as u32)));
+	for ((x, y), price) in coords.zip(prices) {
+		super::notify::notify_owner_changed(x, y, &buyer, price);
+	}
+	Ok(purchase)
+}
+
+/// 代像素占有者 `owner` 改色（`color` 为 0xRRGGBBAA），由 controller 在验证占有者身份后调用。
+///
+/// `blend` 为新色与原色的合成方式，缺省为 [`BlendMode::Replace`]（直接覆盖）。
+#[update]
+pub fn set_pixel_color(
+	x: u32,
+	y: u32,
+	owner: String,
+	color: u32,
+	blend: Option<BlendMode>,
+) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	let mode = blend.unwrap_or_default();
+	super::with_canvas_mut(|c| c.set_color(x as usize, y as usize, owner, Rgba8888(color), mode))
+		.map_err(|e| e.to_string())
+}
+
+/// 代像素占有者 `from` 把像素转让给 `to`（不涉及付款），由 controller 在验证占有者身份后调用
+#[update]
+pub fn transfer_pixel(x: u32, y: u32, from: String, to: String) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| c.transfer_pixel(x as usize, y as usize, from, to))
+		.map_err(|e| e.to_string())
+}
+
+/// 代像素占有者 `owner` 把像素释放回项目方（恢复初始标价，`reset_color` 为 true 时恢复白色），
+/// 由 controller 在验证占有者身份后调用
+#[update]
+pub fn release_pixel(x: u32, y: u32, owner: String, reset_color: bool) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| c.release_pixel(x as usize, y as usize, owner, reset_color))
+		.map_err(|e| e.to_string())
+}
+
+/// 代像素占有者 `owner` 设置留言（最长 140 字节），传入 None 清除，由 controller 在验证占有者身份后调用
+#[update]
+pub fn set_pixel_memo(x: u32, y: u32, owner: String, memo: Option<String>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| c.set_memo(x as usize, y as usize, owner, memo))
+		.map_err(|e| e.to_string())
+}
+
+/// 管理接口：从 (x0,y0) 起导入 `width`×`height` 的 RGB 图块（行主序 RGB 三元组），只改颜色不改所有权
+#[update]
+pub fn import_canvas_rgb(
+	x0: u32,
+	y0: u32,
+	width: u32,
+	height: u32,
+	data: Vec<u8>,
+) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| {
+		c.import_rgb(x0 as usize, y0 as usize, width as usize, height as usize, &data)
+	})
+	.map_err(|e| e.to_string())
+}
+
+/// 管理接口：分段导出整张画布的快照，用于迁移前备份。
+///
+/// 快照编码后可能超过单次响应的上限，因此从 `offset = 0` 开始逐段读取，每段最长
+/// [`SNAPSHOT_CHUNK_LEN`] 字节，直到读满 `total_len`；各段的 `merkle_root` 必须相同，否则应重新导出。
+#[query]
+pub fn export_canvas(offset: u64) -> Result<SnapshotChunk, String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	let bytes = super::with_canvas(|c| c.snapshot().encode());
+	let start = usize::try_from(offset)
+		.ok()
+		.filter(|start| *start <= bytes.len())
+		.ok_or(CanvasError::OutOfBounds.to_string())?;
+	let end = start.saturating_add(SNAPSHOT_CHUNK_LEN).min(bytes.len());
+	Ok(SnapshotChunk {
+		merkle_root: super::merkle_root().to_vec(),
+		total_len: bytes.len() as u64,
+		offset,
+		data: bytes[start..end].to_vec(),
+	})
+}
+
+/// 管理接口：追加一段待导入的快照数据，`offset` 必须等于已暂存的长度；`offset = 0` 时重新开始
+#[update]
+pub fn import_canvas_chunk(offset: u64, data: Vec<u8>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	IMPORT_BUFFER.with_borrow_mut(|buffer| {
+		if offset == 0 {
+			buffer.clear();
+		}
+		if offset != buffer.len() as u64 {
+			return Err(CanvasError::DataSizeMismatch { expected: buffer.len() as u64 }.to_string());
+		}
+		buffer.extend_from_slice(&data);
+		Ok(())
+	})
+}
+
+/// 管理接口：用已暂存的快照整体替换当前画布；`total_len` 须等于导出时的总字节数。
+///
+/// 成功后清空暂存数据；失败时画布与暂存数据都保持不变。
+#[update]
+pub fn import_canvas(total_len: u64) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	let snapshot = IMPORT_BUFFER.with_borrow(|buffer| {
+		if buffer.len() as u64 != total_len {
+			return Err(CanvasError::DataSizeMismatch { expected: buffer.len() as u64 });
+		}
+		CanvasSnapshot::decode(buffer)
+	})
+	.map_err(|e| e.to_string())?;
+	let restored = Canvas::restore(snapshot).map_err(|e| e.to_string())?;
+	super::with_canvas_mut(|c| {
+		*c = restored;
+		Ok::<(), String>(())
+	})?;
+	IMPORT_BUFFER.with_borrow_mut(|buffer| buffer.clear());
+	Ok(())
+}
+
+/// 管理接口：设置画布像素总数上限，之后的创建、调整尺寸与导入都不能超过它
+#[update]
+pub fn set_canvas_max_pixels(max_pixels: u64) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::set_max_canvas_pixels(max_pixels)
+}
+
+/// 当前的画布像素总数上限
+#[query]
+pub fn get_canvas_max_pixels() -> u64 {
+	super::max_canvas_pixels()
+}
+
+/// 管理接口：设置同一像素两次成交之间的冷却时间（纳秒），传入 None 取消限制
+#[update]
+pub fn set_canvas_cooldown(cooldown_ns: Option<u64>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| {
+		c.set_cooldown(cooldown_ns);
+		Ok::<(), String>(())
+	})
+}
+
+/// 管理接口：设置按矩形区域划分的初始标价（重叠时靠前者优先），无主像素的标价随之重置
+#[update]
+pub fn set_canvas_price_zones(price_zones: Vec<PriceZone>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| c.set_price_zones(price_zones)).map_err(|e| e.to_string())
+}
+
+/// 管理接口：设置像素标价上限，传入 None 取消上限
+#[update]
+pub fn set_canvas_max_price(max_price: Option<u128>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| {
+		c.set_max_price(max_price);
+		Ok::<(), String>(())
+	})
+}
+
+/// 管理接口：设置调价溢出时饱和（Saturate）还是拒绝购买（Reject）
+#[update]
+pub fn set_canvas_price_overflow_policy(policy: PriceOverflowPolicy) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| {
+		c.set_price_overflow_policy(policy);
+		Ok::<(), String>(())
+	})
+}
+
+/// 管理接口：设置每个账户的购买频率限制，传入 None 取消限制
+#[update]
+pub fn set_canvas_rate_limit(rate_limit: Option<RateLimit>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::with_canvas_mut(|c| {
+		c.set_rate_limit(rate_limit);
+		Ok::<(), String>(())
+	})
+}
+
+/// 管理接口：设置调色板（0xRRGGBBAA 列表），传入 None 取消颜色限制
+#[update]
+pub fn set_canvas_palette(palette: Option<Vec<u32>>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	let palette = palette.map(|colors| colors.into_iter().map(Rgba8888).collect());
+	super::with_canvas_mut(|c| {
+		c.set_palette(palette);
+		Ok::<(), String>(())
+	})
+}
+
+/// 管理接口：设置接收像素易主通知的订阅者 canister 列表（整体替换）
+#[update]
+pub fn set_canvas_subscribers(subscribers: Vec<Principal>) -> Result<(), String> {
+	let caller = ic_cdk::api::caller();
+	if !ic_cdk::api::is_controller(&caller) {
+		return Err("Not authorized".to_string());
+	}
+	
+	super::notify::set_subscribers(subscribers)
+}
+
+#[query]
+pub fn get_canvas_subscribers() -> Vec<Principal> {
+	super::notify::subscribers()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::canvas::{notify::take_sent, set_now};
+	
+	#[test]
+	fn only_exempt_buys_skip_the_cooldown() {
+		set_canvas_cooldown(Some(100)).unwrap();
+		set_now(1_000);
+		buy_pixel_tx(0, 0, "alice".to_string(), 1_000, 0xFF00_00FF, false).unwrap();
+		
+		let result = buy_pixel_tx(0, 0, "bob".to_string(), 2_000, 0xFF00_00FF, false);
+		assert_eq!(result, Err(CanvasError::Cooldown { retry_after: 1_100 }.to_string()));
+		let payout = buy_pixel_tx(0, 0, "bob".to_string(), 2_000, 0xFF00_00FF, true).unwrap();
+		assert_eq!(payout.previous_owner.as_deref(), Some("alice"));
+	}
+	
+	#[test]
+	fn region_purchase_is_reachable_through_the_api() {
+		buy_pixel_tx(1, 0, "alice".to_string(), 1_000, 0xFF00_00FF, true).unwrap();
+		
+		let too_low = buy_region_tx(0, 0, 1, 0, "bob".to_string(), 1_000, 0x0000_FFFF, None, true);
+		assert_eq!(too_low, Err(CanvasError::PriceTooLow { required: 2_000 }.to_string()));
+		assert_eq!(get_pixel(0, 0).unwrap().owner, None);
+		
+		let purchase = buy_region_tx(0, 0, 1, 0, "bob".to_string(), 2_000, 0x0000_FFFF, None, true).unwrap();
+		assert_eq!(purchase.total_required, 3_000);
+		assert_eq!(purchase.previous_owners.into_iter().collect::<Vec<_>>(), vec!["alice".to_string()]);
+		for view in get_region(0, 0, 1, 0).unwrap() {
+			assert_eq!((view.owner.as_deref(), view.color.as_str()), (Some("bob"), "#0000FFFF"));
+		}
+	}
+	
+	#[test]
+	fn purchases_notify_subscribers_of_each_new_owner() {
+		let subscriber = Principal::from_slice(&[7]);
+		set_canvas_subscribers(vec![subscriber]).unwrap();
+		buy_pixel_tx(0, 0, "alice".to_string(), 1_500, 0xFF00_00FF, true).unwrap();
+		buy_region_tx(0, 0, 1, 0, "bob".to_string(), 2_000, 0xFF00_00FF, None, true).unwrap();
+		// 失败的购买与转让都不发通知
+		assert!(buy_pixel_tx(0, 0, "carol".to_string(), 1, 0xFF00_00FF, true).is_err());
+		transfer_pixel(1, 0, "bob".to_string(), "carol".to_string()).unwrap();
+		
+		let sent = take_sent();
+		assert!(sent.iter().all(|(to, _)| *to == subscriber));
+		assert_eq!(
+			sent.into_iter().map(|(_, args)| args).collect::<Vec<_>>(),
+			vec![
+				(0, 0, "alice".to_string(), 1_500),
+				(0, 0, "bob".to_string(), 2_000),
+				(1, 0, "bob".to_string(), 1_000),
+			]
+		);
+	}
+	
+	#[test]
+	fn errors_read_as_their_messages() {
+		assert_eq!(get_pixel(0, 100), Err("coordinates out of bounds".to_string()));
+		
+		// 作为 Candid 值返回时，错误经编码、解码后保持不变
+		for err in [CanvasError::NotOwner, CanvasError::Cooldown { retry_after: 1_100 }] {
+			let bytes = candid::encode_one(&err).unwrap();
+			assert_eq!(candid::decode_one::<CanvasError>(&bytes).unwrap(), err);
+		}
+	}
+	
+	#[test]
+	fn a_full_size_canvas_round_trips_through_the_chunked_export() {
+		use crate::canvas::{PriceStrategy, merkle_root, with_canvas, with_canvas_mut};
+		
+		set_now(1_000);
+		// 512×512 即默认上限 262,144 个像素
+		with_canvas_mut(|c| {
+			*c = Canvas::new(512, 512, 1_000, PriceStrategy::Double, vec![])?;
+			for i in 0..512 {
+				c.buy_pixel(i, i, format!("owner-{}", i % 7), 1_000, Rgba8888(0xFF00_00FF), true)?;
+			}
+			c.set_memo(3, 3, "owner-3".to_string(), Some("gm".to_string()))?;
+			Ok::<(), CanvasError>(())
+		})
+		.unwrap();
+		let before = with_canvas(|c| c.snapshot());
+		let root = merkle_root();
+		
+		let mut bytes = vec![];
+		let mut chunks = 0;
+		loop {
+			let chunk = export_canvas(bytes.len() as u64).unwrap();
+			assert_eq!(chunk.merkle_root, root.to_vec());
+			assert!(chunk.data.len() <= SNAPSHOT_CHUNK_LEN);
+			bytes.extend_from_slice(&chunk.data);
+			chunks += 1;
+			if bytes.len() as u64 == chunk.total_len {
+				break;
+			}
+		}
+		assert!(chunks > 1);
+		assert!(export_canvas(bytes.len() as u64 + 1).is_err());
+		
+		with_canvas_mut(|c| {
+			*c = Canvas::new(2, 2, 1_000, PriceStrategy::Double, vec![])?;
+			Ok::<(), CanvasError>(())
+		})
+		.unwrap();
+		assert!(import_canvas_chunk(1, vec![0]).is_err());
+		for (i, chunk) in bytes.chunks(SNAPSHOT_CHUNK_LEN).enumerate() {
+			import_canvas_chunk((i * SNAPSHOT_CHUNK_LEN) as u64, chunk.to_vec()).unwrap();
+		}
+		assert!(import_canvas(bytes.len() as u64 - 1).is_err());
+		assert_eq!(with_canvas(|c| c.pixel_count()), 4);
+		
+		import_canvas(bytes.len() as u64).unwrap();
+		assert_eq!(with_canvas(|c| c.snapshot()), before);
+		assert_eq!(merkle_root(), root);
+		assert_eq!(import_canvas(bytes.len() as u64), Err(CanvasError::DataSizeMismatch { expected: 0 }.to_string()));
+	}
+	
+	#[test]
+	fn owned_pixels_follow_each_buy_and_page() {
+		for (x, y) in [(5, 0), (0, 0), (1, 2)] {
+			buy_pixel_tx(x, y, "alice".to_string(), 1_000, 0xFF00_00FF, true).unwrap();
+		}
+		let owned = get_owned_pixels("alice".to_string(), None, None);
+		assert_eq!(owned, OwnedPixels { total: 3, pixels: vec![(0, 0), (5, 0), (1, 2)] });
+		
+		// 被买走的像素从原占有者名下消失
+		buy_pixel_tx(5, 0, "bob".to_string(), 2_000, 0xFF00_00FF, true).unwrap();
+		assert_eq!(get_owned_pixels("bob".to_string(), None, None).pixels, vec![(5, 0)]);
+		let page = get_owned_pixels("alice".to_string(), Some(1), Some(1));
+		assert_eq!(page, OwnedPixels { total: 2, pixels: vec![(1, 2)] });
+		assert_eq!(get_owned_pixels("alice".to_string(), Some(2), None).pixels, vec![]);
+		assert_eq!(get_owned_pixels("carol".to_string(), None, None).total, 0);
+	}
+}
@@ -1,33 +1,355 @@
-use crate::canvas::pixel::{AccountId, Pixel, Rgb888};
+use crate::canvas::merkle;
+use crate::canvas::pixel::{AccountId, BlendMode, MAX_MEMO_LEN, Pixel, PixelEvent, PixelView, Rgb888, Rgba8888};
+use candid::CandidType;
+use ic_stable_structures::{Storable, storable::Bound};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
 
-#[derive(Clone)]
+/// 每个像素最多保留的成交记录条数
+pub const PIXEL_HISTORY_LIMIT: usize = 16;
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Canvas {
 	width: usize,
 	height: usize,
 	// 一维向量存储，按行主序（row-major）：idx = x + y * width
 	pixels: Vec<Pixel>,
+	// 像素被购买后的价格递增策略
+	price_strategy: PriceStrategy,
+	// 像素初始标价，像素被释放回项目方时恢复为该价格
+	#[serde(default = "default_initial_price")]
+	initial_price: u128,
+	// 按矩形区域设定的初始标价，覆盖 initial_price；多个区域重叠时以靠前者为准
+	#[serde(default)]
+	price_zones: Vec<PriceZone>,
+	// 像素标价上限，调价后不会超过该值，None 表示不设上限
+	#[serde(default)]
+	max_price: Option<u128>,
+	// 调价溢出时饱和还是拒绝购买
+	#[serde(default)]
+	price_overflow_policy: PriceOverflowPolicy,
+	// 允许使用的调色板，None 表示不限制颜色
+	#[serde(default)]
+	palette: Option<Vec<Rgba8888>>,
+	// 每个像素 (x,y) 最近的成交记录，按时间从旧到新，最多 PIXEL_HISTORY_LIMIT 条
+	#[serde(default)]
+	history: BTreeMap<(usize, usize), Vec<PixelEvent>>,
+	// 同一像素两次成交之间的最短间隔（纳秒），None 表示不限制
+	#[serde(default)]
+	cooldown_ns: Option<u64>,
+	// 累计应归项目方的收入：无主像素的成交价，以及任何成交中超出标价的部分
+	#[serde(default)]
+	project_revenue: u128,
+	// 各原占有者因像素被转售累计应得的收入
+	#[serde(default)]
+	owner_revenue: BTreeMap<AccountId, u128>,
+	// 每个账户在一个时间窗口内的购买次数上限，None 表示不限制
+	#[serde(default)]
+	rate_limit: Option<RateLimit>,
+	// 各账户当前窗口的 (窗口起点, 已购买像素数)
+	#[serde(default)]
+	purchase_windows: BTreeMap<AccountId, (u64, u32)>,
+}
+
+/// 引入该字段之前创建的画布都使用默认初始价
+fn default_initial_price() -> u128 {
+	crate::canvas::INITIAL_PIXEL_PRICE
+}
+
+/// 初始标价区域：闭区间矩形 [x0,x1]×[y0,y1] 内的像素以 `price` 为初始标价
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PriceZone {
+	pub x0: u32,
+	pub y0: u32,
+	pub x1: u32,
+	pub y1: u32,
+	pub price: u128,
+}
+
+impl PriceZone {
+	fn contains(&self, x: usize, y: usize) -> bool {
+		(self.x0 as usize..=self.x1 as usize).contains(&x)
+			&& (self.y0 as usize..=self.y1 as usize).contains(&y)
+	}
+}
+
+/// 每个账户的购买频率限制：每 `window_ns` 纳秒内最多购买 `max_purchases` 个像素
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimit {
+	pub max_purchases: u32,
+	pub window_ns: u64,
+}
+
+/// 像素被购买后的价格递增策略，所有计算在 `u128::MAX` 处饱和
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PriceStrategy {
+	/// 价格翻倍
+	Double,
+	/// 价格加上固定增量
+	Linear { delta: u128 },
+	/// 价格乘以 num / den（den 为 0 时价格保持不变）
+	Multiplier { num: u32, den: u32 },
+	/// 价格保持不变
+	Fixed,
 }
 
-/// 与画布相关的错误类型
-#[derive(Debug)]
+impl PriceStrategy {
+	/// 根据当前价计算下一次的标价，溢出时饱和为 `u128::MAX`
+	pub fn next_price(&self, current: u128) -> u128 {
+		self.checked_next_price(current).unwrap_or(u128::MAX)
+	}
+	
+	/// 根据当前价计算下一次的标价，溢出时返回 None
+	pub fn checked_next_price(&self, current: u128) -> Option<u128> {
+		match *self {
+			PriceStrategy::Double => current.checked_mul(2),
+			PriceStrategy::Linear { delta } => current.checked_add(delta),
+			PriceStrategy::Multiplier { num, den } => {
+				if den == 0 {
+					return Some(current);
+				}
+				let (num, den) = (num as u128, den as u128);
+				// 先乘后除精度最高；溢出时改为先除后乘
+				current
+					.checked_mul(num)
+					.map(|v| v / den)
+					.or_else(|| (current / den).checked_mul(num))
+			}
+			PriceStrategy::Fixed => Some(current),
+		}
+	}
+}
+
+/// 调价溢出 `u128` 时的处理方式
+#[derive(CandidType, Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PriceOverflowPolicy {
+	/// 标价饱和在 `u128::MAX`（设置了标价上限时为上限）
+	#[default]
+	Saturate,
+	/// 拒绝这笔购买，返回 `CanvasError::PriceOverflow`
+	Reject,
+}
+
+/// 与画布相关的错误类型，可直接作为 Candid 返回值
+#[derive(Debug, Error, CandidType, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum CanvasError {
+	#[error("coordinates out of bounds")]
 	OutOfBounds,
+	#[error("price too low, at least {required} is required")]
 	PriceTooLow { required: u128 },
+	#[error("caller does not own the pixel")]
+	NotOwner,
+	#[error("color is not in the canvas palette")]
+	ColorNotAllowed,
+	#[error("pixel is cooling down until {retry_after}")]
+	Cooldown { retry_after: u64 }, // retry_after：可再次购买的时间点（纳秒）
+	#[error("memo longer than {max} bytes")]
+	MemoTooLong { max: u64 },
+	#[error("data size mismatch, expected {expected}")]
+	DataSizeMismatch { expected: u64 }, // expected：期望的数据长度（字节数或像素数）
+	#[error("purchase rate limit reached, retry after {retry_after}")]
+	RateLimited { retry_after: u64 }, // retry_after：新窗口开始、可再次购买的时间点（纳秒）
+	#[error("the next price of the pixel overflows")]
+	PriceOverflow,
+	#[error("canvas larger than {max} pixels")]
+	TooLarge { max: u64 }, // max：画布像素总数上限
+	#[error("snapshot data cannot be decoded")]
+	InvalidSnapshot,
+}
+
+/// 画布的完整快照，用于迁移前备份；颜色一律为 0xRRGGBBAA。
+///
+/// 不含成交记录与频率限制的窗口计数等临时数据，恢复后二者为空。
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CanvasSnapshot {
+	pub width: u32,
+	pub height: u32,
+	pub price_strategy: PriceStrategy,
+	pub initial_price: u128,
+	pub price_zones: Vec<PriceZone>,
+	pub max_price: Option<u128>,
+	pub price_overflow_policy: PriceOverflowPolicy,
+	pub palette: Option<Vec<u32>>,
+	pub cooldown_ns: Option<u64>,
+	pub rate_limit: Option<RateLimit>,
+	pub project_revenue: u128,
+	pub owner_revenue: Vec<(AccountId, u128)>,
+	pub pixels: Vec<PixelSnapshot>, // 按行主序，长度必须等于 width * height
+}
+
+/// 快照中的单个像素
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PixelSnapshot {
+	pub owner: Option<AccountId>,
+	pub price: u128,
+	pub color: u32,
+	pub last_bought: u64,
+	pub memo: Option<String>,
+}
+
+/// 分段导出快照时每段的最大字节数，远低于 2 MiB 的响应上限
+pub const SNAPSHOT_CHUNK_LEN: usize = 1 << 20;
+
+/// 分段导出的一段：快照整体按 CBOR 编码后，每 [`SNAPSHOT_CHUNK_LEN`] 字节一段
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotChunk {
+	pub merkle_root: Vec<u8>, // 导出时画布的 Merkle 根；各段不一致说明导出期间画布被修改过，需重新导出
+	pub total_len: u64,       // 编码后快照的总字节数
+	pub offset: u64,          // 本段在编码数据中的起始位置
+	pub data: Vec<u8>,
+}
+
+impl CanvasSnapshot {
+	/// 按 CBOR 编码，即分段导出、导入时传输的数据
+	pub fn encode(&self) -> Vec<u8> {
+		let mut bytes = vec![];
+		let _ = ciborium::ser::into_writer(self, &mut bytes);
+		bytes
+	}
+	
+	/// [`CanvasSnapshot::encode`] 的逆过程
+	pub fn decode(bytes: &[u8]) -> Result<Self, CanvasError> {
+		ciborium::de::from_reader(bytes).map_err(|_| CanvasError::InvalidSnapshot)
+	}
+}
+
+/// 购买像素后的分账信息：`amount_paid` 应流向何处。
+///
+/// 分账规则与 [`Canvas::buy_region`] 一致：像素的标价归原占有者（无主时归项目方），超出标价的部分归项目方。
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PixelPayout {
+	pub previous_owner: Option<AccountId>, // None 表示收入归项目方，为Some则归原像素占有者
+	pub payout_amount: u128,               // 应付给上述一方的金额，即成交前的标价
+	pub overpay: u128,                     // amount_paid 超出标价、归项目方的部分
+}
+
+/// 排行榜条目：某个占有者持有的像素数及其当前标价之和
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OwnerStats {
+	pub owner: AccountId,
+	pub pixel_count: u64,
+	pub total_value: u128, // 所持像素当前标价之和
+}
+
+/// 画布汇总统计
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CanvasStats {
+	pub total_pixels: u64,
+	pub owned_pixels: u64,
+	pub unowned_pixels: u64,
+	pub total_value: u128, // 全部像素当前标价之和
+	pub unique_owners: u64,
+}
+
+/// 批量查询标价时单次允许的最大坐标数
+pub const MAX_PRICE_LOOKUP: usize = 1_000;
+
+/// 排行榜单次返回的最大条目数
+pub const MAX_LEADERBOARD_LEN: usize = 100;
+
+/// 购买矩形区域的报价，不修改画布
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegionQuote {
+	pub total: u128,       // 区域内所有像素当前标价之和，即 buy_region 成功后的 total_required
+	pub max_price: u128,   // 区域内最高的单像素标价，buy_region 的 per_pixel_paid 不得低于此值
+	pub prices: Vec<u128>, // 各像素当前标价，按行主序
+}
+
+/// 批量购买矩形区域的结果，供调用方做退款 / 分账
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegionPurchase {
+	pub total_required: u128,                // 区域内所有像素购买前标价之和
+	pub previous_owners: BTreeSet<AccountId>, // 区域内被替换的原占有者（不含无主像素）
+}
+
+impl Storable for Canvas {
+	const BOUND: Bound = Bound::Unbounded;
+	
+	fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+		let mut bytes = vec![];
+		let _ = ciborium::ser::into_writer(self, &mut bytes);
+		std::borrow::Cow::Owned(bytes)
+	}
+	
+	fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+		ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode Canvas")
+	}
 }
 
 impl Canvas {
-	/// 创建画布：所有像素初始无主、指定初始价、默认颜色 (#FFFFFF)。
-	pub fn new(width: usize, height: usize, initial_price: u128) -> Self {
+	/// 创建画布：所有像素初始无主、默认颜色 (#FFFFFFFF)，购买后按 `price_strategy` 调价。
+	///
+	/// 落在 `price_zones` 某个区域内的像素以该区域的标价为初始价，其余为 `initial_price`。
+	/// 像素总数超过 [`max_canvas_pixels`](crate::canvas::max_canvas_pixels) 时返回 `TooLarge`，不做分配。
+	pub fn new(
+		width: usize,
+		height: usize,
+		initial_price: u128,
+		price_strategy: PriceStrategy,
+		price_zones: Vec<PriceZone>,
+	) -> Result<Self, CanvasError> {
+		let len = Self::checked_len(width, height)?;
 		let default_pixel = Pixel {
 			owner: None,
 			price: initial_price,
-			color: Rgb888(0xFFFFFF),
+			color: Rgba8888(0xFFFF_FFFF),
+			last_bought: 0,
+			memo: None,
 		};
-		Self {
+		let mut canvas = Self {
 			width,
 			height,
-			pixels: vec![default_pixel; width * height],
+			pixels: vec![default_pixel; len],
+			price_strategy,
+			initial_price,
+			price_zones,
+			max_price: None,
+			price_overflow_policy: PriceOverflowPolicy::default(),
+			palette: None,
+			history: BTreeMap::new(),
+			cooldown_ns: None,
+			project_revenue: 0,
+			owner_revenue: BTreeMap::new(),
+			rate_limit: None,
+			purchase_windows: BTreeMap::new(),
+		};
+		for i in 0..canvas.pixels.len() {
+			canvas.pixels[i].price = canvas.base_price(i % width, i / width);
+		}
+		Ok(canvas)
+	}
+	
+	/// `width * height` 个像素，溢出或超过当前像素总数上限时返回 `TooLarge`
+	fn checked_len(width: usize, height: usize) -> Result<usize, CanvasError> {
+		let max = crate::canvas::max_canvas_pixels();
+		width
+			.checked_mul(height)
+			.filter(|len| *len as u64 <= max)
+			.ok_or(CanvasError::TooLarge { max })
+	}
+	
+	/// 像素 (x,y) 的初始标价：所在的第一个标价区域的价格，不在任何区域内时为 initial_price
+	pub fn base_price(&self, x: usize, y: usize) -> u128 {
+		self.price_zones
+			.iter()
+			.find(|zone| zone.contains(x, y))
+			.map_or(self.initial_price, |zone| zone.price)
+	}
+	
+	/// 管理接口：替换初始标价区域，并把所有无主像素的标价重置为新的初始标价。
+	/// 区域必须满足 x0<=x1、y0<=y1 且整个矩形在画布内。
+	pub fn set_price_zones(&mut self, price_zones: Vec<PriceZone>) -> Result<(), CanvasError> {
+		for zone in &price_zones {
+			self.rect_indices(zone.x0 as usize, zone.y0 as usize, zone.x1 as usize, zone.y1 as usize)?;
+		}
+		self.price_zones = price_zones;
+		for i in 0..self.pixels.len() {
+			if self.pixels[i].owner.is_none() {
+				self.pixels[i].price = self.base_price(i % self.width, i / self.width);
+			}
 		}
+		Ok(())
 	}
 	
 	/// 将 (x,y) 坐标映射到vec索引
@@ -39,54 +361,1287 @@ impl Canvas {
 		}
 	}
 	
+	/// 画布像素总数（宽 × 高）
+	pub fn pixel_count(&self) -> u64 {
+		self.pixels.len() as u64
+	}
+	
 	/// 读取像素
 	pub fn pixel(&self, x: usize, y: usize) -> Result<&Pixel, CanvasError> {
 		self.idx(x, y).map(|i| &self.pixels[i])
 	}
 	
+	/// 按顺序读取多个像素的当前标价，越界坐标对应位置返回 `OutOfBounds`
+	pub fn prices(&self, coords: &[(usize, usize)]) -> Vec<Result<u128, CanvasError>> {
+		coords
+			.iter()
+			.map(|&(x, y)| self.pixel(x, y).map(|p| p.price))
+			.collect()
+	}
+	
 	/// **内部函数**：可变引用（封装成公共业务函数更安全）
 	fn pixel_mut(&mut self, x: usize, y: usize) -> Result<&mut Pixel, CanvasError> {
 		self.idx(x, y).map(|i| &mut self.pixels[i])
 	}
 	
+	/// 校验颜色是否在调色板内（未设置调色板时总是通过）
+	fn check_color(&self, color: Rgba8888) -> Result<(), CanvasError> {
+		match &self.palette {
+			Some(palette) if !palette.contains(&color) => Err(CanvasError::ColorNotAllowed),
+			_ => Ok(()),
+		}
+	}
+	
+	/// 校验vec索引 `i` 处的像素是否已过冷却期
+	fn check_cooldown(&self, i: usize, now: u64) -> Result<(), CanvasError> {
+		if let Some(cooldown) = self.cooldown_ns {
+			let retry_after = self.pixels[i].last_bought.saturating_add(cooldown);
+			if now < retry_after {
+				return Err(CanvasError::Cooldown { retry_after });
+			}
+		}
+		Ok(())
+	}
+	
+	/// 校验 `buyer` 在当前窗口内再买 `count` 个像素是否超出频率限制
+	fn check_rate_limit(&self, buyer: &AccountId, count: usize, now: u64) -> Result<(), CanvasError> {
+		let Some(limit) = self.rate_limit else {
+			return Ok(());
+		};
+		let (start, used) = match self.purchase_windows.get(buyer) {
+			Some(&(start, used)) if now < start.saturating_add(limit.window_ns) => (start, used),
+			_ => (now, 0),
+		};
+		if (used as usize).saturating_add(count) > limit.max_purchases as usize {
+			return Err(CanvasError::RateLimited {
+				retry_after: start.saturating_add(limit.window_ns),
+			});
+		}
+		Ok(())
+	}
+	
+	/// 把 `count` 次购买计入 `buyer` 的当前窗口，并顺带清理已过期的窗口
+	fn record_purchases(&mut self, buyer: &AccountId, count: usize, now: u64) {
+		let Some(limit) = self.rate_limit else {
+			return;
+		};
+		self.purchase_windows
+			.retain(|_, (start, _)| now < start.saturating_add(limit.window_ns));
+		let (_, used) = self.purchase_windows.entry(buyer.clone()).or_insert((now, 0));
+		*used = used.saturating_add(count.min(u32::MAX as usize) as u32);
+	}
+	
+	/// 追加一条成交记录，超出上限时丢弃最旧的记录
+	fn record_event(&mut self, x: usize, y: usize, event: PixelEvent) {
+		let events = self.history.entry((x, y)).or_default();
+		events.push(event);
+		if events.len() > PIXEL_HISTORY_LIMIT {
+			let excess = events.len() - PIXEL_HISTORY_LIMIT;
+			events.drain(..excess);
+		}
+	}
+	
+	/// 把一笔像素成交款记入原占有者（`None` 时记入项目方）的累计收入
+	fn credit_revenue(&mut self, previous_owner: Option<&AccountId>, amount: u128) {
+		let total = match previous_owner {
+			Some(owner) => self.owner_revenue.entry(owner.clone()).or_default(),
+			None => &mut self.project_revenue,
+		};
+		*total = total.saturating_add(amount);
+	}
+	
+	/// 将闭区间矩形 [x0,x1]×[y0,y1] 映射为按行主序排列的vec索引
+	fn rect_indices(
+		&self,
+		x0: usize,
+		y0: usize,
+		x1: usize,
+		y1: usize,
+	) -> Result<Vec<usize>, CanvasError> {
+		if x0 > x1 || y0 > y1 {
+			return Err(CanvasError::OutOfBounds);
+		}
+		// 两个对角都在画布内即可保证整个矩形在画布内
+		self.idx(x0, y0)?;
+		self.idx(x1, y1)?;
+		Ok((y0..=y1)
+			.flat_map(|y| (x0..=x1).map(move |x| x + y * self.width))
+			.collect())
+	}
+	
+	/// 为闭区间矩形 [x0,x1]×[y0,y1] 报价：与 [`Canvas::buy_region`] 使用相同的标价，只读
+	pub fn quote_region(
+		&self,
+		x0: usize,
+		y0: usize,
+		x1: usize,
+		y1: usize,
+	) -> Result<RegionQuote, CanvasError> {
+		let prices: Vec<u128> = self
+			.rect_indices(x0, y0, x1, y1)?
+			.into_iter()
+			.map(|i| self.pixels[i].price)
+			.collect();
+		Ok(RegionQuote {
+			total: prices.iter().fold(0u128, |acc, p| acc.saturating_add(*p)),
+			max_price: prices.iter().copied().max().unwrap_or_default(),
+			prices,
+		})
+	}
+	
+	/// 读取闭区间矩形 [x0,x1]×[y0,y1] 内的像素视图（行主序），用于前端按块加载
+	pub fn region(
+		&self,
+		x0: usize,
+		y0: usize,
+		x1: usize,
+		y1: usize,
+	) -> Result<Vec<PixelView>, CanvasError> {
+		let indices = self.rect_indices(x0, y0, x1, y1)?;
+		Ok(indices
+			.into_iter()
+			.map(|i| self.pixels[i].view(i % self.width, i / self.width))
+			.collect())
+	}
+	
+	/// (x,y) 在画布内的相邻坐标（行主序）：`diagonal` 为 false 时取上下左右 4 邻域，
+	/// 为 true 时再加上 4 个对角，共 8 邻域。边缘与角落的像素只返回画布内的邻居，
+	/// (x,y) 本身越界时返回空列表。
+	pub fn neighbors(&self, x: usize, y: usize, diagonal: bool) -> Vec<(usize, usize)> {
+		if self.idx(x, y).is_err() {
+			return vec![];
+		}
+		let mut neighbors = Vec::with_capacity(8);
+		for ny in y.saturating_sub(1)..=(y + 1).min(self.height - 1) {
+			for nx in x.saturating_sub(1)..=(x + 1).min(self.width - 1) {
+				let is_diagonal = nx != x && ny != y;
+				if (nx, ny) != (x, y) && (diagonal || !is_diagonal) {
+					neighbors.push((nx, ny));
+				}
+			}
+		}
+		neighbors
+	}
+	
+	/// 导出整张画布的快照，可经 [`Canvas::restore`] 原样恢复
+	pub fn snapshot(&self) -> CanvasSnapshot {
+		CanvasSnapshot {
+			width: self.width as u32,
+			height: self.height as u32,
+			price_strategy: self.price_strategy,
+			initial_price: self.initial_price,
+			price_zones: self.price_zones.clone(),
+			max_price: self.max_price,
+			price_overflow_policy: self.price_overflow_policy,
+			palette: self
+				.palette
+				.as_ref()
+				.map(|colors| colors.iter().map(|c| c.0).collect()),
+			cooldown_ns: self.cooldown_ns,
+			rate_limit: self.rate_limit,
+			project_revenue: self.project_revenue,
+			owner_revenue: self
+				.owner_revenue
+				.iter()
+				.map(|(account, amount)| (account.clone(), *amount))
+				.collect(),
+			pixels: self
+				.pixels
+				.iter()
+				.map(|p| PixelSnapshot {
+					owner: p.owner.clone(),
+					price: p.price,
+					color: p.color.0,
+					last_bought: p.last_bought,
+					memo: p.memo.clone(),
+				})
+				.collect(),
+		}
+	}
+	
+	/// 由快照重建画布；像素数与宽高不符时返回 `DataSizeMismatch`，宽高超过上限时返回 `TooLarge`
+	pub fn restore(snapshot: CanvasSnapshot) -> Result<Self, CanvasError> {
+		let width = snapshot.width as usize;
+		let height = snapshot.height as usize;
+		let expected = Self::checked_len(width, height)?;
+		if snapshot.pixels.len() != expected {
+			return Err(CanvasError::DataSizeMismatch { expected: expected as u64 });
+		}
+		
+		Ok(Self {
+			width,
+			height,
+			pixels: snapshot
+				.pixels
+				.into_iter()
+				.map(|p| Pixel {
+					owner: p.owner,
+					price: p.price,
+					color: Rgba8888(p.color),
+					last_bought: p.last_bought,
+					memo: p.memo,
+				})
+				.collect(),
+			price_strategy: snapshot.price_strategy,
+			initial_price: snapshot.initial_price,
+			price_zones: snapshot.price_zones,
+			max_price: snapshot.max_price,
+			price_overflow_policy: snapshot.price_overflow_policy,
+			palette: snapshot
+				.palette
+				.map(|colors| colors.into_iter().map(Rgba8888).collect()),
+			history: BTreeMap::new(),
+			cooldown_ns: snapshot.cooldown_ns,
+			project_revenue: snapshot.project_revenue,
+			owner_revenue: snapshot.owner_revenue.into_iter().collect(),
+			rate_limit: snapshot.rate_limit,
+			purchase_windows: BTreeMap::new(),
+		})
+	}
+	
+	/// 读取像素最近的成交记录（从旧到新）
+	pub fn pixel_history(&self, x: usize, y: usize) -> Result<Vec<PixelEvent>, CanvasError> {
+		self.idx(x, y)?;
+		Ok(self.history.get(&(x, y)).cloned().unwrap_or_default())
+	}
+	
+	/// 列出 `account` 持有的全部像素坐标（行主序），需全表扫描
+	pub fn pixels_owned_by(&self, account: &AccountId) -> Vec<(usize, usize)> {
+		self.pixels
+			.iter()
+			.enumerate()
+			.filter(|(_, p)| p.owner.as_ref() == Some(account))
+			.map(|(i, _)| (i % self.width, i / self.width))
+			.collect()
+	}
+	
+	/// 一次遍历计算画布汇总统计，需全表扫描
+	pub fn stats(&self) -> CanvasStats {
+		let mut owners = BTreeSet::new();
+		let mut owned_pixels = 0u64;
+		let mut total_value = 0u128;
+		for p in &self.pixels {
+			total_value = total_value.saturating_add(p.price);
+			if let Some(owner) = p.owner.as_ref() {
+				owned_pixels += 1;
+				owners.insert(owner);
+			}
+		}
+		CanvasStats {
+			total_pixels: self.pixels.len() as u64,
+			owned_pixels,
+			unowned_pixels: self.pixels.len() as u64 - owned_pixels,
+			total_value,
+			unique_owners: owners.len() as u64,
+		}
+	}
+	
+	/// 按持有像素数（其次按标价之和）降序列出前 `limit` 名占有者，`limit` 不超过
+	/// [`MAX_LEADERBOARD_LEN`]。需全表扫描
+	pub fn leaderboard(&self, limit: usize) -> Vec<OwnerStats> {
+		let mut stats: BTreeMap<&AccountId, (u64, u128)> = BTreeMap::new();
+		for p in &self.pixels {
+			if let Some(owner) = p.owner.as_ref() {
+				let (count, value) = stats.entry(owner).or_default();
+				*count += 1;
+				*value = value.saturating_add(p.price);
+			}
+		}
+		
+		let mut board: Vec<OwnerStats> = stats
+			.into_iter()
+			.map(|(owner, (pixel_count, total_value))| OwnerStats {
+				owner: owner.clone(),
+				pixel_count,
+				total_value,
+			})
+			.collect();
+		// BTreeMap 已按地址排序，稳定排序保证并列时按地址升序
+		board.sort_by(|a, b| {
+			b.pixel_count
+				.cmp(&a.pixel_count)
+				.then(b.total_value.cmp(&a.total_value))
+		});
+		board.truncate(limit.min(MAX_LEADERBOARD_LEN));
+		board
+	}
+	
+	/// 项目方累计收入（来自无主像素的首次出售）
+	pub fn project_revenue(&self) -> u128 {
+		self.project_revenue
+	}
+	
+	/// 各原占有者累计应得的转售收入
+	pub fn owner_revenue(&self) -> &BTreeMap<AccountId, u128> {
+		&self.owner_revenue
+	}
+	
+	/// 将整张画布按行主序编码为 PNG；宽或高为 0 时返回空 vec
+	pub fn to_png(&self) -> Vec<u8> {
+		let rgba: Vec<u8> = self.pixels.iter().flat_map(|p| p.color.channels()).collect();
+		super::png::encode_rgba(self.width, self.height, &rgba)
+	}
+	
+	/// [`Canvas::to_png`] 输出的字节数，无需编码
+	pub fn png_len(&self) -> usize {
+		super::png::encoded_len(self.width, self.height)
+	}
+	
+	/// 将整张画布的颜色按行主序做游程编码：(颜色, 连续像素数)，游程可跨行。
+	///
+	/// 颜色使用 RGBA，保留透明度；纯色或稀疏的画布编码后远小于逐像素数据。
+	pub fn to_rle(&self) -> Vec<(Rgba8888, u32)> {
+		let mut runs: Vec<(Rgba8888, u32)> = Vec::new();
+		for pixel in &self.pixels {
+			match runs.last_mut() {
+				Some((color, len)) if *color == pixel.color && *len < u32::MAX => *len += 1,
+				_ => runs.push((pixel.color, 1)),
+			}
+		}
+		runs
+	}
+	
+	/// 画布状态的 Merkle 根：按行主序对每个像素的 owner、price、color 建树（叶子定义见 `merkle` 模块）
+	pub fn merkle_root(&self) -> [u8; 32] {
+		merkle::root(self.pixels.iter().map(merkle::leaf_hash).collect())
+	}
+	
+	/// 整棵 Merkle 树，根即 [`Canvas::merkle_root`]
+	pub(crate) fn merkle_tree(&self) -> merkle::Tree {
+		merkle::Tree::new(self.pixels.iter().map(merkle::leaf_hash).collect())
+	}
+	
+	/// 把 `previous` 的 Merkle 树 `tree` 更新为本画布的树：像素数不变时只重算叶子变了的像素，否则整棵重建
+	pub(crate) fn update_merkle_tree(&self, previous: &Canvas, tree: &mut merkle::Tree) {
+		if previous.pixels.len() != self.pixels.len() {
+			*tree = self.merkle_tree();
+			return;
+		}
+		for (i, (old, new)) in previous.pixels.iter().zip(&self.pixels).enumerate() {
+			if old.owner != new.owner || old.price != new.price || old.color != new.color {
+				tree.update(i, merkle::leaf_hash(new));
+			}
+		}
+	}
+	
+	/// 像素 (x,y) 对应叶子的 Merkle 证明，可据此对照 [`Canvas::merkle_root`] 验证单个像素
+	pub fn merkle_proof(&self, x: usize, y: usize) -> Result<Vec<[u8; 32]>, CanvasError> {
+		let i = self.idx(x, y)?;
+		Ok(merkle::proof(self.pixels.iter().map(merkle::leaf_hash).collect(), i))
+	}
+	
 	// ─── 业务接口 ───────────────────────
 	
-	/// 仅改变颜色，不涉及价格与 ownership
-	pub fn set_color(&mut self, x: usize, y: usize, color: Rgb888) -> Result<(), CanvasError> {
+	/// 管理接口：调整画布尺寸，保留新边界内的像素（坐标不变），新增区域用 `fill` 填充。
+	///
+	/// 返回因缩小而被移除的像素及其原坐标，供调用方做退款处理；
+	/// 新尺寸超过 `max_canvas_pixels` 时返回 `TooLarge`，画布保持不变。
+	pub fn resize(
+		&mut self,
+		new_width: usize,
+		new_height: usize,
+		fill: Pixel,
+	) -> Result<Vec<(usize, usize, Pixel)>, CanvasError> {
+		let len = Self::checked_len(new_width, new_height)?;
+		let old_width = self.width;
+		let mut old = std::mem::take(&mut self.pixels).into_iter().map(Some).collect::<Vec<_>>();
+		
+		let mut pixels = Vec::with_capacity(len);
+		for y in 0..new_height {
+			for x in 0..new_width {
+				let kept = if x < old_width && y < self.height {
+					old[x + y * old_width].take()
+				} else {
+					None
+				};
+				pixels.push(kept.unwrap_or_else(|| fill.clone()));
+			}
+		}
+		
+		// 剩下未被取走的即为落在新边界之外的像素
+		let dropped = old
+			.into_iter()
+			.enumerate()
+			.filter_map(|(i, p)| p.map(|p| (i % old_width, i / old_width, p)))
+			.collect();
+		
+		self.width = new_width;
+		self.height = new_height;
+		self.pixels = pixels;
+		self.history.retain(|&(x, y), _| x < new_width && y < new_height);
+		Ok(dropped)
+	}
+	
+	/// 管理接口：设置同一像素两次成交之间的冷却时间（纳秒），传入 None 取消限制
+	pub fn set_cooldown(&mut self, cooldown_ns: Option<u64>) {
+		self.cooldown_ns = cooldown_ns;
+	}
+	
+	/// 管理接口：设置像素标价上限，传入 None 取消上限。
+	/// 只在之后的成交调价时生效，已有标价不会被立即调整。
+	pub fn set_max_price(&mut self, max_price: Option<u128>) {
+		self.max_price = max_price;
+	}
+	
+	/// 管理接口：设置调价溢出时的处理方式
+	pub fn set_price_overflow_policy(&mut self, policy: PriceOverflowPolicy) {
+		self.price_overflow_policy = policy;
+	}
+	
+	/// 成交后的新标价：按调价策略计算并受标价上限约束。
+	/// 溢出时按 [`PriceOverflowPolicy`] 处理，有标价上限时直接取上限。
+	fn bumped_price(&self, current: u128) -> Result<u128, CanvasError> {
+		let next = match self.price_strategy.checked_next_price(current) {
+			Some(next) => next,
+			None => match (self.max_price, self.price_overflow_policy) {
+				(Some(cap), _) => cap,
+				(None, PriceOverflowPolicy::Saturate) => u128::MAX,
+				(None, PriceOverflowPolicy::Reject) => return Err(CanvasError::PriceOverflow),
+			},
+		};
+		Ok(next.min(self.max_price.unwrap_or(u128::MAX)))
+	}
+	
+	/// 管理接口：设置每个账户的购买频率限制，传入 None 取消限制
+	pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+		self.rate_limit = rate_limit;
+		self.purchase_windows.clear();
+	}
+	
+	/// 管理接口：设置调色板，传入 None 取消限制。已有像素的颜色不受影响。
+	pub fn set_palette(&mut self, palette: Option<Vec<Rgba8888>>) {
+		self.palette = palette;
+	}
+	
+	/// 管理接口：从 (x0,y0) 起写入 `width`×`height` 的 RGB 图块，`data` 为按行主序排列的 RGB 三元组。
+	///
+	/// 只设置颜色（不透明），不改变所有权与价格；要求 `data.len() == width*height*3`、
+	/// 图块完全落在画布内且颜色均在调色板内，任一校验失败时画布保持不变。
+	pub fn import_rgb(
+		&mut self,
+		x0: usize,
+		y0: usize,
+		width: usize,
+		height: usize,
+		data: &[u8],
+	) -> Result<(), CanvasError> {
+		let expected = width.saturating_mul(height).saturating_mul(3);
+		if data.len() != expected {
+			return Err(CanvasError::DataSizeMismatch { expected: expected as u64 });
+		}
+		let x1 = x0.checked_add(width).and_then(|v| v.checked_sub(1)).ok_or(CanvasError::OutOfBounds)?;
+		let y1 = y0.checked_add(height).and_then(|v| v.checked_sub(1)).ok_or(CanvasError::OutOfBounds)?;
+		let indices = self.rect_indices(x0, y0, x1, y1)?;
+		
+		let colors: Vec<Rgba8888> = data
+			.chunks_exact(3)
+			.map(|rgb| Rgba8888::from(Rgb888(u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]]))))
+			.collect();
+		for &color in &colors {
+			self.check_color(color)?;
+		}
+		
+		for (i, color) in indices.into_iter().zip(colors) {
+			self.pixels[i].color = color;
+		}
+		Ok(())
+	}
+	
+	/// 仅改变颜色，不涉及价格与 ownership；只有像素占有者可以改色。
+	///
+	/// 新颜色按 `mode` 与原有颜色合成，设置了调色板时合成结果必须在调色板内。
+	/// 无主像素归项目方所有，不能通过此接口改色。
+	pub fn set_color(
+		&mut self,
+		x: usize,
+		y: usize,
+		caller: AccountId,
+		color: Rgba8888,
+		mode: BlendMode,
+	) -> Result<(), CanvasError> {
+		let pix = self.pixel(x, y)?;
+		if pix.owner.as_ref() != Some(&caller) {
+			return Err(CanvasError::NotOwner);
+		}
+		let color = color.blend(pix.color, mode);
+		self.check_color(color)?;
 		self.pixel_mut(x, y)?.color = color;
 		Ok(())
 	}
 	
+	/// 设置像素留言，只有像素占有者可以设置；传入 None 清除留言
+	pub fn set_memo(
+		&mut self,
+		x: usize,
+		y: usize,
+		caller: AccountId,
+		memo: Option<String>,
+	) -> Result<(), CanvasError> {
+		if memo.as_ref().is_some_and(|m| m.len() > MAX_MEMO_LEN) {
+			return Err(CanvasError::MemoTooLong { max: MAX_MEMO_LEN as u64 });
+		}
+		let pix = self.pixel_mut(x, y)?;
+		if pix.owner.as_ref() != Some(&caller) {
+			return Err(CanvasError::NotOwner);
+		}
+		pix.memo = memo;
+		Ok(())
+	}
+	
+	/// 赠送 / 转让像素：`from` 必须是当前占有者，所有权交给 `to`，价格与颜色不变。
+	///
+	/// 留言属于原占有者，随所有权转移一并清空；无主像素不能转让。
+	pub fn transfer_pixel(
+		&mut self,
+		x: usize,
+		y: usize,
+		from: AccountId,
+		to: AccountId,
+	) -> Result<(), CanvasError> {
+		let pix = self.pixel_mut(x, y)?;
+		if pix.owner.as_ref() != Some(&from) {
+			return Err(CanvasError::NotOwner);
+		}
+		pix.owner = Some(to);
+		pix.memo = None;
+		Ok(())
+	}
+	
+	/// 释放像素：`owner` 必须是当前占有者，像素回归项目方（无主）并恢复其初始标价
+	/// （见 [`Canvas::base_price`]）。
+	///
+	/// 留言随之清空；`reset_color` 为 true 时颜色同时恢复为白色 (#FFFFFFFF)。
+	pub fn release_pixel(
+		&mut self,
+		x: usize,
+		y: usize,
+		owner: AccountId,
+		reset_color: bool,
+	) -> Result<(), CanvasError> {
+		let base_price = self.idx(x, y).map(|_| self.base_price(x, y))?;
+		let pix = self.pixel_mut(x, y)?;
+		if pix.owner.as_ref() != Some(&owner) {
+			return Err(CanvasError::NotOwner);
+		}
+		pix.owner = None;
+		pix.price = base_price;
+		pix.memo = None;
+		if reset_color {
+			pix.color = Rgba8888(0xFFFF_FFFF);
+		}
+		Ok(())
+	}
+	
 	/// 购买像素：支付金额需 ≥ 当前价；成功后
-	///   * 所有权转移
-	///   * 像素价格可按策略上调（下例简单翻倍，可自行改为 +Δ 或乘常数）
+	///   * 所有权转移（原占有者的留言随之清空）
+	///   * 像素价格按画布的 [`PriceStrategy`] 上调，但不超过标价上限；
+	///     调价溢出且策略为 [`PriceOverflowPolicy::Reject`] 时购买失败
 	///   * 同时设置像素颜色
 	///
-	/// 在链上时应由调用方完成余额扣减 / 资产转移，再回调此逻辑。
+	/// 在链上时应由调用方完成余额扣减 / 资产转移，再回调此逻辑；
+	/// 返回的 [`PixelPayout`] 告诉调用方 `amount_paid` 中多少记入原占有者（或项目方），多少作为溢价记入项目方。
+	///
+	/// 画布设置了冷却时间时，距该像素上次成交不足冷却时间的购买会被拒绝；
+	/// 设置了频率限制时，`buyer` 在当前窗口内超出次数的购买也会被拒绝；
+	/// `exempt` 为 true（由 controller 授予）时跳过这两项限制。
 	pub fn buy_pixel(
 		&mut self,
 		x: usize,
 		y: usize,
 		buyer: AccountId,
 		amount_paid: u128,
-		new_color: Rgb888,
-	) -> Result<(), CanvasError> {
-		let pix = self.pixel_mut(x, y)?;
-		
-		if amount_paid < pix.price {
-			return Err(CanvasError::PriceTooLow {
-				required: pix.price,
-			});
+		new_color: Rgba8888,
+		exempt: bool,
+	) -> Result<PixelPayout, CanvasError> {
+		self.check_color(new_color)?;
+		let i = self.idx(x, y)?;
+		let now = super::now();
+		if !exempt {
+			self.check_cooldown(i, now)?;
+			self.check_rate_limit(&buyer, 1, now)?;
+		}
+		let price = self.pixels[i].price;
+		if amount_paid < price {
+			return Err(CanvasError::PriceTooLow { required: price });
 		}
+		let next_price = self.bumped_price(price)?;
 		
-		pix.owner = Some(buyer);
+		let pix = &mut self.pixels[i];
+		let previous_owner = pix.owner.replace(buyer.clone());
 		pix.color = new_color;
-		pix.price = Self::next_price(pix.price);
-		Ok(())
+		pix.memo = None;
+		pix.price = next_price;
+		pix.last_bought = now;
+		let overpay = amount_paid - price;
+		self.credit_revenue(previous_owner.as_ref(), price);
+		self.credit_revenue(None, overpay);
+		if !exempt {
+			self.record_purchases(&buyer, 1, now);
+		}
+		self.record_event(
+			x,
+			y,
+			PixelEvent {
+				timestamp: now,
+				buyer,
+				price_paid: amount_paid,
+				color: new_color.0,
+			},
+		);
+		Ok(PixelPayout {
+			previous_owner,
+			payout_amount: price,
+			overpay,
+		})
+	}
+	
+	/// 批量购买闭区间矩形 [x0,x1]×[y0,y1]：`per_pixel_paid` 是买家愿为单个像素支付的上限，
+	/// 每个像素实际按其当前标价收费，原占有者（或项目方）也只记入该标价，差额由调用方退还。
+	///
+	/// 先校验全部像素的标价（及冷却时间与频率限制，`exempt` 含义同 [`Canvas::buy_pixel`]），
+	/// 全部通过后才统一修改所有权、颜色与价格；任意一个像素校验失败时画布保持不变。
+	///
+	/// `color` 按 `mode` 与每个像素原有的颜色合成，设置了调色板时每个合成结果都必须在调色板内。
+	pub fn buy_region(
+		&mut self,
+		x0: usize,
+		y0: usize,
+		x1: usize,
+		y1: usize,
+		buyer: AccountId,
+		per_pixel_paid: u128,
+		color: Rgba8888,
+		mode: BlendMode,
+		exempt: bool,
+	) -> Result<RegionPurchase, CanvasError> {
+		let indices = self.rect_indices(x0, y0, x1, y1)?;
+		let timestamp = super::now();
+		
+		// 第一遍：只读校验，累计所需总额；区域内每个像素都计入频率限制
+		if !exempt {
+			self.check_rate_limit(&buyer, indices.len(), timestamp)?;
+		}
+		let mut total_required: u128 = 0;
+		let mut updates = Vec::with_capacity(indices.len());
+		for &i in &indices {
+			if !exempt {
+				self.check_cooldown(i, timestamp)?;
+			}
+			let price = self.pixels[i].price;
+			if per_pixel_paid < price {
+				return Err(CanvasError::PriceTooLow { required: price });
+			}
+			let color = color.blend(self.pixels[i].color, mode);
+			self.check_color(color)?;
+			total_required = total_required.saturating_add(price);
+			updates.push((price, self.bumped_price(price)?, color));
+		}
+		
+		// 第二遍：校验已全部通过，统一写入
+		let mut previous_owners = BTreeSet::new();
+		for (&i, (price, next_price, color)) in indices.iter().zip(updates) {
+			let pix = &mut self.pixels[i];
+			let previous_owner = pix.owner.replace(buyer.clone());
+			pix.color = color;
+			pix.memo = None;
+			pix.price = next_price;
+			pix.last_bought = timestamp;
+			self.credit_revenue(previous_owner.as_ref(), price);
+			if let Some(prev) = previous_owner {
+				previous_owners.insert(prev);
+			}
+			self.record_event(
+				i % self.width,
+				i / self.width,
+				PixelEvent {
+					timestamp,
+					buyer: buyer.clone(),
+					price_paid: price,
+					color: color.0,
+				},
+			);
+		}
+		
+		if !exempt {
+			self.record_purchases(&buyer, indices.len(), timestamp);
+		}
+		
+		Ok(RegionPurchase {
+			total_required,
+			previous_owners,
+		})
 	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::canvas::{
+		DEFAULT_MAX_CANVAS_PIXELS, MAX_CANVAS_PIXELS, max_canvas_pixels, set_max_canvas_pixels, set_now,
+	};
 	
-	/// 定义价格递增策略（示例：*2）
-	fn next_price(current: u128) -> u128 {
-		current.saturating_mul(2)
+	const RED: Rgba8888 = Rgba8888(0xFF00_00FF);
+	
+	/// 宽 `width`、高 `height`，初始标价 1000、成交后翻倍的画布
+	fn canvas(width: usize, height: usize) -> Canvas {
+		Canvas::new(width, height, 1_000, PriceStrategy::Double, vec![]).unwrap()
 	}
-}
\ No newline at end of file
+	
+	#[test]
+	fn pixel_cap_bounds_new_and_resized_canvases() {
+		let cap = max_canvas_pixels();
+		assert_eq!(cap, DEFAULT_MAX_CANVAS_PIXELS);
+		let too_large = Canvas::new(513, 512, 1_000, PriceStrategy::Double, vec![]);
+		assert!(matches!(too_large, Err(CanvasError::TooLarge { max }) if max == cap));
+		
+		// 上限既不能超过 MAX_CANVAS_PIXELS，也不能低于当前 100×100 的画布
+		assert!(set_max_canvas_pixels(MAX_CANVAS_PIXELS + 1).is_err());
+		assert!(set_max_canvas_pixels(9_999).is_err());
+		set_max_canvas_pixels(10_000).unwrap();
+		let mut c = canvas(2, 2);
+		let fill = c.pixel(0, 0).unwrap().clone();
+		assert!(matches!(c.resize(101, 100, fill.clone()), Err(CanvasError::TooLarge { max: 10_000 })));
+		assert!(c.resize(100, 100, fill).is_ok());
+	}
+	
+	#[test]
+	fn cooldown_holds_back_a_second_buy_within_the_window() {
+		let mut c = canvas(1, 1);
+		c.cooldown_ns = Some(100);
+		set_now(1_000);
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		
+		set_now(1_099);
+		let result = c.buy_pixel(0, 0, "bob".to_string(), 2_000, RED, false);
+		assert_eq!(result, Err(CanvasError::Cooldown { retry_after: 1_100 }));
+		// controller 购买不受冷却限制
+		assert!(c.clone().buy_pixel(0, 0, "bob".to_string(), 2_000, RED, true).is_ok());
+		
+		set_now(1_100);
+		assert!(c.buy_pixel(0, 0, "bob".to_string(), 2_000, RED, false).is_ok());
+	}
+	
+	#[test]
+	fn region_credits_each_owner_the_price_of_their_pixel() {
+		let mut c = canvas(2, 1);
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		
+		let purchase = c
+			.buy_region(0, 0, 1, 0, "bob".to_string(), 2_000, RED, BlendMode::Replace, true)
+			.unwrap();
+		assert_eq!(purchase.total_required, 3_000);
+		assert_eq!(purchase.previous_owners, BTreeSet::from(["alice".to_string()]));
+		assert_eq!(c.owner_revenue.get("alice"), Some(&2_000));
+		assert_eq!(c.project_revenue, 2_000);
+		let paid: Vec<u128> = [(0, 0), (1, 0)]
+			.iter()
+			.map(|xy| c.history[xy].last().unwrap().price_paid)
+			.collect();
+		assert_eq!(paid, vec![2_000, 1_000]);
+		assert_eq!(c.prices(&[(0, 0), (1, 0)]), vec![Ok(4_000), Ok(2_000)]);
+	}
+	
+	#[test]
+	fn region_below_any_price_changes_nothing() {
+		let mut c = canvas(2, 1);
+		c.buy_pixel(1, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		let before = c.snapshot();
+		
+		let result = c.buy_region(0, 0, 1, 0, "bob".to_string(), 1_000, RED, BlendMode::Replace, true);
+		assert_eq!(result, Err(CanvasError::PriceTooLow { required: 2_000 }));
+		assert_eq!(c.snapshot(), before);
+	}
+	
+	#[test]
+	fn payout_goes_to_the_project_first_then_to_the_previous_owner() {
+		let mut c = canvas(1, 1);
+		let first = c.buy_pixel(0, 0, "alice".to_string(), 1_500, RED, true).unwrap();
+		assert_eq!(
+			first,
+			PixelPayout {
+				previous_owner: None,
+				payout_amount: 1_000,
+				overpay: 500,
+			}
+		);
+		assert_eq!(c.project_revenue, 1_500);
+		
+		let resale = c.buy_pixel(0, 0, "bob".to_string(), 2_000, RED, true).unwrap();
+		assert_eq!(
+			resale,
+			PixelPayout {
+				previous_owner: Some("alice".to_string()),
+				payout_amount: 2_000,
+				overpay: 0,
+			}
+		);
+		assert_eq!(c.owner_revenue.get("alice"), Some(&2_000));
+		assert_eq!(c.pixel(0, 0).unwrap().owner.as_deref(), Some("bob"));
+	}
+	
+	#[test]
+	fn each_price_strategy_steps_and_saturates() {
+		let cases = [
+			(PriceStrategy::Double, 2_000, u128::MAX),
+			(PriceStrategy::Linear { delta: 250 }, 1_250, u128::MAX),
+			(PriceStrategy::Multiplier { num: 3, den: 2 }, 1_500, u128::MAX),
+			// 先乘溢出时改为先除后乘
+			(PriceStrategy::Multiplier { num: 2, den: 3 }, 666, u128::MAX / 3 * 2),
+			(PriceStrategy::Multiplier { num: 1, den: 0 }, 1_000, u128::MAX),
+			(PriceStrategy::Fixed, 1_000, u128::MAX),
+		];
+		for (strategy, next, saturated) in cases {
+			assert_eq!(strategy.next_price(1_000), next, "{:?}", strategy);
+			assert_eq!(strategy.next_price(u128::MAX), saturated, "{:?}", strategy);
+		}
+		assert_eq!(PriceStrategy::Double.checked_next_price(u128::MAX), None);
+		assert_eq!(PriceStrategy::Fixed.checked_next_price(u128::MAX), Some(u128::MAX));
+		
+		let mut c = Canvas::new(1, 1, 1_000, PriceStrategy::Linear { delta: 250 }, vec![]).unwrap();
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().price, 1_250);
+	}
+	
+	#[test]
+	fn region_rolls_back_when_a_middle_pixel_is_too_expensive() {
+		let mut c = canvas(3, 3);
+		c.buy_pixel(1, 1, "alice".to_string(), 1_000, RED, true).unwrap();
+		let before = c.snapshot();
+		
+		// (1,1) 的标价已翻倍，排在它之前的像素也不能被买走
+		let result = c.buy_region(0, 0, 2, 2, "bob".to_string(), 1_500, RED, BlendMode::Replace, true);
+		assert_eq!(result, Err(CanvasError::PriceTooLow { required: 2_000 }));
+		assert_eq!(c.snapshot(), before);
+		
+		for (x0, y0, x1, y1) in [(0, 0, 3, 2), (2, 0, 1, 2), (0, 2, 2, 0)] {
+			let result = c.buy_region(x0, y0, x1, y1, "bob".to_string(), 5_000, RED, BlendMode::Replace, true);
+			assert_eq!(result, Err(CanvasError::OutOfBounds));
+		}
+		assert_eq!(c.snapshot(), before);
+	}
+	
+	#[test]
+	fn neighbors_stop_at_the_edges() {
+		let c = canvas(3, 3);
+		assert_eq!(c.neighbors(0, 0, false), vec![(1, 0), (0, 1)]);
+		assert_eq!(c.neighbors(0, 0, true), vec![(1, 0), (0, 1), (1, 1)]);
+		assert_eq!(c.neighbors(1, 1, false), vec![(1, 0), (0, 1), (2, 1), (1, 2)]);
+		assert_eq!(c.neighbors(1, 1, true).len(), 8);
+		assert_eq!(c.neighbors(2, 2, false), vec![(2, 1), (1, 2)]);
+		assert!(c.neighbors(3, 0, true).is_empty());
+		assert!(canvas(1, 1).neighbors(0, 0, true).is_empty());
+	}
+	
+	#[test]
+	fn restore_rebuilds_the_snapshotted_canvas() {
+		let mut c = Canvas::new(2, 2, 1_000, PriceStrategy::Linear { delta: 10 }, vec![]).unwrap();
+		c.set_cooldown(Some(50));
+		c.set_max_price(Some(5_000));
+		c.set_rate_limit(Some(RateLimit { max_purchases: 3, window_ns: 100 }));
+		set_now(1_000);
+		c.buy_pixel(1, 0, "alice".to_string(), 1_200, RED, false).unwrap();
+		c.set_memo(1, 0, "alice".to_string(), Some("gm".to_string())).unwrap();
+		c.buy_pixel(0, 1, "bob".to_string(), 1_000, RED, true).unwrap();
+		
+		let snapshot = c.snapshot();
+		let restored = Canvas::restore(snapshot.clone()).unwrap();
+		assert_eq!(restored.snapshot(), snapshot);
+		assert_eq!(restored.merkle_root(), c.merkle_root());
+		assert_eq!(restored.owner_revenue(), c.owner_revenue());
+		// 成交记录不在快照内
+		assert!(restored.pixel_history(1, 0).unwrap().is_empty());
+		
+		let mut short = snapshot.clone();
+		short.pixels.pop();
+		assert_eq!(Canvas::restore(short).err(), Some(CanvasError::DataSizeMismatch { expected: 4 }));
+		let mut huge = snapshot;
+		huge.width = 1 << 20;
+		assert!(matches!(Canvas::restore(huge), Err(CanvasError::TooLarge { .. })));
+	}
+	
+	#[test]
+	fn single_and_region_buys_book_the_price_to_the_owner() {
+		let mut single = canvas(1, 1);
+		single.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		let mut region = single.clone();
+		
+		// 单个购买多付的 500 归项目方；区域购买只按标价收费，差额退还买家
+		let payout = single.buy_pixel(0, 0, "bob".to_string(), 2_500, RED, true).unwrap();
+		assert_eq!((payout.payout_amount, payout.overpay), (2_000, 500));
+		let purchase = region
+			.buy_region(0, 0, 0, 0, "bob".to_string(), 2_500, RED, BlendMode::Replace, true)
+			.unwrap();
+		assert_eq!(purchase.total_required, 2_000);
+		
+		for (c, charged) in [(&single, 2_500), (&region, 2_000)] {
+			assert_eq!(c.owner_revenue().get("alice"), Some(&2_000));
+			assert_eq!(c.project_revenue(), 1_000 + charged - 2_000);
+		}
+	}
+	
+	/// 行主序的颜色，便于比较尺寸调整前后的布局
+	fn colors(c: &Canvas) -> Vec<u32> {
+		c.pixels.iter().map(|p| p.color.0).collect()
+	}
+	
+	#[test]
+	fn resize_grows_shrinks_and_keeps_pixels_in_place() {
+		let mut c = canvas(2, 2);
+		for (i, (x, y)) in [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter().enumerate() {
+			c.buy_pixel(x, y, format!("owner-{}", i), 1_000, Rgba8888(i as u32), true).unwrap();
+		}
+		let fill = || Pixel {
+			owner: None,
+			price: 7,
+			color: Rgba8888(0xAA),
+			last_bought: 0,
+			memo: None,
+		};
+		
+		// 尺寸不变时原样保留
+		let before = c.snapshot();
+		assert!(c.resize(2, 2, fill()).unwrap().is_empty());
+		assert_eq!(c.snapshot(), before);
+		
+		// 扩大：原像素坐标不变，新增的位置用 fill 填充
+		assert!(c.resize(3, 3, fill()).unwrap().is_empty());
+		assert_eq!(colors(&c), vec![0, 1, 0xAA, 2, 3, 0xAA, 0xAA, 0xAA, 0xAA]);
+		assert_eq!(c.pixel(1, 1).unwrap().owner.as_deref(), Some("owner-3"));
+		assert_eq!(c.pixel(2, 2).unwrap().price, 7);
+		
+		// 缩小：落在新边界外的像素连同原坐标返回
+		let dropped = c.resize(1, 2, fill()).unwrap();
+		assert_eq!(colors(&c), vec![0, 2]);
+		let dropped: Vec<(usize, usize, u32)> = dropped.into_iter().map(|(x, y, p)| (x, y, p.color.0)).collect();
+		assert_eq!(
+			dropped,
+			vec![(1, 0, 1), (2, 0, 0xAA), (1, 1, 3), (2, 1, 0xAA), (0, 2, 0xAA), (1, 2, 0xAA), (2, 2, 0xAA)]
+		);
+		assert!(c.pixel_history(1, 1).is_err());
+		assert_eq!(c.pixel_history(0, 1).unwrap().len(), 1);
+	}
+	
+	#[test]
+	fn only_the_owner_recolors_a_pixel() {
+		let mut c = canvas(2, 1);
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		let blue = Rgba8888(0x0000_FFFF);
+		
+		c.set_color(0, 0, "alice".to_string(), blue, BlendMode::Replace).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().color, blue);
+		assert_eq!(c.set_color(0, 0, "bob".to_string(), RED, BlendMode::Replace), Err(CanvasError::NotOwner));
+		assert_eq!(c.pixel(0, 0).unwrap().color, blue);
+		// 无主像素归项目方，任何地址都不能改色
+		assert_eq!(c.set_color(1, 0, "alice".to_string(), RED, BlendMode::Replace), Err(CanvasError::NotOwner));
+		assert_eq!(c.set_color(2, 0, "alice".to_string(), RED, BlendMode::Replace), Err(CanvasError::OutOfBounds));
+	}
+	
+	#[test]
+	fn palette_rejects_colors_outside_it() {
+		let blue = Rgba8888(0x0000_FFFF);
+		let mut c = canvas(2, 1);
+		c.set_palette(Some(vec![RED]));
+		
+		assert_eq!(c.buy_pixel(0, 0, "alice".to_string(), 1_000, blue, true), Err(CanvasError::ColorNotAllowed));
+		assert!(c.pixel(0, 0).unwrap().owner.is_none());
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		assert_eq!(c.set_color(0, 0, "alice".to_string(), blue, BlendMode::Replace), Err(CanvasError::ColorNotAllowed));
+		let region = c.buy_region(1, 0, 1, 0, "alice".to_string(), 1_000, blue, BlendMode::Replace, true);
+		assert_eq!(region, Err(CanvasError::ColorNotAllowed));
+		
+		// 取消调色板后任何颜色都可以
+		c.set_palette(None);
+		c.set_color(0, 0, "alice".to_string(), blue, BlendMode::Replace).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().color, blue);
+	}
+	
+	#[test]
+	fn history_keeps_only_the_latest_events() {
+		let mut c = Canvas::new(1, 1, 1, PriceStrategy::Fixed, vec![]).unwrap();
+		let buys = PIXEL_HISTORY_LIMIT as u64 + 3;
+		for n in 0..buys {
+			set_now(n);
+			c.buy_pixel(0, 0, format!("buyer-{}", n), 1 + n as u128, RED, true).unwrap();
+		}
+		
+		let history = c.pixel_history(0, 0).unwrap();
+		assert_eq!(history.len(), PIXEL_HISTORY_LIMIT);
+		let timestamps: Vec<u64> = history.iter().map(|e| e.timestamp).collect();
+		assert_eq!(timestamps, (3..buys).collect::<Vec<_>>());
+		let last = history.last().unwrap();
+		assert_eq!((last.buyer.clone(), last.price_paid, last.color), (format!("buyer-{}", buys - 1), buys as u128, RED.0));
+		assert!(c.pixel_history(1, 0).is_err());
+	}
+	
+	#[test]
+	fn only_the_owner_sets_a_memo_of_bounded_length() {
+		let mut c = canvas(1, 1);
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		
+		let longest = "m".repeat(MAX_MEMO_LEN);
+		c.set_memo(0, 0, "alice".to_string(), Some(longest.clone())).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().view(0, 0).memo, Some(longest));
+		let too_long = Some("m".repeat(MAX_MEMO_LEN + 1));
+		let max = MAX_MEMO_LEN as u64;
+		assert_eq!(c.set_memo(0, 0, "alice".to_string(), too_long), Err(CanvasError::MemoTooLong { max }));
+		assert_eq!(c.set_memo(0, 0, "bob".to_string(), Some("gm".to_string())), Err(CanvasError::NotOwner));
+		
+		// 留言随成交清空
+		c.buy_pixel(0, 0, "bob".to_string(), 2_000, RED, true).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().memo, None);
+	}
+	
+	#[test]
+	fn transfer_hands_over_ownership_but_not_price_or_color() {
+		let mut c = canvas(2, 1);
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		c.set_memo(0, 0, "alice".to_string(), Some("gm".to_string())).unwrap();
+		
+		assert_eq!(c.transfer_pixel(0, 0, "bob".to_string(), "carol".to_string()), Err(CanvasError::NotOwner));
+		assert_eq!(c.transfer_pixel(1, 0, "alice".to_string(), "bob".to_string()), Err(CanvasError::NotOwner));
+		c.transfer_pixel(0, 0, "alice".to_string(), "bob".to_string()).unwrap();
+		let pix = c.pixel(0, 0).unwrap();
+		assert_eq!(pix.owner.as_deref(), Some("bob"));
+		assert_eq!((pix.price, pix.color, pix.memo.clone()), (2_000, RED, None));
+		// 转让不是成交，不记录历史也不计收入
+		assert_eq!(c.pixel_history(0, 0).unwrap().len(), 1);
+		assert!(c.owner_revenue().is_empty());
+	}
+	
+	#[test]
+	fn import_writes_a_block_of_opaque_colors() {
+		let mut c = canvas(3, 2);
+		c.buy_pixel(1, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		let data = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC];
+		
+		c.import_rgb(1, 0, 2, 2, &data).unwrap();
+		assert_eq!(colors(&c), vec![0xFFFF_FFFF, 0x1122_33FF, 0x4455_66FF, 0xFFFF_FFFF, 0x7788_99FF, 0xAABB_CCFF]);
+		// 只改颜色，不动所有权与价格
+		assert_eq!(c.pixel(1, 0).unwrap().owner.as_deref(), Some("alice"));
+		assert_eq!(c.pixel(1, 0).unwrap().price, 2_000);
+		
+		let before = colors(&c);
+		assert_eq!(c.import_rgb(0, 0, 2, 2, &data[..9]), Err(CanvasError::DataSizeMismatch { expected: 12 }));
+		assert_eq!(c.import_rgb(2, 0, 2, 2, &data), Err(CanvasError::OutOfBounds));
+		c.set_palette(Some(vec![Rgba8888(0x1122_33FF)]));
+		assert_eq!(c.import_rgb(1, 0, 2, 2, &data), Err(CanvasError::ColorNotAllowed));
+		assert_eq!(colors(&c), before);
+	}
+	
+	#[test]
+	fn rle_decodes_back_to_every_pixel() {
+		let mut c = canvas(3, 3);
+		// 第一行末尾与第二行开头同色，游程跨行
+		c.import_rgb(2, 0, 1, 1, &[0xFF, 0, 0]).unwrap();
+		c.import_rgb(0, 1, 2, 1, &[0xFF, 0, 0, 0xFF, 0, 0]).unwrap();
+		c.buy_pixel(2, 2, "alice".to_string(), 1_000, Rgba8888(0x0000_FF80), true).unwrap();
+		
+		let runs = c.to_rle();
+		let white = Rgba8888(0xFFFF_FFFF);
+		assert_eq!(runs, vec![(white, 2), (RED, 3), (white, 3), (Rgba8888(0x0000_FF80), 1)]);
+		let decoded: Vec<u32> = runs
+			.iter()
+			.flat_map(|(color, len)| std::iter::repeat_n(color.0, *len as usize))
+			.collect();
+		assert_eq!(decoded, colors(&c));
+	}
+	
+	#[test]
+	fn rate_limit_refuses_the_extra_buy_until_a_fresh_window() {
+		let mut c = Canvas::new(4, 1, 1_000, PriceStrategy::Fixed, vec![]).unwrap();
+		c.set_rate_limit(Some(RateLimit { max_purchases: 2, window_ns: 100 }));
+		set_now(1_000);
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		set_now(1_050);
+		c.buy_pixel(1, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		
+		let third = c.buy_pixel(2, 0, "alice".to_string(), 1_000, RED, false);
+		assert_eq!(third, Err(CanvasError::RateLimited { retry_after: 1_100 }));
+		// 限制按账户计算，controller 授予的豁免不受限制
+		c.buy_pixel(2, 0, "bob".to_string(), 1_000, RED, false).unwrap();
+		c.buy_pixel(3, 0, "alice".to_string(), 1_000, RED, true).unwrap();
+		// 区域内的每个像素都计入次数（bob 的窗口从 1_050 开始）
+		let region = c.buy_region(0, 0, 1, 0, "bob".to_string(), 1_000, RED, BlendMode::Replace, false);
+		assert_eq!(region, Err(CanvasError::RateLimited { retry_after: 1_150 }));
+		
+		set_now(1_100);
+		c.buy_pixel(2, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		c.buy_pixel(3, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		assert!(c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, false).is_err());
+	}
+	
+	#[test]
+	fn leaderboard_ranks_owners_by_count_then_value() {
+		let mut c = canvas(4, 1);
+		for x in 0..2 {
+			c.buy_pixel(x, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		}
+		c.buy_pixel(2, 0, "bob".to_string(), 1_000, RED, false).unwrap();
+		c.buy_pixel(3, 0, "carol".to_string(), 1_000, RED, false).unwrap();
+		// carol 从 alice 手中买走 (1,0)（新标价 4_000）；alice 与 bob 并列时按地址升序
+		c.buy_pixel(1, 0, "carol".to_string(), 2_000, RED, false).unwrap();
+		
+		let board = c.leaderboard(10);
+		let rows: Vec<(&str, u64, u128)> = board
+			.iter()
+			.map(|s| (s.owner.as_str(), s.pixel_count, s.total_value))
+			.collect();
+		assert_eq!(
+			rows,
+			vec![("carol", 2, 6_000), ("alice", 1, 2_000), ("bob", 1, 2_000)]
+		);
+		assert_eq!(c.leaderboard(1).len(), 1);
+		assert_eq!(c.leaderboard(usize::MAX).len(), 3);
+	}
+	
+	#[test]
+	fn capped_price_stays_flat_across_resales() {
+		let mut c = canvas(1, 1);
+		c.set_max_price(Some(3_000));
+		let mut paid = Vec::new();
+		for buyer in ["a", "b", "c", "d"] {
+			let price = c.pixel(0, 0).unwrap().price;
+			paid.push(price);
+			c.buy_pixel(0, 0, buyer.to_string(), price, RED, false).unwrap();
+		}
+		assert_eq!(paid, vec![1_000, 2_000, 3_000, 3_000]);
+		assert_eq!(c.pixel(0, 0).unwrap().price, 3_000);
+		
+		c.set_max_price(None);
+		c.buy_pixel(0, 0, "e".to_string(), 3_000, RED, false).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().price, 6_000);
+	}
+	
+	#[test]
+	fn only_the_owner_releases_a_pixel_back_to_its_initial_price() {
+		let mut c = canvas(2, 1);
+		c.buy_pixel(0, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		c.buy_pixel(0, 0, "bob".to_string(), 2_000, RED, false).unwrap();
+		c.set_memo(0, 0, "bob".to_string(), Some("gm".to_string())).unwrap();
+		
+		assert_eq!(c.release_pixel(0, 0, "alice".to_string(), true), Err(CanvasError::NotOwner));
+		assert_eq!(c.release_pixel(1, 0, "bob".to_string(), true), Err(CanvasError::NotOwner));
+		c.release_pixel(0, 0, "bob".to_string(), false).unwrap();
+		let pix = c.pixel(0, 0).unwrap();
+		assert_eq!((pix.owner.clone(), pix.price, pix.color, pix.memo.clone()), (None, 1_000, RED, None));
+		
+		// 释放后按初始标价再次出售，收入记入项目方
+		assert_eq!(
+			c.buy_pixel(0, 0, "carol".to_string(), 999, RED, false),
+			Err(CanvasError::PriceTooLow { required: 1_000 })
+		);
+		let payout = c.buy_pixel(0, 0, "carol".to_string(), 1_000, RED, false).unwrap();
+		assert_eq!((payout.previous_owner, payout.payout_amount), (None, 1_000));
+		
+		c.release_pixel(0, 0, "carol".to_string(), true).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().color, Rgba8888(0xFFFF_FFFF));
+	}
+	
+	#[test]
+	fn region_quote_matches_what_the_region_buy_charges() {
+		let mut c = canvas(3, 2);
+		c.buy_pixel(1, 0, "alice".to_string(), 1_000, RED, false).unwrap();
+		c.buy_pixel(1, 0, "bob".to_string(), 2_000, RED, false).unwrap();
+		
+		let quote = c.quote_region(0, 0, 2, 1).unwrap();
+		assert_eq!(quote.prices, vec![1_000, 4_000, 1_000, 1_000, 1_000, 1_000]);
+		assert_eq!((quote.total, quote.max_price), (9_000, 4_000));
+		// 报价只读：再次报价结果相同
+		assert_eq!(c.quote_region(0, 0, 2, 1).unwrap(), quote);
+		
+		let purchase = c
+			.buy_region(0, 0, 2, 1, "carol".to_string(), quote.max_price, RED, BlendMode::Replace, false)
+			.unwrap();
+		assert_eq!(purchase.total_required, quote.total);
+		assert_eq!(c.quote_region(1, 0, 1, 0).unwrap().total, 8_000);
+		assert_eq!(c.quote_region(2, 0, 0, 0), Err(CanvasError::OutOfBounds));
+	}
+	
+	#[test]
+	fn center_zones_start_pricier_and_release_back_to_their_zone_price() {
+		let zones = vec![
+			PriceZone { x0: 2, y0: 2, x1: 2, y1: 2, price: 9_000 },
+			PriceZone { x0: 1, y0: 1, x1: 3, y1: 3, price: 5_000 },
+		];
+		let mut c = Canvas::new(5, 5, 1_000, PriceStrategy::Double, zones).unwrap();
+		// 重叠时取第一个包含该像素的区域
+		let prices: Vec<u128> = c.prices(&[(2, 2), (1, 1), (3, 2), (0, 0), (4, 4)]).into_iter().map(Result::unwrap).collect();
+		assert_eq!(prices, vec![9_000, 5_000, 5_000, 1_000, 1_000]);
+		
+		assert_eq!(
+			c.buy_pixel(1, 1, "alice".to_string(), 1_000, RED, false),
+			Err(CanvasError::PriceTooLow { required: 5_000 })
+		);
+		c.buy_pixel(1, 1, "alice".to_string(), 5_000, RED, false).unwrap();
+		assert_eq!(c.pixel(1, 1).unwrap().price, 10_000);
+		c.release_pixel(1, 1, "alice".to_string(), false).unwrap();
+		assert_eq!(c.pixel(1, 1).unwrap().price, 5_000);
+		
+		// 替换区域只重置无主像素
+		c.buy_pixel(0, 0, "bob".to_string(), 1_000, RED, false).unwrap();
+		c.set_price_zones(vec![PriceZone { x0: 0, y0: 0, x1: 4, y1: 0, price: 3_000 }]).unwrap();
+		assert_eq!(c.prices(&[(0, 0), (1, 0), (1, 1)]), vec![Ok(2_000), Ok(3_000), Ok(1_000)]);
+		assert_eq!(
+			c.set_price_zones(vec![PriceZone { x0: 0, y0: 0, x1: 5, y1: 0, price: 3_000 }]),
+			Err(CanvasError::OutOfBounds)
+		);
+	}
+	
+	#[test]
+	fn overflowing_price_saturates_or_rejects_per_policy() {
+		let top = u128::MAX / 2 + 1; // 翻倍即溢出
+		let mut c = Canvas::new(1, 1, top, PriceStrategy::Double, vec![]).unwrap();
+		c.set_price_overflow_policy(PriceOverflowPolicy::Reject);
+		assert_eq!(
+			c.buy_pixel(0, 0, "alice".to_string(), top, RED, false),
+			Err(CanvasError::PriceOverflow)
+		);
+		assert_eq!(c.pixel(0, 0).unwrap().owner, None);
+		
+		// 有标价上限时溢出直接取上限，不会失败
+		c.set_max_price(Some(top));
+		c.buy_pixel(0, 0, "alice".to_string(), top, RED, false).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().price, top);
+		
+		c.set_max_price(None);
+		c.set_price_overflow_policy(PriceOverflowPolicy::Saturate);
+		c.buy_pixel(0, 0, "bob".to_string(), top, RED, false).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().price, u128::MAX);
+		
+		// 恰好不溢出的一步在两种策略下结果相同
+		let mut c = Canvas::new(1, 1, u128::MAX / 2, PriceStrategy::Double, vec![]).unwrap();
+		c.set_price_overflow_policy(PriceOverflowPolicy::Reject);
+		c.buy_pixel(0, 0, "alice".to_string(), u128::MAX / 2, RED, false).unwrap();
+		assert_eq!(c.pixel(0, 0).unwrap().price, u128::MAX - 1);
+	}
+	
+	#[test]
+	fn the_cached_merkle_tree_follows_every_change() {
+		let mut c = Canvas::new(5, 3, 1_000, PriceStrategy::Double, vec![]).unwrap();
+		let mut tree = c.merkle_tree();
+		let mut change = |c: &mut Canvas, f: &dyn Fn(&mut Canvas)| {
+			let previous = c.clone();
+			f(c);
+			c.update_merkle_tree(&previous, &mut tree);
+			assert_eq!(tree.root(), c.merkle_root());
+		};
+		
+		change(&mut c, &|c| {
+			c.buy_pixel(4, 2, "alice".to_string(), 1_000, RED, true).unwrap();
+		});
+		change(&mut c, &|c| {
+			c.set_color(4, 2, "alice".to_string(), Rgba8888(0x00FF_00FF), BlendMode::Replace).unwrap();
+		});
+		change(&mut c, &|c| c.transfer_pixel(4, 2, "alice".to_string(), "bob".to_string()).unwrap());
+		// 像素数变化时整棵重建
+		change(&mut c, &|c| {
+			let fill = c.pixel(0, 0).unwrap().clone();
+			c.resize(6, 3, fill).unwrap();
+		});
+		// 只改尺寸、像素数不变时按下标逐个比对
+		change(&mut c, &|c| {
+			let fill = c.pixel(0, 0).unwrap().clone();
+			c.resize(3, 6, fill).unwrap();
+		});
+	}
+}
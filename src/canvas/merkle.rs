@@ -0,0 +1,167 @@
+//! 画布状态的 Merkle 承诺，用于认证（certified）查询。
+//!
+//! 叶子按行主序排列，每个像素一片叶子：
+//!   leaf = sha256(0x00 || price (16 字节大端) || color (4 字节大端) || owner)
+//! 其中 owner 为 0x00（无主）或 0x01 后接地址的 UTF‑8 字节。
+//! 内部节点 node = sha256(0x01 || left || right)；某层节点数为奇数时，最后一个节点与自身配对。
+
+use crate::canvas::pixel::Pixel;
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+/// 单个像素的叶子哈希
+pub fn leaf_hash(pixel: &Pixel) -> Hash {
+	let mut hasher = Sha256::new();
+	hasher.update([0x00]);
+	hasher.update(pixel.price.to_be_bytes());
+	hasher.update(pixel.color.0.to_be_bytes());
+	match &pixel.owner {
+		None => hasher.update([0x00]),
+		Some(owner) => {
+			hasher.update([0x01]);
+			hasher.update(owner.as_bytes());
+		}
+	}
+	hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+	let mut hasher = Sha256::new();
+	hasher.update([0x01]);
+	hasher.update(left);
+	hasher.update(right);
+	hasher.finalize().into()
+}
+
+/// 缓存了各层哈希的 Merkle 树，改动个别叶子时只需重算其到根的路径
+pub struct Tree {
+	// 自底向上的各层哈希，第一层为叶子，最后一层只有根；没有叶子时为空
+	levels: Vec<Vec<Hash>>,
+}
+
+impl Tree {
+	pub fn new(leaves: Vec<Hash>) -> Self {
+		let mut levels = vec![leaves];
+		while levels.last().is_some_and(|level| level.len() > 1) {
+			let next = levels
+				.last()
+				.unwrap()
+				.chunks(2)
+				.map(|pair| node_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+				.collect();
+			levels.push(next);
+		}
+		Tree { levels }
+	}
+	
+	/// Merkle 根；没有叶子时为全 0
+	pub fn root(&self) -> Hash {
+		self.levels
+			.last()
+			.and_then(|level| level.first().copied())
+			.unwrap_or([0; 32])
+	}
+	
+	/// 第 `index` 片叶子的证明：自底向上每层的兄弟节点。
+	///
+	/// 验证时从叶子出发，逐层按当前下标的奇偶决定兄弟在左（奇）还是在右（偶），再把下标除以 2。
+	pub fn proof(&self, mut index: usize) -> Vec<Hash> {
+		let mut siblings = vec![];
+		for level in &self.levels[..self.levels.len().saturating_sub(1)] {
+			let sibling = index ^ 1;
+			siblings.push(*level.get(sibling).unwrap_or(&level[index]));
+			index /= 2;
+		}
+		siblings
+	}
+	
+	/// 把第 `index` 片叶子换成 `leaf`，并重算它到根的路径
+	pub fn update(&mut self, mut index: usize, leaf: Hash) {
+		self.levels[0][index] = leaf;
+		for depth in 1..self.levels.len() {
+			let left = index & !1;
+			let below = &self.levels[depth - 1];
+			let parent = node_hash(&below[left], below.get(left + 1).unwrap_or(&below[left]));
+			index /= 2;
+			self.levels[depth][index] = parent;
+		}
+	}
+}
+
+/// Merkle 根；没有叶子时为全 0
+pub fn root(leaves: Vec<Hash>) -> Hash {
+	Tree::new(leaves).root()
+}
+
+/// 第 `index` 片叶子的证明，见 [`Tree::proof`]
+pub fn proof(leaves: Vec<Hash>, index: usize) -> Vec<Hash> {
+	Tree::new(leaves).proof(index)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::canvas::canvas::{Canvas, PriceStrategy};
+	use crate::canvas::pixel::Rgba8888;
+	
+	/// 按 `proof` 的说明从叶子逐层求根
+	fn verify(leaf: Hash, mut index: usize, proof: &[Hash], root: Hash) -> bool {
+		let mut hash = leaf;
+		for sibling in proof {
+			hash = if index % 2 == 0 {
+				node_hash(&hash, sibling)
+			} else {
+				node_hash(sibling, &hash)
+			};
+			index /= 2;
+		}
+		hash == root
+	}
+	
+	#[test]
+	fn changing_one_pixel_changes_the_root() {
+		let mut c = Canvas::new(3, 2, 1_000, PriceStrategy::Double, vec![]).unwrap();
+		let root = c.merkle_root();
+		
+		c.buy_pixel(2, 1, "alice".to_string(), 1_000, Rgba8888(0xFF00_00FF), true).unwrap();
+		assert_ne!(c.merkle_root(), root);
+		assert_eq!(super::root(vec![]), [0; 32]);
+	}
+	
+	#[test]
+	fn every_pixel_proof_verifies_against_the_root() {
+		// 5×1 画布：前两层节点数为奇数，末尾节点与自身配对
+		let mut c = Canvas::new(5, 1, 1_000, PriceStrategy::Double, vec![]).unwrap();
+		c.buy_pixel(4, 0, "alice".to_string(), 1_000, Rgba8888(0xFF00_00FF), true).unwrap();
+		let root = c.merkle_root();
+		
+		for x in 0..5 {
+			let leaf = leaf_hash(c.pixel(x, 0).unwrap());
+			let proof = c.merkle_proof(x, 0).unwrap();
+			assert_eq!(proof.len(), 3);
+			assert!(verify(leaf, x, &proof, root));
+		}
+		// 换成别的像素就验证不过
+		let other = leaf_hash(c.pixel(0, 0).unwrap());
+		assert!(!verify(other, 4, &c.merkle_proof(4, 0).unwrap(), root));
+	}
+	
+	#[test]
+	fn updating_a_leaf_matches_rebuilding_the_tree() {
+		let leaf = |n: u8| [n; 32];
+		// 5 片叶子：前两层节点数为奇数，末尾节点与自身配对
+		let mut leaves: Vec<Hash> = (0..5).map(leaf).collect();
+		let mut tree = Tree::new(leaves.clone());
+		for (index, n) in [(4, 9), (0, 7), (3, 8), (4, 4)] {
+			tree.update(index, leaf(n));
+			leaves[index] = leaf(n);
+			assert_eq!(tree.root(), root(leaves.clone()));
+			assert_eq!(tree.proof(index), proof(leaves.clone(), index));
+		}
+		
+		let mut single = Tree::new(vec![leaf(1)]);
+		single.update(0, leaf(2));
+		assert_eq!(single.root(), leaf(2));
+	}
+}
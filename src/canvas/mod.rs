@@ -2,6 +2,167 @@
 //! 游戏逻辑：
 //! 通过外部函数传入要修改的像素块的坐标（x,y）, x>=0, y>=0
 //!
+//! 坐标原点 (0,0) 位于画布左上角，x 向右递增，y 向下递增，
+//! 像素按行主序存储。
+//!
+//! 典型调用流程：
+//! 1. 前端调用 `get_pixel(x, y)` 读取当前标价、占有者与颜色；
+//! 2. 买家完成付款后，由受信任的调用方（controller）调用 `buy_pixel_tx` 完成过户，
+//!    并按返回的 `PixelPayout` 把款项记给原占有者或项目方；
+//! 3. 之后占有者可经由 controller 调用 `set_pixel_color` 改色。
+//!
+//! 每次 `buy_pixel_tx` / `buy_region_tx` 成交后，会就每个易主的像素
+//! 向 controller 配置的订阅者 canister 发送易主通知（见 `notify`）。
+//!
+//! 所有修改画布的接口都是同步的，在一条消息内完成读取、校验与写回，中途没有 await，
+//! 因此同一像素不会被两笔调用交错修改，无需加锁；若日后购买改为异步（如先跨 canister 付款），
+//! 需要像 ree 模块的 `ExecuteTxGuard` 那样按像素加锁。
 
+mod api;
+mod canvas;
+mod merkle;
+mod notify;
 mod pixel;
-mod canvas;
\ No newline at end of file
+mod png;
+
+pub use self::canvas::{
+	Canvas, CanvasError, CanvasSnapshot, CanvasStats, MAX_LEADERBOARD_LEN, MAX_PRICE_LOOKUP,
+	OwnerStats, PixelPayout, PixelSnapshot, PriceOverflowPolicy, PriceStrategy, PriceZone,
+	RateLimit, RegionPurchase, RegionQuote, SNAPSHOT_CHUNK_LEN, SnapshotChunk,
+};
+pub use self::pixel::{AccountId, BlendMode, MAX_MEMO_LEN, Pixel, PixelEvent, PixelView, Rgb888, Rgba8888};
+
+use crate::state::{MEMORY_MANAGER, Memory};
+use ic_stable_structures::{StableCell, memory_manager::MemoryId};
+use std::cell::{Cell, RefCell};
+
+/// 默认画布宽度（像素）
+pub const CANVAS_WIDTH: usize = 100;
+/// 默认画布高度（像素）
+pub const CANVAS_HEIGHT: usize = 100;
+/// 画布像素总数（宽 × 高）上限的默认值。每次修改都会整体克隆画布、比对像素并重新序列化，
+/// 像素越多单次调用消耗的指令越多，因此默认值远低于 [`MAX_CANVAS_PIXELS`]
+pub const DEFAULT_MAX_CANVAS_PIXELS: u64 = 262_144;
+/// controller 可设置的像素总数上限的最大值，防止创建、调整尺寸或导入时分配过多内存
+pub const MAX_CANVAS_PIXELS: u64 = 1_000_000;
+/// 像素初始标价（最小计价单位）
+pub const INITIAL_PIXEL_PRICE: u128 = 1_000;
+
+thread_local! {
+	// MAX_PIXELS 保存 controller 设置的画布像素总数上限
+	static MAX_PIXELS: RefCell<StableCell<u64, Memory>> = RefCell::new(
+		StableCell::init(
+			MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+			DEFAULT_MAX_CANVAS_PIXELS,
+		)
+		.expect("failed to init MAX_PIXELS"),
+	);
+	
+	// CANVAS 保存整张画布，放在稳定内存中以便升级后保留
+	static CANVAS: RefCell<StableCell<Canvas, Memory>> = RefCell::new(
+		StableCell::init(
+			MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+			Canvas::new(
+				CANVAS_WIDTH,
+				CANVAS_HEIGHT,
+				INITIAL_PIXEL_PRICE,
+				PriceStrategy::Double,
+				vec![],
+			)
+			.expect("default canvas exceeds the pixel cap"),
+		)
+		.expect("failed to init CANVAS"),
+	);
+	
+	// MERKLE_TREE 缓存当前画布的 Merkle 树，其根即 canister 的认证数据；修改画布时只重算变动的路径，
+	// 升级后由 post_upgrade 重新建树
+	static MERKLE_TREE: RefCell<Option<merkle::Tree>> = const { RefCell::new(None) };
+}
+
+/// 当前的画布像素总数上限
+pub fn max_canvas_pixels() -> u64 {
+	MAX_PIXELS.with_borrow(|m| *m.get())
+}
+
+/// 设置画布像素总数上限：不得超过 [`MAX_CANVAS_PIXELS`]，也不得低于当前画布的像素数
+pub fn set_max_canvas_pixels(max: u64) -> Result<(), String> {
+	if max > MAX_CANVAS_PIXELS {
+		return Err(format!("Pixel cap too large, max {}", MAX_CANVAS_PIXELS));
+	}
+	let current = with_canvas(|c| c.pixel_count());
+	if max < current {
+		return Err(format!("Pixel cap below the current canvas of {} pixels", current));
+	}
+	MAX_PIXELS.with_borrow_mut(|m| {
+		m.set(max).expect("failed to persist MAX_PIXELS");
+	});
+	Ok(())
+}
+
+/// 只读访问画布
+pub fn with_canvas<R>(f: impl FnOnce(&Canvas) -> R) -> R {
+	CANVAS.with_borrow(|c| f(c.get()))
+}
+
+/// 修改画布：仅当 `f` 返回 `Ok` 时才写回稳定内存并重新认证，失败时画布保持不变
+pub fn with_canvas_mut<R, E>(f: impl FnOnce(&mut Canvas) -> Result<R, E>) -> Result<R, E> {
+	CANVAS.with_borrow_mut(|c| {
+		let mut canvas = c.get().clone();
+		let res = f(&mut canvas)?;
+		recertify(c.get(), &canvas);
+		c.set(canvas).expect("failed to persist CANVAS");
+		Ok(res)
+	})
+}
+
+/// 当前时间（纳秒）；原生单元测试中没有 replica 时钟，改读由 `set_now` 拨动的测试时钟
+#[cfg(not(test))]
+pub(crate) fn now() -> u64 {
+	ic_cdk::api::time()
+}
+
+#[cfg(test)]
+thread_local! {
+	static NOW: Cell<u64> = const { Cell::new(0) };
+}
+
+#[cfg(test)]
+pub(crate) fn now() -> u64 {
+	NOW.get()
+}
+
+#[cfg(test)]
+pub(crate) fn set_now(time: u64) {
+	NOW.set(time);
+}
+
+/// 当前画布的 Merkle 根（优先使用缓存）
+pub fn merkle_root() -> [u8; 32] {
+	MERKLE_TREE
+		.with_borrow(|t| t.as_ref().map(merkle::Tree::root))
+		.unwrap_or_else(|| with_canvas(|c| c.merkle_root()))
+}
+
+/// 重新计算当前画布的 Merkle 根并写入认证数据，只能在 update / init / post_upgrade 中调用
+pub fn certify_canvas() {
+	CANVAS.with_borrow(|c| certify(c.get()));
+}
+
+fn certify(canvas: &Canvas) {
+	let tree = canvas.merkle_tree();
+	ic_cdk::api::set_certified_data(&tree.root());
+	MERKLE_TREE.set(Some(tree));
+}
+
+/// 画布从 `previous` 改为 `canvas` 后重新认证：有缓存的树时只更新变动的像素，否则整棵重建
+fn recertify(previous: &Canvas, canvas: &Canvas) {
+	let tree = match MERKLE_TREE.take() {
+		Some(mut tree) => {
+			canvas.update_merkle_tree(previous, &mut tree);
+			tree
+		}
+		None => canvas.merkle_tree(),
+	};
+	ic_cdk::api::set_certified_data(&tree.root());
+	MERKLE_TREE.set(Some(tree));
+}
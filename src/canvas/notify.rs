@@ -0,0 +1,126 @@
+//! 像素易主通知：成交后向订阅者 canister 发送单向（notify）调用，
+//! 不等待应答，订阅者失败不会影响购买本身。
+//!
+//! 只有购买（`buy_pixel_tx` / `buy_region_tx`）会发出通知；`transfer_pixel` 与 `release_pixel`
+//! 不涉及付款、也不发通知，需要完整所有权变化的订阅者应另行读取像素状态。
+
+use crate::state::{MEMORY_MANAGER, Memory};
+use candid::Principal;
+use ic_stable_structures::{StableCell, Storable, memory_manager::MemoryId, storable::Bound};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+/// 订阅者 canister 上接收通知的方法名，参数为 `(x: nat32, y: nat32, new_owner: text, price_paid: nat)`
+pub const OWNER_CHANGED_METHOD: &str = "on_pixel_owner_changed";
+
+/// 订阅者列表的最大长度，限制每笔成交发出的调用数
+pub const MAX_SUBSCRIBERS: usize = 16;
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Subscribers(Vec<Principal>);
+
+impl Storable for Subscribers {
+	const BOUND: Bound = Bound::Unbounded;
+	
+	fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+		let mut bytes = vec![];
+		let _ = ciborium::ser::into_writer(self, &mut bytes);
+		std::borrow::Cow::Owned(bytes)
+	}
+	
+	fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+		ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode Subscribers")
+	}
+}
+
+thread_local! {
+	// SUBSCRIBERS 保存由 controller 管理的订阅者 canister 列表
+	static SUBSCRIBERS: RefCell<StableCell<Subscribers, Memory>> = RefCell::new(
+		StableCell::init(
+			MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+			Subscribers::default(),
+		)
+		.expect("failed to init SUBSCRIBERS"),
+	);
+}
+
+/// 当前的订阅者列表
+pub fn subscribers() -> Vec<Principal> {
+	SUBSCRIBERS.with_borrow(|s| s.get().0.clone())
+}
+
+/// 整体替换订阅者列表，重复项只保留第一个
+pub fn set_subscribers(mut subscribers: Vec<Principal>) -> Result<(), String> {
+	let mut seen = BTreeSet::new();
+	subscribers.retain(|p| seen.insert(*p));
+	if subscribers.len() > MAX_SUBSCRIBERS {
+		return Err(format!("Too many subscribers, max {}", MAX_SUBSCRIBERS));
+	}
+	SUBSCRIBERS.with_borrow_mut(|s| {
+		s.set(Subscribers(subscribers)).expect("failed to persist SUBSCRIBERS");
+	});
+	Ok(())
+}
+
+/// 易主通知的参数：`(x, y, new_owner, price_paid)`
+pub type OwnerChanged = (u32, u32, String, u128);
+
+/// 像素 (x,y) 易主时要发出的通知：每个订阅者一条
+pub fn owner_changed_notices(x: u32, y: u32, new_owner: &str, price_paid: u128) -> Vec<(Principal, OwnerChanged)> {
+	subscribers()
+		.into_iter()
+		.map(|subscriber| (subscriber, (x, y, new_owner.to_string(), price_paid)))
+		.collect()
+}
+
+/// 通知所有订阅者像素 (x,y) 已易主。尽力而为：单向调用入队失败只记录日志
+pub fn notify_owner_changed(x: u32, y: u32, new_owner: &str, price_paid: u128) {
+	for (subscriber, args) in owner_changed_notices(x, y, new_owner, price_paid) {
+		send(subscriber, args);
+	}
+}
+
+/// 发出单向调用；单元测试中改为记入 `SENT`，由 `take_sent` 取回
+#[cfg(not(test))]
+fn send(subscriber: Principal, args: OwnerChanged) {
+	if let Err(code) = ic_cdk::api::call::notify(subscriber, OWNER_CHANGED_METHOD, args) {
+		ic_cdk::println!("notify {} failed: {:?}", subscriber.to_text(), code);
+	}
+}
+
+#[cfg(test)]
+thread_local! {
+	static SENT: RefCell<Vec<(Principal, OwnerChanged)>> = const { RefCell::new(vec![]) };
+}
+
+#[cfg(test)]
+fn send(subscriber: Principal, args: OwnerChanged) {
+	SENT.with_borrow_mut(|sent| sent.push((subscriber, args)));
+}
+
+/// 取出本线程至今发出的通知
+#[cfg(test)]
+pub(crate) fn take_sent() -> Vec<(Principal, OwnerChanged)> {
+	SENT.with_borrow_mut(std::mem::take)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn every_subscriber_gets_the_owner_change_once() {
+		let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+		set_subscribers(vec![a, b, a]).unwrap();
+		assert_eq!(subscribers(), vec![a, b]);
+		
+		notify_owner_changed(3, 4, "alice", 2_000);
+		let payload = (3, 4, "alice".to_string(), 2_000);
+		assert_eq!(take_sent(), vec![(a, payload.clone()), (b, payload)]);
+		
+		let too_many = (0..=MAX_SUBSCRIBERS as u8).map(|i| Principal::from_slice(&[i])).collect();
+		assert!(set_subscribers(too_many).is_err());
+		assert_eq!(subscribers(), vec![a, b]);
+	}
+}
@@ -1,22 +1,209 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 /// 像素点占有者的BTC 地址
 pub type AccountId = String;
 
+/// 像素留言的最大长度（字节）
+pub const MAX_MEMO_LEN: usize = 140;
+
 /// 像素单元
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Pixel {
 	pub owner: Option<AccountId>, // None 表示无人持有。收入归项目方，为Some则收入归像素占有者。
 	pub price: u128,              // 当前标价（最小计价单位，自行决定 Token 精度）
-	pub color: Rgb888,            // 24‑bit 颜色
+	pub color: Rgba8888,          // 32‑bit 颜色（含 alpha）
+	#[serde(default)]
+	pub last_bought: u64,         // 最近一次成交时间（纳秒），0 表示从未成交
+	#[serde(default)]
+	pub memo: Option<String>,     // 占有者留言，最长 MAX_MEMO_LEN 字节，过户时清空
 }
 
 /// 24‑bit 颜色封装（0xRRGGBB）
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Rgb888(pub u32);
 
+impl From<u32> for Rgb888 {
+	/// 丢弃高 8 位，只保留 0xRRGGBB
+	fn from(value: u32) -> Self {
+		Rgb888(value & 0x00FF_FFFF)
+	}
+}
+
 impl fmt::Display for Rgb888 {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(f, "#{:06X}", self.0 & 0x00FF_FFFF)
 	}
-}
\ No newline at end of file
+}
+
+/// 32‑bit 颜色封装（0xRRGGBBAA），AA = 0xFF 表示完全不透明
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(from = "StoredColor", into = "StoredColor")]
+pub struct Rgba8888(pub u32);
+
+impl Rgba8888 {
+	/// 拆分为 [R, G, B, A]
+	pub fn channels(&self) -> [u8; 4] {
+		self.0.to_be_bytes()
+	}
+	
+	pub fn alpha(&self) -> u8 {
+		self.channels()[3]
+	}
+	
+	/// 按 `mode` 把本颜色（新色）画在 `dst`（像素原有颜色）之上，返回合成后的颜色
+	pub fn blend(self, dst: Rgba8888, mode: BlendMode) -> Rgba8888 {
+		let [sr, sg, sb, sa] = self.channels().map(u32::from);
+		let [dr, dg, db, da] = dst.channels().map(u32::from);
+		// 原色在新色之下透出的 alpha 份额
+		let da_under = div255(da * (255 - sa));
+		let out_a = sa + da_under;
+		let over = |s: u32, d: u32| match out_a {
+			0 => 0,
+			_ => (s * sa + d * da_under + out_a / 2) / out_a,
+		};
+		let [r, g, b, a] = match mode {
+			BlendMode::Replace => return self,
+			BlendMode::AlphaOver => [over(sr, dr), over(sg, dg), over(sb, db), out_a],
+			BlendMode::Multiply => [div255(sr * dr), div255(sg * dg), div255(sb * db), out_a],
+		};
+		Rgba8888(u32::from_be_bytes([r as u8, g as u8, b as u8, a as u8]))
+	}
+}
+
+/// x / 255，四舍五入
+fn div255(x: u32) -> u32 {
+	(x + 127) / 255
+}
+
+/// 新颜色与像素原有颜色的合成方式
+#[derive(CandidType, Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BlendMode {
+	/// 直接覆盖原有颜色
+	#[default]
+	Replace,
+	/// 按新色的 alpha 叠加在原色之上（source-over）
+	AlphaOver,
+	/// RGB 逐通道相乘（c = s * d / 255），alpha 同 AlphaOver
+	Multiply,
+}
+
+impl From<Rgb888> for Rgba8888 {
+	/// 旧的 24‑bit 颜色，alpha 默认为 0xFF（不透明）
+	fn from(value: Rgb888) -> Self {
+		Rgba8888(((value.0 & 0x00FF_FFFF) << 8) | 0xFF)
+	}
+}
+
+impl fmt::Display for Rgba8888 {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "#{:08X}", self.0)
+	}
+}
+
+/// 颜色在稳定内存中的编码：
+/// 旧数据是裸的 24‑bit 整数（`Rgb888`），新数据是带 `rgba` 字段的结构，
+/// 以便升级后旧画布仍能解码。
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(untagged)]
+enum StoredColor {
+	Rgba { rgba: u32 },
+	Legacy(u32),
+}
+
+impl From<StoredColor> for Rgba8888 {
+	fn from(value: StoredColor) -> Self {
+		match value {
+			StoredColor::Rgba { rgba } => Rgba8888(rgba),
+			StoredColor::Legacy(rgb) => Rgb888::from(rgb).into(),
+		}
+	}
+}
+
+impl From<Rgba8888> for StoredColor {
+	fn from(value: Rgba8888) -> Self {
+		StoredColor::Rgba { rgba: value.0 }
+	}
+}
+
+/// 像素成交记录
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PixelEvent {
+	pub timestamp: u64,    // 成交时间（ic_cdk::api::time，纳秒）
+	pub buyer: AccountId,  // 买家
+	pub price_paid: u128,  // 实付金额
+	pub color: u32,        // 买家设置的颜色（0xRRGGBBAA）
+}
+
+/// 对外（Candid）展示的像素视图
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PixelView {
+	pub x: u32,
+	pub y: u32,
+	pub owner: Option<AccountId>,
+	pub price: u128,
+	pub color: String, // "#RRGGBBAA"
+	pub memo: Option<String>,
+}
+
+impl Pixel {
+	/// 生成位于 (x,y) 的像素视图
+	pub fn view(&self, x: usize, y: usize) -> PixelView {
+		PixelView {
+			x: x as u32,
+			y: y as u32,
+			owner: self.owner.clone(),
+			price: self.price,
+			color: self.color.to_string(),
+			memo: self.memo.clone(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	const RED: Rgba8888 = Rgba8888(0xFF00_00FF);
+	const BLUE: Rgba8888 = Rgba8888(0x0000_FFFF);
+	
+	#[test]
+	fn replace_and_opaque_alpha_over_keep_the_new_color() {
+		let half_red = Rgba8888(0xFF00_0080);
+		assert_eq!(half_red.blend(BLUE, BlendMode::Replace), half_red);
+		assert_eq!(RED.blend(BLUE, BlendMode::AlphaOver), RED);
+		// 完全透明的新色不改变原色
+		assert_eq!(Rgba8888(0x1234_5600).blend(BLUE, BlendMode::AlphaOver), BLUE);
+	}
+	
+	#[test]
+	fn alpha_over_mixes_by_the_new_color_alpha() {
+		assert_eq!(Rgba8888(0xFF00_0080).blend(BLUE, BlendMode::AlphaOver), Rgba8888(0x8000_7FFF));
+		// 两者都完全透明时结果为全 0，不做除零
+		assert_eq!(Rgba8888(0xFF00_0000).blend(Rgba8888(0x0000_FF00), BlendMode::AlphaOver), Rgba8888(0));
+	}
+	
+	#[test]
+	fn multiply_scales_each_channel() {
+		assert_eq!(Rgba8888(0x8080_80FF).blend(RED, BlendMode::Multiply), Rgba8888(0x8000_00FF));
+		assert_eq!(RED.blend(BLUE, BlendMode::Multiply), Rgba8888(0x0000_00FF));
+	}
+	
+	#[test]
+	fn colors_keep_their_alpha_and_legacy_colors_decode_opaque() {
+		let half_red = Rgba8888(0xFF00_0080);
+		assert_eq!(half_red.to_string(), "#FF000080");
+		assert_eq!(half_red.alpha(), 0x80);
+		assert_eq!(Rgba8888::from(Rgb888(0x12_3456)), Rgba8888(0x1234_56FF));
+		
+		let mut bytes = vec![];
+		ciborium::ser::into_writer(&half_red, &mut bytes).unwrap();
+		assert_eq!(ciborium::de::from_reader::<Rgba8888, _>(bytes.as_slice()).unwrap(), half_red);
+		
+		// 升级前的画布把颜色存为裸的 24‑bit 整数
+		let mut legacy = vec![];
+		ciborium::ser::into_writer(&0x12_3456u32, &mut legacy).unwrap();
+		assert_eq!(ciborium::de::from_reader::<Rgba8888, _>(legacy.as_slice()).unwrap(), Rgba8888(0x1234_56FF));
+	}
+}
@@ -0,0 +1,167 @@
+//! 极简 PNG 编码器：8-bit RGBA、无隔行、deflate 仅使用 stored（不压缩）块，
+//! 不依赖任何第三方库。
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// deflate stored 块单块最大长度
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// 将行主序的 RGBA 数据（每像素 4 字节）编码为 PNG。
+///
+/// PNG 不允许宽或高为 0，此时返回空 vec。
+pub fn encode_rgba(width: usize, height: usize, rgba: &[u8]) -> Vec<u8> {
+	if width == 0 || height == 0 || rgba.len() != width * height * 4 {
+		return vec![];
+	}
+	
+	// 每行前加一个 filter 字节（0 = None）
+	let row_len = width * 4;
+	let mut raw = Vec::with_capacity((row_len + 1) * height);
+	for row in rgba.chunks(row_len) {
+		raw.push(0);
+		raw.extend_from_slice(row);
+	}
+	
+	let mut ihdr = Vec::with_capacity(13);
+	ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+	ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+	// bit depth 8, color type 6 (RGBA), compression 0, filter 0, interlace 0
+	ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+	
+	let mut out = SIGNATURE.to_vec();
+	write_chunk(&mut out, b"IHDR", &ihdr);
+	write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+	write_chunk(&mut out, b"IEND", &[]);
+	out
+}
+
+/// `encode_rgba` 对 width×height 图像输出的字节数，无需实际编码即可判断结果大小
+pub fn encoded_len(width: usize, height: usize) -> usize {
+	if width == 0 || height == 0 {
+		return 0;
+	}
+	let raw = (width * 4 + 1) * height;
+	let idat = 2 + raw + raw.div_ceil(MAX_STORED_BLOCK) * 5 + 4;
+	// 签名 + 三个块各自的长度、类型与 CRC（12 字节）+ IHDR 数据 + IDAT 数据
+	SIGNATURE.len() + 3 * 12 + 13 + idat
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+	out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+	let start = out.len();
+	out.extend_from_slice(kind);
+	out.extend_from_slice(data);
+	let crc = crc32(&out[start..]);
+	out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// 用 stored 块封装成 zlib 数据流
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+	let blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+	let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+	// CMF/FLG：deflate、32K 窗口、无预设字典
+	out.extend_from_slice(&[0x78, 0x01]);
+	
+	let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+	if chunks.peek().is_none() {
+		out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+	}
+	while let Some(chunk) = chunks.next() {
+		let last = chunks.peek().is_none();
+		let len = chunk.len() as u16;
+		out.push(last as u8);
+		out.extend_from_slice(&len.to_le_bytes());
+		out.extend_from_slice(&(!len).to_le_bytes());
+		out.extend_from_slice(chunk);
+	}
+	
+	out.extend_from_slice(&adler32(data).to_be_bytes());
+	out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc = 0xFFFF_FFFFu32;
+	for &b in data {
+		crc ^= b as u32;
+		for _ in 0..8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+		}
+	}
+	!crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+	const MOD: u32 = 65521;
+	let (mut a, mut b) = (1u32, 0u32);
+	for &byte in data {
+		a = (a + byte as u32) % MOD;
+		b = (b + a) % MOD;
+	}
+	(b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	/// 测试用的最小解码器：校验签名、CRC 与 Adler-32，只接受 stored deflate 块和 None filter，
+	/// 返回 (宽, 高, RGBA 数据)
+	fn decode(png: &[u8]) -> (usize, usize, Vec<u8>) {
+		assert_eq!(png[..8], SIGNATURE);
+		let (mut pos, mut ihdr, mut idat) = (8, vec![], vec![]);
+		loop {
+			let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+			let body = &png[pos + 4..pos + 8 + len];
+			let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+			assert_eq!(crc32(body), crc);
+			let (kind, data) = body.split_at(4);
+			pos += 12 + len;
+			match kind {
+				b"IHDR" => ihdr = data.to_vec(),
+				b"IDAT" => idat.extend_from_slice(data),
+				b"IEND" => break,
+				_ => panic!("unexpected chunk"),
+			}
+		}
+		assert_eq!(pos, png.len());
+		let width = u32::from_be_bytes(ihdr[0..4].try_into().unwrap()) as usize;
+		let height = u32::from_be_bytes(ihdr[4..8].try_into().unwrap()) as usize;
+		assert_eq!(ihdr[8..], [8, 6, 0, 0, 0]);
+		
+		assert_eq!(((idat[0] as u16) << 8 | idat[1] as u16) % 31, 0);
+		let (mut pos, mut raw) = (2, vec![]);
+		loop {
+			let header = idat[pos];
+			assert_eq!(header >> 1, 0, "only stored blocks");
+			let len = u16::from_le_bytes([idat[pos + 1], idat[pos + 2]]);
+			let nlen = u16::from_le_bytes([idat[pos + 3], idat[pos + 4]]);
+			assert_eq!(len, !nlen);
+			raw.extend_from_slice(&idat[pos + 5..pos + 5 + len as usize]);
+			pos += 5 + len as usize;
+			if header & 1 == 1 {
+				break;
+			}
+		}
+		assert_eq!(idat[pos..], adler32(&raw).to_be_bytes());
+		
+		let mut rgba = vec![];
+		for row in raw.chunks(width * 4 + 1) {
+			assert_eq!(row[0], 0);
+			rgba.extend_from_slice(&row[1..]);
+		}
+		(width, height, rgba)
+	}
+	
+	#[test]
+	fn decoding_gives_back_the_encoded_pixels() {
+		// 300×300 的数据跨越多个 stored 块
+		for (width, height) in [(1, 1), (3, 2), (300, 300)] {
+			let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i * 7 % 251) as u8).collect();
+			let png = encode_rgba(width, height, &rgba);
+			assert_eq!(png.len(), encoded_len(width, height));
+			assert_eq!(decode(&png), (width, height, rgba));
+		}
+		assert!(encode_rgba(0, 5, &[]).is_empty());
+		assert!(encode_rgba(2, 2, &[0; 15]).is_empty());
+	}
+}
@@ -0,0 +1,132 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde::Serialize;
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // Asks the boundary node to replay the request as an http_request_update call
+    pub upgrade: Option<bool>,
+}
+
+// Largest body served in one response, under the 2 MiB reply limit with room for the headers
+const MAX_HTTP_BODY: usize = 2_000_000;
+
+impl HttpResponse {
+    fn ok(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+            ],
+            body,
+            upgrade: None,
+        }
+    }
+
+    fn text(status_code: u16, message: &str) -> Self {
+        Self {
+            status_code,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: message.as_bytes().to_vec(),
+            upgrade: None,
+        }
+    }
+
+    fn not_found() -> Self {
+        Self::text(404, "not found")
+    }
+
+    fn upgrade() -> Self {
+        Self {
+            upgrade: Some(true),
+            ..Self::text(200, "")
+        }
+    }
+}
+
+// Serves plain HTTP GETs coming through the boundary node, e.g. `/canvas.png` or `/metrics`
+// Query responses come from a single replica and are not certified; `/canvas.png` is upgraded
+// to an update call instead, so the image a client gets has gone through consensus
+// `/metrics` stays an uncertified query, scraped through the raw domain: it is only read by
+// monitoring, and an update per scrape would cost far more than a skewed gauge
+#[query]
+pub fn http_request(req: HttpRequest) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
+    match path {
+        "/canvas.png" => HttpResponse::upgrade(),
+        "/metrics" => HttpResponse::ok(
+            "text/plain; version=0.0.4",
+            crate::ree::metrics().into_bytes(),
+        ),
+        _ => HttpResponse::not_found(),
+    }
+}
+
+#[update]
+// Serves the requests http_request upgraded
+pub fn http_request_update(req: HttpRequest) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
+    match path {
+        "/canvas.png" => crate::canvas::with_canvas(canvas_png),
+        _ => HttpResponse::not_found(),
+    }
+}
+
+// The canvas as one PNG, unless it would not fit in a response: the size is known before
+// encoding, so an oversized canvas is refused without building the image
+// Such canvases are read through get_canvas_rle or get_region instead
+fn canvas_png(canvas: &crate::canvas::Canvas) -> HttpResponse {
+    let len = canvas.png_len();
+    if len == 0 {
+        return HttpResponse::not_found();
+    }
+    if len > MAX_HTTP_BODY {
+        return HttpResponse::text(413, "canvas too large for one image, use get_canvas_rle");
+    }
+    HttpResponse::ok("image/png", canvas.to_png())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{Canvas, PriceStrategy};
+
+    fn get(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn the_canvas_image_is_served_through_an_update_and_capped() {
+        assert_eq!(http_request(get("/canvas.png?v=1")).upgrade, Some(true));
+        assert_eq!(http_request(get("/metrics")).upgrade, None);
+
+        let small = Canvas::new(2, 2, 1_000, PriceStrategy::Double, vec![]).unwrap();
+        let served = canvas_png(&small);
+        assert_eq!(served.status_code, 200);
+        assert_eq!(served.body, small.to_png());
+        assert_eq!(http_request_update(get("/canvas.png")).status_code, 200);
+
+        // At 1000×1000 the image would be about 4 MB
+        crate::canvas::set_max_canvas_pixels(crate::canvas::MAX_CANVAS_PIXELS).unwrap();
+        let large = Canvas::new(1_000, 1_000, 1_000, PriceStrategy::Double, vec![]).unwrap();
+        assert!(large.png_len() > MAX_HTTP_BODY);
+        assert_eq!(canvas_png(&large).status_code, 413);
+    }
+}
@@ -1,4 +1,17 @@
 mod canvas;
+mod http;
 mod ree;
 mod state;
-mod rank;
\ No newline at end of file
+mod rank;
+
+#[ic_cdk_macros::init]
+fn init() {
+    canvas::certify_canvas();
+}
+
+#[ic_cdk_macros::post_upgrade]
+fn post_upgrade() {
+    // Certified data does not survive an upgrade, so re-certify the canvas in stable memory
+    canvas::certify_canvas();
+    ree::index_trade_events();
+}
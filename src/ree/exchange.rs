@@ -1,58 +1,139 @@
-use super::ExecuteTxGuard;
-use super::token_pool;
+use super::ExchangeError;
+use super::{ExecuteTxGuard, PayoutGuard};
+use super::token_pool::{CanvasToken, TokenState};
 use ic_cdk_macros::{query, update};
-use ree_types::orchestrator_interfaces::ensure_testnet4_orchestrator;
+use super::ensure_orchestrator;
 use ree_types::{
-    Intention, bitcoin::psbt::Psbt, exchange_interfaces::*,
+    CoinBalance, CoinId, InputCoin, Intention, IntentionSet, OutputCoin, Txid, Utxo,
+    bitcoin::{Address, ScriptBuf, address::NetworkUnchecked, psbt::Psbt},
+    exchange_interfaces::*,
+    schnorr::ree_pool_sign,
 };
+use std::str::FromStr;
+
+// Upper bound on the pools returned by one get_pool_list_paged call
+pub const MAX_POOL_LIST_PAGE: u64 = 100;
+
+// Upper bound on the addresses of one get_pool_infos call
+pub const MAX_POOL_INFOS_BATCH: usize = 100;
 
 #[query]
 pub fn get_pool_list() -> GetPoolListResponse {
-    let tokens = super::get_canvas_tokens();
-    tokens
-        .iter()
-        .map(|t| PoolBasic {
-            name: t.meta.symbol.clone(),
-            address: t.addr.clone(),
+    pool_list(0, usize::MAX)
+}
+
+#[query]
+// Same as get_pool_list, a page at a time in stable (address) order
+pub fn get_pool_list_paged(offset: u64, limit: u64) -> GetPoolListResponse {
+    pool_list(offset as usize, limit.min(MAX_POOL_LIST_PAGE) as usize)
+}
+
+fn pool_list(offset: usize, limit: usize) -> GetPoolListResponse {
+    super::get_token_metas(offset, limit)
+        .into_iter()
+        .map(|(address, meta)| PoolBasic {
+            name: meta.symbol,
+            address,
         })
         .collect()
 }
 
+#[query]
+// Only the pool addresses, in the same order as get_pool_list; the tokens themselves are
+// never decoded, which keeps this cheap for large maps
+pub fn get_pool_addresses() -> Vec<String> {
+    super::CANVAS_TOKENS.with_borrow(|t| t.keys().collect())
+}
+
 #[query]
 pub fn get_pool_info(args: GetPoolInfoArgs) -> GetPoolInfoResponse {
-    let GetPoolInfoArgs { pool_address } = args;
-    let t = super::get_canvas_token(&pool_address)?;
+    pool_info(args.pool_address)
+}
+
+#[query]
+// Batched get_pool_info: one entry per requested address, in order, None for unknown pools
+// At most MAX_POOL_INFOS_BATCH addresses per call
+pub fn get_pool_infos(addresses: Vec<String>) -> Result<Vec<Option<PoolInfo>>, String> {
+    if addresses.len() > MAX_POOL_INFOS_BATCH {
+        return Err(format!("Too many addresses, max {}", MAX_POOL_INFOS_BATCH));
+    }
+    Ok(addresses.into_iter().map(pool_info).collect())
+}
+
+#[query]
+// Sum of btc_reserved over every pool, i.e. the latest btc_balance of each token
+pub fn total_btc_reserved() -> Result<u64, String> {
+    super::CANVAS_TOKENS.with_borrow(|t| {
+        t.iter()
+            .try_fold(0u64, |total, (_, token)| {
+                let balance = token.current_state().btc_balance;
+                total.checked_add(balance).ok_or(ExchangeError::Overflow)
+            })
+            .map_err(|e| e.to_string())
+    })
+}
 
-    Some(PoolInfo {
+#[query]
+// Like get_pool_info, but tells an unknown address (InvalidToken) apart from a token
+// that has no state yet (EmptyToken)
+pub fn try_get_pool_info(pool_address: String) -> Result<PoolInfo, ExchangeError> {
+    let t = super::get_canvas_token(&pool_address).ok_or(ExchangeError::InvalidToken)?;
+    if t.states.is_empty() {
+        return Err(ExchangeError::EmptyToken);
+    }
+    Ok(token_pool_info(&t))
+}
+
+fn pool_info(pool_address: String) -> Option<PoolInfo> {
+    super::get_canvas_token(&pool_address).map(|t| token_pool_info(&t))
+}
+
+fn token_pool_info(t: &CanvasToken) -> PoolInfo {
+    let state = t.current_state();
+
+    PoolInfo {
         key: t.pubkey.clone(),
         name: t.meta.symbol.clone(),
-        key_derivation_path: vec![t.meta.id.to_bytes()],
+        // Must match the path the pool signs with
+        key_derivation_path: t.derivation_path(),
         address: t.addr.clone(),
-        nonce: t.states.last().map(|s| s.nonce).unwrap_or_default(),
-        btc_reserved: t.states.last().map(|s| s.btc_balance).unwrap_or_default(),
-        coin_reserved: vec![],
-        utxos: vec![], 
+        nonce: state.nonce,
+        btc_reserved: state.btc_balance,
+        coin_reserved: vec![CoinBalance {
+            id: t.token_id(),
+            value: state.token_reserve,
+        }],
+        utxos: state.utxos,
         attributes: t.attrs(),
-    })
+    }
 }
 
 #[query]
-fn get_minimal_tx_value(_args: GetMinimalTxValueArgs) -> GetMinimalTxValueResponse {
-    token_pool::MIN_BTC_VALUE
+// Reports the minimum of the pool named in the args, or the global one for unknown pools
+fn get_minimal_tx_value(args: GetMinimalTxValueArgs) -> GetMinimalTxValueResponse {
+    super::get_canvas_token(&args.pool_address)
+        .map(|t| t.min_tx_value())
+        .unwrap_or_else(super::get_min_btc_value)
 }
 
-#[update(guard = "ensure_testnet4_orchestrator")]
+#[update(guard = "ensure_orchestrator")]
 pub fn rollback_tx(args: RollbackTxArgs) -> RollbackTxResponse {
+    rollback_txid(args.txid)
+}
+
+// Rolls every token touched by `txid` back to its state before the transaction
+// and drops the transaction's records
+fn rollback_txid(txid: Txid) -> Result<(), String> {
     let result = super::TX_RECORDS.with_borrow_mut(|m| {
-        let maybe_unconfirmed_record = m.get(&(args.txid.clone(), false));
-        let maybe_confirmed_record = m.get(&(args.txid.clone(), true));
+        let maybe_unconfirmed_record = m.get(&(txid.clone(), false));
+        let maybe_confirmed_record = m.get(&(txid.clone(), true));
         let record = maybe_confirmed_record
             .or(maybe_unconfirmed_record)
-            .ok_or(format!("No record found for txid: {}", args.txid))?;
+            .ok_or(format!("No record found for txid: {}", txid))?;
 
-        ic_cdk::println!(
+        super::log::info!(
             "rollback txid: {} with tokens: {:?}",
-            args.txid,
+            txid,
             record.pools
         );
 
@@ -60,19 +141,31 @@ pub fn rollback_tx(args: RollbackTxArgs) -> RollbackTxResponse {
         record.pools.iter().for_each(|token_address| {
             super::CANVAS_TOKENS.with_borrow_mut(|tokens| {
                 if let Some(mut token) = tokens.get(token_address) {
-                    if let Err(e) = token.rollback(args.txid) {
-                        ic_cdk::println!("Rollback failed: {:?}", e);
+                    if let Err(e) = token.rollback(txid) {
+                        super::log::error!("Rollback failed: {}", e);
                     } else {
                         tokens.insert(token_address.clone(), token);
                     }
                 } else {
-                    ic_cdk::println!("Token not found: {}", token_address);
+                    super::log::info!("Token not found: {}", token_address);
                 }
             });
         });
 
-        m.remove(&(args.txid.clone(), false));
-        m.remove(&(args.txid.clone(), true));
+        m.remove(&(txid.clone(), false));
+        m.remove(&(txid.clone(), true));
+
+        // The nonces rolled back with the states, so remember the txid to keep it from
+        // being silently applied again
+        let height = super::BLOCKS
+            .with_borrow(|b| b.last_key_value().map(|(height, _)| height))
+            .unwrap_or_default();
+        super::ROLLED_BACK_TXIDS.with_borrow_mut(|r| {
+            r.insert(txid, height);
+        });
+
+        super::forget_executed_intentions(txid);
+        super::mark_trade_events_rolled_back(txid);
 
         Ok(())
     });
@@ -80,88 +173,185 @@ pub fn rollback_tx(args: RollbackTxArgs) -> RollbackTxResponse {
     result
 }
 
-#[update(guard = "ensure_testnet4_orchestrator")]
+#[update(guard = "ensure_orchestrator")]
 
 pub fn new_block(args: NewBlockArgs) -> NewBlockResponse {
     let NewBlockArgs {
         block_height,
-        block_hash: _,
+        block_hash,
         block_timestamp: _,
         confirmed_txids,
     } = args.clone();
 
+    // The same block sent again has been processed already; re-running the confirmation
+    // steps could process its txids twice
+    let known = super::BLOCKS.with_borrow(|m| m.get(&block_height));
+    if known.as_ref().is_some_and(|old| old.block_hash == block_hash) {
+        super::log::debug!("block {} at height {} already processed", block_hash, block_height);
+        return Ok(());
+    }
+
+    // A different block at a height we already know means a reorg: transactions confirmed
+    // by the superseded block but missing from the new one are orphaned and rolled back
+    if let Some(old) = known {
+        super::log::info!("block {} replaced by {} at height {}", old.block_hash, block_hash, block_height);
+        for txid in old.confirmed_txids.iter().filter(|t| !confirmed_txids.contains(t)) {
+            if let Err(e) = rollback_txid(*txid) {
+                super::log::error!("Rollback of orphaned txid {} failed: {}", txid, e);
+            }
+        }
+    }
+
     super::BLOCKS.with_borrow_mut(|m| {
         m.insert(block_height, args);
-        ic_cdk::println!("new block {} inserted into blocks", block_height,);
+        super::log::debug!("new block {} inserted into blocks", block_height);
     });
 
+    let mut summary = super::NewBlockSummary {
+        block_height,
+        ..Default::default()
+    };
+
     for txid in confirmed_txids {
         super::TX_RECORDS.with_borrow_mut(|m| {
             if let Some(record) = m.remove(&(txid.clone(), false)) {
                 m.insert((txid.clone(), true), record.clone());
-                ic_cdk::println!("confirm txid: {} with tokens: {:?}", txid, record.pools);
+                summary.confirmed_count += 1;
+                super::log::debug!("confirm txid: {} with tokens: {:?}", txid, record.pools);
             }
         });
     }
 
     // Calculate the height below which blocks are considered fully confirmed (beyond reorg risk)
-    let confirmed_height = if block_height >= 6 { block_height - 6 } else { 0 };
+    let depth = super::CONFIRMATION_DEPTH.with_borrow(|d| *d.get());
+    let confirmed_height = block_height.saturating_sub(depth);
 
     // Finalize transactions in confirmed blocks
+    // A range scan visits every known height up to the threshold, whatever gaps lie between
     super::BLOCKS.with_borrow(|m| {
-        m.iter()
-            .take_while(|(height, _)| *height <= confirmed_height)
+        m.range(..=confirmed_height)
             .for_each(|(height, block_info)| {
-                ic_cdk::println!("finalizing txs in block: {}", height);
+                super::log::debug!("finalizing txs in block: {}", height);
                 block_info.confirmed_txids.iter().for_each(|txid| {
                     super::TX_RECORDS.with_borrow_mut(|m| {
                         if let Some(record) = m.get(&(txid.clone(), true)) {
-                            ic_cdk::println!(
+                            super::log::debug!(
                                 "finalize txid: {} with tokens: {:?}",
                                 txid,
                                 record.pools
                             );
                             // Make transaction state permanent in each affected token
+                            // finalize is idempotent, so pools that succeeded are simply
+                            // finalized again when a failed record is retried
+                            let mut all_finalized = true;
+                            let mut dropped = false;
                             record.pools.iter().for_each(|token_address| {
                                 super::CANVAS_TOKENS.with_borrow_mut(|t| {
                                     if let Some(mut token) = t.get(token_address) {
-                                        if let Err(e) = token.finalize(txid.clone()) {
-                                            ic_cdk::println!("Finalize failed: {:?}", e);
+                                        if !token.holds_txid(txid.clone()) {
+                                            // Retrying can never bring the state back, so the
+                                            // record is dropped instead of holding its block forever
+                                            super::log::error!(
+                                                "txid {} no longer in the chain of {}, dropping its finalize",
+                                                txid,
+                                                token_address
+                                            );
+                                            dropped = true;
+                                        } else if let Err(e) = token.finalize(txid.clone()) {
+                                            super::log::error!("Finalize failed: {}", e);
+                                            all_finalized = false;
                                         } else {
                                             t.insert(token_address.clone(), token);
                                         }
                                     } else {
-                                        ic_cdk::println!("Token not found: {}", token_address);
+                                        // Nothing left to finalize for a removed token
+                                        super::log::info!("Token not found: {}", token_address);
                                     }
                                 });
                             });
-                            m.remove(&(txid.clone(), true));
+                            // Keep the record, and with it the block, for a retry on the next block
+                            if all_finalized && dropped {
+                                m.remove(&(txid.clone(), true));
+                                summary.dropped_count += 1;
+                                super::forget_executed_intentions(*txid);
+                            } else if all_finalized {
+                                m.remove(&(txid.clone(), true));
+                                summary.finalized_count += 1;
+                                super::forget_executed_intentions(*txid);
+                                super::FINALIZED_TXIDS.with_borrow_mut(|f| {
+                                    f.insert(txid.clone(), height);
+                                });
+                            } else {
+                                super::log::info!("retaining txid: {} for finalize retry", txid);
+                            }
                         }
                     });
                 });
             });
     });
 
+    // Forget finalizing heights and rollbacks older than the retention window
+    let retention = super::FINALIZED_RETENTION.with_borrow(|r| *r.get());
+    let oldest_kept = confirmed_height.saturating_sub(retention);
+    super::FINALIZED_TXIDS.with_borrow_mut(|f| {
+        let expired: Vec<Txid> = f
+            .iter()
+            .filter(|(_, height)| *height < oldest_kept)
+            .map(|(txid, _)| txid)
+            .collect();
+        for txid in expired {
+            f.remove(&txid);
+        }
+    });
+    super::ROLLED_BACK_TXIDS.with_borrow_mut(|r| {
+        let expired: Vec<Txid> = r
+            .iter()
+            .filter(|(_, height)| *height < oldest_kept)
+            .map(|(txid, _)| txid)
+            .collect();
+        for txid in expired {
+            r.remove(&txid);
+        }
+    });
+
     // Clean up old block data that's no longer needed
+    // Blocks still holding a confirmed record whose finalize failed are kept for the retry
     super::BLOCKS.with_borrow_mut(|m| {
         let heights_to_remove: Vec<u32> = m
-            .iter()
-            .take_while(|(height, _)| *height <= confirmed_height)
+            .range(..=confirmed_height)
+            .filter(|(_, block_info)| {
+                super::TX_RECORDS.with_borrow(|r| {
+                    !block_info
+                        .confirmed_txids
+                        .iter()
+                        .any(|txid| r.contains_key(&(txid.clone(), true)))
+                })
+            })
             .map(|(height, _)| height)
             .collect();
         for height in heights_to_remove {
-            ic_cdk::println!("removing block: {}", height);
+            super::log::debug!("removing block: {}", height);
             m.remove(&height);
+            summary.pruned_blocks += 1;
         }
     });
+
+    // A summary that stops counting finalized txids while blocks keep coming means
+    // finalization has stalled
+    super::log::info!("{:?}", summary);
+    super::LAST_BLOCK_SUMMARY.set(Some(summary));
     Ok(())
 }
 
-#[update(guard = "ensure_testnet4_orchestrator")]
+#[update(guard = "ensure_orchestrator")]
 // Accepts transaction execution requests from the orchestrator
 // Verifies the submitted PSBT (Partially Signed Bitcoin Transaction)
 // If validation passes, signs the token's UTXOs and updates the exchange token state
 // Only the orchestrator can call this function (ensured by the guard)
+// Every intention of the set that targets one of our pools is executed, all or nothing:
+// states are only committed once every intention has been validated and signed
+// The orchestrator calls once per intention_index, so a call for an intention an earlier
+// call already executed is acknowledged with the PSBT unchanged; that call returned its signatures
 pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
     let ExecuteTxArgs {
         psbt_hex,
@@ -171,15 +361,201 @@ pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
         zero_confirmed_tx_queue_length: _zero_confirmed_tx_queue_length,
     } = args;
 
+    if super::EXECUTED_INTENTIONS.with_borrow(|e| e.contains_key(&(txid, intention_index))) {
+        super::log::debug!("intention {} of {} already executed", intention_index, txid);
+        return Ok(psbt_hex);
+    }
+
     // Decode and deserialize the PSBT
     let raw = hex::decode(&psbt_hex).map_err(|_| "invalid psbt".to_string())?;
     let mut psbt = Psbt::deserialize(raw.as_slice()).map_err(|_| "invalid psbt".to_string())?;
 
-    // Extract the intention details
-    let intention = intention_set.intentions[intention_index as usize].clone();
+    // Validate every intention against its pool, holding each pool's guard until the end
+    let intentions = addressed_intentions(intention_set, intention_index)?;
+    let prepared = prepare_intentions(&psbt, txid, intentions)?;
+
+    // Sign the pool UTXOs spent by sells; nothing has been committed yet if this fails
+    let key_name = super::get_schnorr_key_name();
+    for p in prepared.iter().filter(|p| !p.spent_utxos.is_empty()) {
+        ree_pool_sign(
+            &mut psbt,
+            p.spent_utxos.iter().collect(),
+            &key_name,
+            p.derivation_path.clone(),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+
+    commit_intentions(txid, &prepared)?;
+    Ok(psbt.serialize_hex())
+}
+
+#[query]
+// Dry run of execute_tx: runs the same validation against the pools' current states and
+// reports the resulting amounts and nonces, without signing or committing anything
+pub fn validate_tx(
+    psbt_hex: String,
+    intention_set: IntentionSet,
+    intention_index: u32,
+) -> Result<super::TxPreview, String> {
+    let raw = hex::decode(&psbt_hex).map_err(|_| "invalid psbt".to_string())?;
+    let psbt = Psbt::deserialize(raw.as_slice()).map_err(|_| "invalid psbt".to_string())?;
+    preview_tx(&psbt, intention_set, intention_index)
+}
+
+// validate_tx on a decoded PSBT; the guards taken while validating are released on return
+fn preview_tx(
+    psbt: &Psbt,
+    intention_set: IntentionSet,
+    intention_index: u32,
+) -> Result<super::TxPreview, String> {
+    let txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string())
+        .map_err(|_| "invalid psbt".to_string())?;
+
+    let intentions = addressed_intentions(intention_set, intention_index)?;
+    let previews = prepare_intentions(psbt, txid, intentions)?
+        .into_iter()
+        .map(|p| super::IntentionPreview {
+            new_nonce: p.new_state.nonce,
+            pool_address: p.pool_address,
+            action: p.action,
+            btc_amount: p.btc_amount,
+            token_amount: p.token_amount,
+            exchange_rate: p.exchange_rate,
+        })
+        .collect();
+
+    Ok(super::TxPreview {
+        txid: txid.to_string(),
+        intentions: previews,
+    })
+}
+
+// The intentions of a set this canister has to execute, with their indices: the one at
+// intention_index is addressed to us, the others only if they target a pool this canister owns
+// A pool targeted twice would need the second intention validated against the first's
+// uncommitted state, so such sets are refused
+fn addressed_intentions(
+    intention_set: IntentionSet,
+    intention_index: u32,
+) -> Result<Vec<(u32, Intention)>, String> {
+    if intention_index as usize >= intention_set.intentions.len() {
+        return Err("invalid intention_index".to_string());
+    }
+
+    let intentions: Vec<(u32, Intention)> = intention_set
+        .intentions
+        .into_iter()
+        .enumerate()
+        .map(|(i, intention)| (i as u32, intention))
+        .filter(|(i, intention)| {
+            *i == intention_index || super::get_canvas_token(&intention.pool_address).is_some()
+        })
+        .collect();
+    for (n, (_, intention)) in intentions.iter().enumerate() {
+        if intentions[..n].iter().any(|(_, other)| other.pool_address == intention.pool_address) {
+            return Err(format!("intention set targets pool {} more than once", intention.pool_address));
+        }
+    }
+    Ok(intentions)
+}
+
+// Validates every intention, failing the whole set on the first invalid one
+fn prepare_intentions(
+    psbt: &Psbt,
+    txid: Txid,
+    intentions: Vec<(u32, Intention)>,
+) -> Result<Vec<PreparedIntention>, String> {
+    intentions
+        .into_iter()
+        .map(|(intention_index, intention)| prepare_intention(psbt, txid, intention_index, intention))
+        .collect()
+}
+
+// Commits the new states of a validated set at once, after checking every token is still
+// there, has not been paused and has not moved on: the token may have been paused, or another
+// transaction against the same pool committed, while the set was being signed
+// If any check fails no state is committed
+fn commit_intentions(txid: Txid, prepared: &[PreparedIntention]) -> Result<(), String> {
+    super::CANVAS_TOKENS
+        .with_borrow_mut(|m| {
+            let mut tokens = vec![];
+            for p in prepared {
+                let mut token = m.get(&p.pool_address).ok_or(ExchangeError::EmptyToken)?;
+                (!token.paused).then(|| ()).ok_or(ExchangeError::TokenPaused)?;
+                let current_nonce = token.current_state().nonce;
+                (current_nonce + 1 == p.new_state.nonce)
+                    .then(|| ())
+                    .ok_or(ExchangeError::TokenStateExpired(current_nonce))?;
+                token.commit(p.new_state.clone())?;
+                tokens.push(token);
+            }
+            for (token, p) in tokens.into_iter().zip(prepared) {
+                m.insert(p.pool_address.clone(), token);
+            }
+            Ok::<(), ExchangeError>(())
+        })
+        .map_err(|e| e.to_string())?;
+
+    super::EXECUTED_INTENTIONS.with_borrow_mut(|e| {
+        for p in prepared {
+            e.insert((txid, p.intention_index), p.pool_address.clone());
+        }
+    });
+    super::TX_RECORDS.with_borrow_mut(|m| {
+        let mut record = m.get(&(txid.clone(), false)).unwrap_or_default();
+        for p in prepared {
+            super::log::debug!("new unconfirmed txid: {} in token: {}", txid, p.pool_address);
+            if !record.pools.contains(&p.pool_address) {
+                record.pools.push(p.pool_address.clone());
+            }
+        }
+        m.insert((txid.clone(), false), record);
+    });
+
+    for p in prepared {
+        super::record_trade_event(super::TradeEvent {
+            txid,
+            pool: p.pool_address.clone(),
+            action: p.action.clone(),
+            btc_amount: p.btc_amount,
+            token_amount: p.token_amount,
+            rate: p.exchange_rate,
+            timestamp: ic_cdk::api::time(),
+            rolled_back: false,
+        });
+    }
+    Ok(())
+}
+
+// An intention that passed validation, waiting to be signed and committed
+struct PreparedIntention {
+    _guard: ExecuteTxGuard,
+    _payout: Option<PayoutGuard>,
+    intention_index: u32,
+    pool_address: String,
+    action: String,
+    new_state: TokenState,
+    btc_amount: u64,
+    token_amount: u128,
+    exchange_rate: u64,
+    // Pool UTXOs to sign for, only non-empty for sells
+    spent_utxos: Vec<Utxo>,
+    derivation_path: Vec<Vec<u8>>,
+}
+
+// Locks the intention's pool and validates the intention and the PSBT against it
+// Returns the resulting state without committing it
+fn prepare_intention(
+    psbt: &Psbt,
+    txid: Txid,
+    intention_index: u32,
+    intention: Intention,
+) -> Result<PreparedIntention, String> {
     let Intention {
         exchange_id: _,
-        action: _,
+        action,
         action_params,
         pool_address,
         nonce,
@@ -189,23 +565,64 @@ pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
         output_coins,
     } = intention;
 
-    // Extract exchange rate from action_params
-    // For now, use a default rate - this should be passed via action_params in the future
-    // TODO: Parse exchange_rate from action_params based on actual type
-    let exchange_rate: u64 = 100; // Default rate
+    // action_params optionally carries the user's minimum output (tokens for a buy,
+    // satoshis for a sell) as a decimal string; empty means no slippage floor
+    let min_out: Option<u128> = if action_params.is_empty() {
+        None
+    } else {
+        Some(
+            action_params
+                .parse()
+                .map_err(|_| "invalid action_params, expected minimum output".to_string())?,
+        )
+    };
 
-    let _guard = ExecuteTxGuard::new(pool_address.clone())
-        .ok_or(format!("Token {0} Executing", pool_address).to_string())?;
+    let guard = ExecuteTxGuard::new(pool_address.clone(), txid)
+        .ok_or(format!("pool {} is already executing an intention of {}, retry later", pool_address, txid))?;
 
     // Get the canvas token from storage
-    let canvas_token = super::CANVAS_TOKENS
-        .with_borrow(|m| m.get(&pool_address).expect("already checked in pre_*; qed"));
+    // It may have been removed since the pre_* check, so fail the tx rather than trap
+    let canvas_token = super::get_canvas_token(&pool_address)
+        .ok_or(ExchangeError::EmptyToken.to_string())?;
+    if canvas_token.paused {
+        return Err(ExchangeError::TokenPaused.to_string());
+    }
+
+    // A re-delivered intention must not be applied twice: it is either still pending, or its
+    // transaction has been finalized, after which no intention of it can be new
+    // Other intentions of a pending transaction, even on the same pool, are fine
+    let executed = super::EXECUTED_INTENTIONS.with_borrow(|e| e.contains_key(&(txid, intention_index)));
+    let finalized = super::FINALIZED_TXIDS.with_borrow(|f| f.contains_key(&txid));
+    if executed || finalized {
+        return Err(ExchangeError::DuplicateTxid(txid.to_string()).to_string());
+    }
+    if super::ROLLED_BACK_TXIDS.with_borrow(|r| r.contains_key(&txid)) {
+        return Err(ExchangeError::RolledBackTxid(txid.to_string()).to_string());
+    }
+
+    // The PSBT must move exactly the pool UTXOs the intention claims, before anything is signed
+    verify_psbt(
+        psbt,
+        txid,
+        &canvas_token.addr,
+        &pool_utxo_spent,
+        &pool_utxo_received,
+        &input_coins,
+        &output_coins,
+    )
+    .map_err(|e| e.to_string())?;
+
+    // Validate against the same rate the pre_* quotes used
+    let exchange_rate = canvas_token.get_current_exchange_rate();
+
+    // The BTC paid in for a buy; validated by the buy branch below
+    let input_value = input_coins.first().map(|c| c.coin.value).unwrap_or_default();
 
     // Process the transaction based on the action type
-    match intention.action.as_ref() {
+    let (new_state, btc_amount, token_amount, spent_utxos, payout) = match action.as_ref() {
         "buy_token" => {
             // Validate the buy token transaction and get the new token state
-            let (new_state, _token_amount) = canvas_token
+            let (new_state, token_amount) = canvas_token
                 .validate_buy_token(
                     txid,
                     nonce,
@@ -214,24 +631,27 @@ pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
                     input_coins,
                     output_coins,
                     exchange_rate,
+                    min_out,
                 )
                 .map_err(|e| e.to_string())?;
 
             // For buy_token, we don't need to sign anything since we're receiving BTC
             // The token minting is handled by the system
-
-            // Update the canvas token with the new state
-            super::CANVAS_TOKENS.with_borrow_mut(|m| {
-                let mut token = m
-                    .get(&pool_address)
-                    .expect("already checked in pre_buy_token; qed");
-                token.commit(new_state);
-                m.insert(pool_address.clone(), token);
-            });
+            (new_state, input_value as u64, token_amount, vec![], None)
         }
         "sell_token" => {
+            // The pool UTXOs this transaction spends, i.e. the inputs the pool must sign
+            let spent_utxos = canvas_token
+                .find_utxos(&pool_utxo_spent)
+                .map_err(|e| e.to_string())?;
+
+            let min_btc_out = min_out
+                .map(u64::try_from)
+                .transpose()
+                .map_err(|_| ExchangeError::Overflow.to_string())?;
+
             // Validate the sell token transaction and get the new token state
-            let (new_state, _btc_amount) = canvas_token
+            let (new_state, btc_amount, token_amount) = canvas_token
                 .validate_sell_token(
                     txid,
                     nonce,
@@ -240,43 +660,893 @@ pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
                     input_coins,
                     output_coins,
                     exchange_rate,
+                    min_btc_out,
+                    super::pending_payouts(&pool_address),
                 )
                 .map_err(|e| e.to_string())?;
 
-            // For sell_token, we need to sign the BTC output to pay the user
-            // Note: This is a simplified example - in practice, you'd need to track UTXOs properly
-            // Sign with the canvas token's key
-            // ree_pool_sign(
-            //     &mut psbt,
-            //     vec![...], // UTXOs to sign
-            //     crate::SCHNORR_KEY_NAME,
-            //     canvas_token.derivation_path(),
-            // )
-            // .await
-            // .map_err(|e| e.to_string())?;
-
-            // Update the canvas token with the new state
-            super::CANVAS_TOKENS.with_borrow_mut(|m| {
-                let mut token = m
-                    .get(&pool_address)
-                    .expect("already checked in pre_sell_token; qed");
-                token.commit(new_state);
-                m.insert(pool_address.clone(), token);
-            });
+            // Hold the payout until the state is committed, so sells validated while this one
+            // is being signed cannot promise the same BTC
+            let payout = PayoutGuard::new(pool_address.clone(), txid, btc_amount);
+
+            // For sell_token, the spent pool UTXOs are signed with the canvas token's key
+            (new_state, btc_amount, token_amount, spent_utxos, Some(payout))
+        }
+        "add_liquidity" => {
+            // Validate the donation and get the new token state
+            // Who may add liquidity is up to the orchestrator, which alone can call execute_tx
+            let (new_state, btc_amount) = canvas_token
+                .validate_add_liquidity(
+                    txid,
+                    nonce,
+                    pool_utxo_spent,
+                    pool_utxo_received,
+                    input_coins,
+                    output_coins,
+                )
+                .map_err(|e| e.to_string())?;
+
+            // Like buy_token, the pool only receives BTC and signs nothing
+            (new_state, btc_amount, 0, vec![], None)
         }
         _ => {
             return Err("invalid method".to_string());
         }
+    };
+
+    Ok(PreparedIntention {
+        _guard: guard,
+        _payout: payout,
+        intention_index,
+        pool_address,
+        action,
+        new_state,
+        btc_amount,
+        token_amount,
+        exchange_rate,
+        spent_utxos,
+        derivation_path: canvas_token.derivation_path(),
+    })
+}
+
+// Cross-checks the PSBT against an intention:
+// the PSBT is the transaction `txid` names, every spent pool UTXO is an input, the outputs
+// paying the pool address are exactly the received pool UTXOs, the BTC the coins move in
+// and out of the pool matches what the pool's inputs and outputs move, and every BTC output
+// coin is paid to its recipient
+fn verify_psbt(
+    psbt: &Psbt,
+    txid: Txid,
+    pool_address: &str,
+    pool_utxo_spent: &[String],
+    pool_utxo_received: &[Utxo],
+    input_coins: &[InputCoin],
+    output_coins: &[OutputCoin],
+) -> Result<(), ExchangeError> {
+    let tx = &psbt.unsigned_tx;
+
+    (Txid::from_str(&tx.compute_txid().to_string()).ok() == Some(txid))
+        .then(|| ())
+        .ok_or(ExchangeError::InvalidSignPsbtArgs("psbt does not match the txid".to_string()))?;
+
+    let pool_script = address_script(pool_address)?;
+
+    // The pool's spent inputs, valued by the UTXOs the PSBT says they spend
+    let mut spent_sats: i128 = 0;
+    for outpoint in pool_utxo_spent {
+        let index = tx
+            .input
+            .iter()
+            .position(|input| input.previous_output.to_string() == *outpoint)
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "pool utxo {} is not spent by the psbt",
+                outpoint
+            )))?;
+        let prevout = psbt
+            .inputs
+            .get(index)
+            .and_then(|input| input.witness_utxo.as_ref())
+            .filter(|prevout| prevout.script_pubkey == pool_script)
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "psbt input {} is missing the pool's witness utxo",
+                index
+            )))?;
+        spent_sats += prevout.value.to_sat() as i128;
     }
 
-    super::TX_RECORDS.with_borrow_mut(|m| {
-        ic_cdk::println!("new unconfirmed txid: {} in token: {} ", txid, pool_address);
-        let mut record = m.get(&(txid.clone(), false)).unwrap_or_default();
-        if !record.pools.contains(&pool_address) {
-            record.pools.push(pool_address.clone());
+    for utxo in pool_utxo_received {
+        let output = (utxo.txid == txid)
+            .then(|| tx.output.get(utxo.vout as usize))
+            .flatten()
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "pool utxo {}:{} is not an output of the psbt",
+                utxo.txid, utxo.vout
+            )))?;
+        (output.script_pubkey == pool_script && output.value.to_sat() == utxo.sats)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "psbt output {} mismatch with the received pool utxo",
+                utxo.vout
+            )))?;
+    }
+
+    // No output may pay the pool without being recorded as a received pool UTXO
+    let mut received_sats: i128 = 0;
+    for (vout, output) in tx.output.iter().enumerate() {
+        if output.script_pubkey != pool_script {
+            continue;
         }
-        m.insert((txid.clone(), false), record);
-    });
+        pool_utxo_received
+            .iter()
+            .any(|utxo| utxo.vout as usize == vout)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "psbt output {} pays the pool but is not a received pool utxo",
+                vout
+            )))?;
+        received_sats += output.value.to_sat() as i128;
+    }
 
-    Ok(psbt.serialize_hex())
-}
\ No newline at end of file
+    let btc_in: u128 = input_coins
+        .iter()
+        .filter(|c| c.coin.id == CoinId::btc())
+        .map(|c| c.coin.value)
+        .sum();
+    let btc_out: u128 = output_coins
+        .iter()
+        .filter(|c| c.coin.id == CoinId::btc())
+        .map(|c| c.coin.value)
+        .sum();
+    (received_sats - spent_sats == btc_in as i128 - btc_out as i128)
+        .then(|| ())
+        .ok_or(ExchangeError::InvalidSignPsbtArgs(
+            "btc coins mismatch with the pool's inputs and outputs in the psbt".to_string(),
+        ))?;
+
+    // Every BTC output coin must actually reach its recipient
+    for coin in output_coins.iter().filter(|c| c.coin.id == CoinId::btc()) {
+        let script = address_script(&coin.to)?;
+        let paid: u128 = tx
+            .output
+            .iter()
+            .filter(|output| output.script_pubkey == script)
+            .map(|output| output.value.to_sat() as u128)
+            .sum();
+        (paid >= coin.coin.value)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "psbt does not pay {} sats to {}",
+                coin.coin.value, coin.to
+            )))?;
+    }
+
+    Ok(())
+}
+
+// The script_pubkey an address is paid with
+fn address_script(address: &str) -> Result<ScriptBuf, ExchangeError> {
+    Ok(Address::<NetworkUnchecked>::from_str(address)
+        .map_err(|_| ExchangeError::InvalidSignPsbtArgs(format!("invalid address {}", address)))?
+        .assume_checked()
+        .script_pubkey())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ree::token_pool::{
+        PricingMode,
+        tests::{funded, input, output, state, token, txid, utxo},
+    };
+    use crate::ree::{
+        BLOCKS, CANVAS_TOKENS, EXECUTED_INTENTIONS, FINALIZED_TXIDS, LAST_BLOCK_SUMMARY, ROLLED_BACK_TXIDS,
+        TX_RECORDS, forget_executed_intentions, trade_events_of,
+    };
+    use ree_types::{
+        CoinBalance, TxRecord,
+        bitcoin::{
+            Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness, absolute::LockTime,
+            transaction::Version,
+        },
+    };
+
+    fn empty_psbt() -> Psbt {
+        Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap()
+    }
+
+    fn intention(pool_address: &str) -> Intention {
+        Intention {
+            exchange_id: "PIXEL_LAND".to_string(),
+            action: "buy_token".to_string(),
+            action_params: String::new(),
+            pool_address: pool_address.to_string(),
+            nonce: 0,
+            pool_utxo_spent: vec![],
+            pool_utxo_received: vec![],
+            input_coins: vec![],
+            output_coins: vec![],
+        }
+    }
+
+    fn block(block_height: u32, confirmed_txids: Vec<Txid>) -> NewBlockArgs {
+        NewBlockArgs {
+            block_height,
+            block_hash: format!("hash{}", block_height),
+            block_timestamp: 0,
+            confirmed_txids,
+        }
+    }
+
+    #[test]
+    fn new_block_drops_finalize_records_missing_from_the_chain() {
+        // txid(1) is still in the pool's chain, txid(2) was cut out by a rollback
+        let mut pool = token(PricingMode::FixedRate);
+        pool.commit(state(1)).unwrap();
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        TX_RECORDS.with_borrow_mut(|r| {
+            for n in [1, 2] {
+                r.insert((txid(n), false), TxRecord { pools: vec![pool.addr.clone()] });
+            }
+        });
+
+        new_block(block(100, vec![txid(1), txid(2)])).unwrap();
+        new_block(block(106, vec![])).unwrap();
+
+        let summary = LAST_BLOCK_SUMMARY.get().unwrap();
+        assert_eq!((summary.finalized_count, summary.dropped_count), (1, 1));
+        assert!(TX_RECORDS.with_borrow(|r| r.is_empty()));
+        assert!(BLOCKS.with_borrow(|b| !b.contains_key(&100)));
+        assert_eq!(FINALIZED_TXIDS.with_borrow(|f| f.get(&txid(1))), Some(100));
+        assert!(FINALIZED_TXIDS.with_borrow(|f| !f.contains_key(&txid(2))));
+    }
+
+    #[test]
+    fn replacing_a_block_rolls_back_the_txids_it_orphans() {
+        let mut pool = token(PricingMode::FixedRate);
+        for n in [1, 2] {
+            pool.commit(state(n)).unwrap();
+        }
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        TX_RECORDS.with_borrow_mut(|r| {
+            for n in [1, 2] {
+                r.insert((txid(n), false), TxRecord { pools: vec![pool.addr.clone()] });
+            }
+        });
+        new_block(block(100, vec![txid(1), txid(2)])).unwrap();
+
+        // The same block again changes nothing
+        new_block(block(100, vec![txid(1), txid(2)])).unwrap();
+        assert!(TX_RECORDS.with_borrow(|r| r.contains_key(&(txid(2), true))));
+
+        let replacement = NewBlockArgs {
+            block_hash: "reorg100".to_string(),
+            ..block(100, vec![txid(1)])
+        };
+        new_block(replacement).unwrap();
+
+        let pool = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        assert!(pool.holds_txid(txid(1)));
+        assert!(!pool.holds_txid(txid(2)));
+        assert!(TX_RECORDS.with_borrow(|r| r.contains_key(&(txid(1), true))));
+        assert!(TX_RECORDS.with_borrow(|r| !r.contains_key(&(txid(2), true))));
+        assert!(ROLLED_BACK_TXIDS.with_borrow(|r| r.contains_key(&txid(2))));
+        assert_eq!(BLOCKS.with_borrow(|b| b.get(&100)).unwrap().block_hash, "reorg100");
+    }
+
+    const POOL: &str = "bc1qpool";
+    const USER: &str = "bc1quser";
+
+    // A transaction spending one 10_000 sat pool UTXO into a pool output and a user output
+    fn pool_psbt(pool_sats: u64, user_sats: u64) -> Psbt {
+        let script = |address: &str| address_script(address).unwrap();
+        let mut psbt = Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::from_str(&format!("{}:0", txid(1))).unwrap(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut { value: Amount::from_sat(pool_sats), script_pubkey: script(POOL) },
+                TxOut { value: Amount::from_sat(user_sats), script_pubkey: script(USER) },
+            ],
+        })
+        .unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: script(POOL),
+        });
+        psbt
+    }
+
+    #[test]
+    fn verify_psbt_rejects_a_psbt_diverging_from_the_intention() {
+        let honest = pool_psbt(7_000, 3_000);
+        let psbt_txid = Txid::from_str(&honest.unsigned_tx.compute_txid().to_string()).unwrap();
+        let spent = vec![honest.unsigned_tx.input[0].previous_output.to_string()];
+        let received = |sats| vec![Utxo { txid: psbt_txid, vout: 0, coins: vec![], sats }];
+        let paid_out = |value| {
+            vec![OutputCoin {
+                to: USER.to_string(),
+                coin: CoinBalance { id: CoinId::btc(), value },
+            }]
+        };
+        let verify = |psbt: &Psbt, txid, received: &[Utxo], btc_out| {
+            verify_psbt(psbt, txid, POOL, &spent, received, &[], &paid_out(btc_out))
+        };
+
+        // The pool pays 3_000 sats out of its 10_000 sat UTXO
+        assert!(verify(&honest, psbt_txid, &received(7_000), 3_000).is_ok());
+
+        let tampered = [
+            // the PSBT is not the transaction the intention was validated for
+            verify(&pool_psbt(7_000, 3_000), txid(9), &received(7_000), 3_000),
+            // the pool output holds less than the received pool UTXO records
+            verify(&pool_psbt(6_000, 4_000), psbt_txid, &received(7_000), 3_000),
+            // the pool loses more than the intention pays out
+            verify(&pool_psbt(6_000, 4_000), psbt_txid, &received(6_000), 3_000),
+            // the user is paid less than the output coin promises
+            verify(&pool_psbt(7_000, 2_000), psbt_txid, &received(7_000), 3_000),
+            // an output pays the pool without being a received pool UTXO
+            verify(&pool_psbt(7_000, 3_000), psbt_txid, &[], 3_000),
+        ];
+        for result in tampered {
+            assert!(matches!(result, Err(ExchangeError::InvalidSignPsbtArgs(_))), "{:?}", result);
+        }
+
+        let mut unfunded = pool_psbt(7_000, 3_000);
+        unfunded.inputs[0].witness_utxo = None;
+        assert!(verify(&unfunded, psbt_txid, &received(7_000), 3_000).is_err());
+    }
+
+    // A funded pool at `addr`, stored in CANVAS_TOKENS
+    fn stored_pool(addr: &str) -> CanvasToken {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.addr = addr.to_string();
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        pool
+    }
+
+    // A transaction paying `sats` into each of `pools`, output i to pool i
+    fn deposit_psbt(pools: &[&CanvasToken], sats: u64) -> Psbt {
+        let output = pools
+            .iter()
+            .map(|pool| TxOut {
+                value: Amount::from_sat(sats),
+                script_pubkey: address_script(&pool.addr).unwrap(),
+            })
+            .collect();
+        Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output,
+        })
+        .unwrap()
+    }
+
+    // Buys with the `sats` paid into `pool` by output `vout` of `psbt`
+    fn buy_intention(pool: &CanvasToken, psbt: &Psbt, vout: u32, sats: u64) -> Intention {
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        Intention {
+            pool_address: pool.addr.clone(),
+            nonce: pool.current_state().nonce,
+            pool_utxo_received: vec![Utxo {
+                vout,
+                ..utxo(psbt_txid, sats)
+            }],
+            input_coins: input(CoinId::btc(), sats as u128),
+            output_coins: vec![output(pool.token_id(), sats as u128 * 10)],
+            ..intention(&pool.addr)
+        }
+    }
+
+    fn intention_set(intentions: Vec<Intention>) -> IntentionSet {
+        IntentionSet {
+            initiator_address: "bc1quser".to_string(),
+            tx_fee_in_sats: 0,
+            intentions,
+        }
+    }
+
+    #[test]
+    fn every_owned_intention_is_addressed_and_each_pool_only_once() {
+        let (a, b) = (stored_pool("bc1qpoola"), stored_pool("bc1qpoolb"));
+        let psbt = deposit_psbt(&[&a, &b], 1_000);
+        let set = intention_set(vec![
+            intention("bc1qelsewhere"),
+            buy_intention(&a, &psbt, 0, 1_000),
+            buy_intention(&b, &psbt, 1, 1_000),
+        ]);
+
+        let indices = |set, index| {
+            addressed_intentions(set, index).map(|i| i.into_iter().map(|(i, _)| i).collect::<Vec<_>>())
+        };
+        assert_eq!(indices(set.clone(), 1), Ok(vec![1, 2]));
+        assert_eq!(indices(set.clone(), 0), Ok(vec![0, 1, 2]));
+        assert!(indices(set, 3).is_err());
+
+        let twice = intention_set(vec![buy_intention(&a, &psbt, 0, 1_000), buy_intention(&a, &psbt, 0, 1_000)]);
+        assert!(indices(twice, 0).is_err());
+    }
+
+    #[test]
+    fn a_failing_second_intention_leaves_the_first_uncommitted() {
+        let (a, b) = (stored_pool("bc1qpoola"), stored_pool("bc1qpoolb"));
+        let psbt = deposit_psbt(&[&a, &b], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let untouched = || {
+            CANVAS_TOKENS.with_borrow(|t| t.get(&a.addr)).unwrap().states == a.states
+                && TX_RECORDS.with_borrow(|r| r.is_empty())
+                && EXECUTED_INTENTIONS.with_borrow(|e| e.is_empty())
+                && trade_events_of(psbt_txid).is_empty()
+        };
+
+        // The second intention fails validation
+        let stale = Intention {
+            nonce: 0,
+            ..buy_intention(&b, &psbt, 1, 1_000)
+        };
+        let set = intention_set(vec![buy_intention(&a, &psbt, 0, 1_000), stale]);
+        assert!(prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).is_err());
+        assert!(untouched());
+
+        // The second pool moves on while the set is being signed
+        let set = intention_set(vec![buy_intention(&a, &psbt, 0, 1_000), buy_intention(&b, &psbt, 1, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set.clone(), 0).unwrap()).unwrap();
+        let mut moved = b.clone();
+        moved.commit(TokenState { nonce: 2, ..b.current_state() }).unwrap();
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(b.addr.clone(), moved));
+        assert!(commit_intentions(psbt_txid, &prepared).is_err());
+        drop(prepared);
+        assert!(untouched());
+
+        // Once both validate and commit, one record covers both pools
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(b.addr.clone(), b.clone()));
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).unwrap();
+        commit_intentions(psbt_txid, &prepared).unwrap();
+        let record = TX_RECORDS.with_borrow(|r| r.get(&(psbt_txid, false))).unwrap();
+        assert_eq!(record.pools, vec![a.addr.clone(), b.addr.clone()]);
+        assert_eq!(EXECUTED_INTENTIONS.with_borrow(|e| e.len()), 2);
+        assert_eq!(trade_events_of(psbt_txid).len(), 2);
+    }
+
+    #[test]
+    fn a_token_paused_while_signing_is_not_committed_until_resumed() {
+        let pool = stored_pool("bc1qpoola");
+        let psbt = deposit_psbt(&[&pool], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&pool, &psbt, 0, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).unwrap();
+
+        crate::ree::token::set_token_paused(pool.addr.clone(), true).unwrap();
+        assert!(matches!(
+            crate::ree::token::pre_buy_token(pool.addr.clone(), 1_000, None, None),
+            Err(ExchangeError::TokenPaused)
+        ));
+        assert_eq!(commit_intentions(psbt_txid, &prepared), Err(ExchangeError::TokenPaused.to_string()));
+        assert_eq!(CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap().states, pool.states);
+        assert!(TX_RECORDS.with_borrow(|r| r.is_empty()));
+
+        crate::ree::token::set_token_paused(pool.addr.clone(), false).unwrap();
+        assert!(crate::ree::token::pre_buy_token(pool.addr.clone(), 1_000, None, None).is_ok());
+        commit_intentions(psbt_txid, &prepared).unwrap();
+        let token = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        assert_eq!(token.current_state().nonce, pool.current_state().nonce + 1);
+    }
+
+    #[test]
+    fn executed_intentions_are_keyed_by_txid_and_index() {
+        let pool = token(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert("executed".to_string(), pool));
+        EXECUTED_INTENTIONS.with_borrow_mut(|e| e.insert((txid(5), 0), "executed".to_string()));
+        let duplicate = ExchangeError::DuplicateTxid(txid(5).to_string()).to_string();
+
+        let replayed = prepare_intention(&empty_psbt(), txid(5), 0, intention("executed"));
+        assert_eq!(replayed.err(), Some(duplicate.clone()));
+        // Another intention of the same transaction on the same pool is not a replay
+        let sibling = prepare_intention(&empty_psbt(), txid(5), 1, intention("executed"));
+        assert_ne!(sibling.err(), Some(duplicate.clone()));
+
+        forget_executed_intentions(txid(5));
+        let retried = prepare_intention(&empty_psbt(), txid(5), 0, intention("executed"));
+        assert_ne!(retried.err(), Some(duplicate));
+    }
+
+    #[test]
+    fn a_token_removed_mid_flight_fails_the_transaction() {
+        let pool = stored_pool("bc1qpoola");
+        let psbt = deposit_psbt(&[&pool], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&pool, &psbt, 0, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).unwrap();
+
+        // Removed while the set is being signed: the commit fails instead of trapping
+        CANVAS_TOKENS.with_borrow_mut(|t| t.remove(&pool.addr));
+        assert_eq!(commit_intentions(psbt_txid, &prepared), Err(ExchangeError::EmptyToken.to_string()));
+        assert!(TX_RECORDS.with_borrow(|r| r.is_empty()));
+        assert!(trade_events_of(psbt_txid).is_empty());
+        drop(prepared);
+
+        // Removed before validation
+        let intentions = vec![(0, buy_intention(&pool, &psbt, 0, 1_000))];
+        assert_eq!(
+            prepare_intentions(&psbt, psbt_txid, intentions).err(),
+            Some(ExchangeError::EmptyToken.to_string())
+        );
+    }
+
+    #[test]
+    fn a_shallower_confirmation_depth_finalizes_sooner() {
+        let mut pool = token(PricingMode::FixedRate);
+        pool.commit(state(1)).unwrap();
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        TX_RECORDS.with_borrow_mut(|r| r.insert((txid(1), false), TxRecord { pools: vec![pool.addr.clone()] }));
+        let finalized = || FINALIZED_TXIDS.with_borrow(|f| f.contains_key(&txid(1)));
+
+        // At the default depth of 6, one block on top is not enough
+        new_block(block(100, vec![txid(1)])).unwrap();
+        new_block(block(101, vec![])).unwrap();
+        assert!(!finalized());
+
+        assert!(crate::ree::token::set_confirmation_depth(0).is_err());
+        crate::ree::token::set_confirmation_depth(1).unwrap();
+        assert_eq!(crate::ree::token::get_confirmation_depth(), 1);
+        new_block(block(102, vec![])).unwrap();
+        assert!(finalized());
+        assert!(TX_RECORDS.with_borrow(|r| r.is_empty()));
+    }
+
+    #[test]
+    fn pool_info_reports_the_utxos_a_buy_received() {
+        let pool = stored_pool("bc1qpoola");
+        let psbt = deposit_psbt(&[&pool], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&pool, &psbt, 0, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).unwrap();
+        commit_intentions(psbt_txid, &prepared).unwrap();
+
+        let info = get_pool_info(GetPoolInfoArgs { pool_address: pool.addr.clone() }).unwrap();
+        assert_eq!(info.utxos, vec![utxo(txid(1), 100_000), utxo(psbt_txid, 1_000)]);
+        assert_eq!(info.btc_reserved, 101_000);
+        assert_eq!(info.utxos.iter().map(|u| u.sats).sum::<u64>(), info.btc_reserved);
+        assert_eq!(info.coin_reserved, vec![CoinBalance { id: pool.token_id(), value: 1_000_000 }]);
+        assert_eq!(info.nonce, 2);
+    }
+
+    #[test]
+    fn a_full_state_chain_refuses_commits_until_blocks_finalize_it() {
+        assert!(crate::ree::token::set_max_pending_states(0).is_err());
+        crate::ree::token::set_max_pending_states(2).unwrap();
+        let mut pool = token(PricingMode::FixedRate);
+        for n in [1, 2] {
+            pool.commit(state(n)).unwrap();
+        }
+        assert!(matches!(pool.commit(state(3)), Err(ExchangeError::InvalidState(_))));
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        TX_RECORDS.with_borrow_mut(|r| {
+            for n in [1, 2] {
+                r.insert((txid(n), false), TxRecord { pools: vec![pool.addr.clone()] });
+            }
+        });
+
+        new_block(block(100, vec![txid(1), txid(2)])).unwrap();
+        new_block(block(106, vec![])).unwrap();
+        let mut pool = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        assert_eq!(pool.states.len(), 1);
+        pool.commit(state(3)).unwrap();
+        assert!(pool.commit(state(4)).is_err());
+
+        // Rollback and finalize still work on a full chain
+        pool.rollback(txid(3)).unwrap();
+        pool.commit(state(3)).unwrap();
+        pool.finalize(txid(3)).unwrap();
+        assert_eq!(pool.states, vec![state(3)]);
+    }
+
+    #[test]
+    fn pool_pages_follow_the_address_order_of_the_full_list() {
+        for n in [3, 0, 4, 1, 2] {
+            stored_pool(&format!("bc1qpool{}", n));
+        }
+        let addresses = |pools: GetPoolListResponse| pools.into_iter().map(|p| p.address).collect::<Vec<_>>();
+        let full = addresses(get_pool_list());
+        assert_eq!(full, (0..5).map(|n| format!("bc1qpool{}", n)).collect::<Vec<_>>());
+
+        let paged: Vec<String> = (0..3).flat_map(|page| addresses(get_pool_list_paged(page * 2, 2))).collect();
+        assert_eq!(paged, full);
+        assert!(get_pool_list_paged(5, 2).is_empty());
+
+        for n in 5..=MAX_POOL_LIST_PAGE {
+            stored_pool(&format!("bc1qpool{}", n));
+        }
+        assert_eq!(get_pool_list_paged(0, u64::MAX).len() as u64, MAX_POOL_LIST_PAGE);
+    }
+
+    #[test]
+    fn each_pool_reports_its_own_minimal_tx_value() {
+        let (a, b) = (stored_pool("bc1qpoola"), stored_pool("bc1qpoolb"));
+        crate::ree::token::set_min_tx_value(b.addr.clone(), Some(20_000)).unwrap();
+        let minimal = |pool_address: &str| {
+            get_minimal_tx_value(GetMinimalTxValueArgs {
+                pool_address: pool_address.to_string(),
+                zero_confirmed_tx_queue_length: 0,
+            })
+        };
+        assert_eq!((minimal(&a.addr), minimal(&b.addr)), (1, 20_000));
+        assert_eq!(minimal("bc1qunknown"), crate::ree::DEFAULT_MIN_BTC_VALUE);
+
+        crate::ree::token::set_min_tx_value(b.addr.clone(), None).unwrap();
+        assert_eq!(minimal(&b.addr), crate::ree::DEFAULT_MIN_BTC_VALUE);
+    }
+
+    #[test]
+    fn a_pool_is_pending_from_execution_until_its_block_confirms_it() {
+        use crate::ree::token::get_pending_pools;
+
+        let (a, b) = (stored_pool("bc1qpoola"), stored_pool("bc1qpoolb"));
+        assert!(get_pending_pools().is_empty());
+        let psbt = deposit_psbt(&[&a, &b], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&a, &psbt, 0, 1_000), buy_intention(&b, &psbt, 1, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).unwrap();
+        commit_intentions(psbt_txid, &prepared).unwrap();
+        TX_RECORDS.with_borrow_mut(|r| r.insert((txid(9), false), TxRecord { pools: vec![a.addr.clone()] }));
+
+        assert_eq!(get_pending_pools(), vec![(a.addr.clone(), 2), (b.addr.clone(), 1)]);
+        new_block(block(100, vec![psbt_txid])).unwrap();
+        assert_eq!(get_pending_pools(), vec![(a.addr.clone(), 1)]);
+    }
+
+    #[test]
+    fn blocks_below_the_threshold_are_finalized_across_height_gaps() {
+        let mut pool = token(PricingMode::FixedRate);
+        for n in [1, 2] {
+            pool.commit(state(n)).unwrap();
+        }
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        TX_RECORDS.with_borrow_mut(|r| {
+            for n in [1, 2] {
+                r.insert((txid(n), false), TxRecord { pools: vec![pool.addr.clone()] });
+            }
+        });
+
+        new_block(block(100, vec![txid(1)])).unwrap();
+        new_block(block(103, vec![txid(2)])).unwrap();
+        new_block(block(105, vec![])).unwrap();
+        new_block(block(110, vec![])).unwrap();
+
+        let summary = LAST_BLOCK_SUMMARY.get().unwrap();
+        assert_eq!((summary.finalized_count, summary.pruned_blocks), (2, 2));
+        assert_eq!(BLOCKS.with_borrow(|b| b.iter().map(|(height, _)| height).collect::<Vec<_>>()), vec![105, 110]);
+        assert_eq!(FINALIZED_TXIDS.with_borrow(|f| f.get(&txid(2))), Some(103));
+        let pool = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        assert_eq!(pool.states, vec![state(2)]);
+    }
+
+    #[test]
+    fn the_same_block_sent_twice_changes_nothing_the_second_time() {
+        let mut pool = token(PricingMode::FixedRate);
+        for n in [1, 2] {
+            pool.commit(state(n)).unwrap();
+        }
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        TX_RECORDS.with_borrow_mut(|r| {
+            for n in [1, 2] {
+                r.insert((txid(n), false), TxRecord { pools: vec![pool.addr.clone()] });
+            }
+        });
+        let snapshot = || {
+            (
+                BLOCKS.with_borrow(|b| b.iter().collect::<Vec<_>>()),
+                TX_RECORDS.with_borrow(|r| r.iter().map(|(key, _)| key).collect::<Vec<_>>()),
+                CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap().states,
+                FINALIZED_TXIDS.with_borrow(|f| f.iter().collect::<Vec<_>>()),
+                LAST_BLOCK_SUMMARY.get(),
+            )
+        };
+
+        new_block(block(100, vec![txid(1)])).unwrap();
+        // Confirms txid(2) and finalizes txid(1)
+        new_block(block(106, vec![txid(2)])).unwrap();
+        let processed = snapshot();
+        assert_eq!(processed.1, vec![(txid(2), true)]);
+
+        new_block(block(106, vec![txid(2)])).unwrap();
+        assert_eq!(snapshot(), processed);
+    }
+
+    #[test]
+    fn the_finalizing_height_is_kept_for_the_retention_window() {
+        use crate::ree::token::{get_finalizing_height, set_finalized_retention};
+
+        let mut pool = token(PricingMode::FixedRate);
+        pool.commit(state(1)).unwrap();
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        TX_RECORDS.with_borrow_mut(|r| r.insert((txid(1), false), TxRecord { pools: vec![pool.addr.clone()] }));
+        set_finalized_retention(2).unwrap();
+
+        new_block(block(100, vec![txid(1)])).unwrap();
+        assert_eq!(get_finalizing_height(txid(1)), None);
+        new_block(block(106, vec![])).unwrap();
+        assert_eq!(get_finalizing_height(txid(1)), Some(100));
+        assert_eq!(get_finalizing_height(txid(2)), None);
+
+        // Kept while the threshold is at most 2 blocks past it
+        new_block(block(108, vec![])).unwrap();
+        assert_eq!(get_finalizing_height(txid(1)), Some(100));
+        new_block(block(109, vec![])).unwrap();
+        assert_eq!(get_finalizing_height(txid(1)), None);
+    }
+
+    #[test]
+    fn a_preview_validates_like_execution_without_committing() {
+        let pool = stored_pool("bc1qpoola");
+        let psbt = deposit_psbt(&[&pool], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&pool, &psbt, 0, 1_000)]);
+
+        let preview = preview_tx(&psbt, set.clone(), 0).unwrap();
+        assert_eq!(preview.txid, psbt_txid.to_string());
+        assert_eq!(
+            preview.intentions,
+            vec![crate::ree::IntentionPreview {
+                pool_address: pool.addr.clone(),
+                action: "buy_token".to_string(),
+                btc_amount: 1_000,
+                token_amount: 10_000,
+                exchange_rate: 10,
+                new_nonce: 2,
+            }]
+        );
+        assert_eq!(CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap().states, pool.states);
+        assert!(TX_RECORDS.with_borrow(|r| r.is_empty()));
+        assert!(!crate::ree::is_executing(&pool.addr));
+
+        // An invalid transaction reports the error execution would fail with
+        let stale = intention_set(vec![Intention {
+            nonce: 0,
+            ..buy_intention(&pool, &psbt, 0, 1_000)
+        }]);
+        let executed = prepare_intentions(&psbt, psbt_txid, addressed_intentions(stale.clone(), 0).unwrap());
+        let previewed = preview_tx(&psbt, stale, 0);
+        assert_eq!(previewed.as_ref().err(), executed.as_ref().err());
+        assert_eq!(previewed.err(), Some(ExchangeError::TokenStateExpired(1).to_string()));
+    }
+
+    #[test]
+    fn pool_addresses_match_the_pool_list() {
+        assert!(get_pool_addresses().is_empty());
+        for addr in ["bc1qpoolb", "bc1qpoola", "bc1qpoolc"] {
+            stored_pool(addr);
+        }
+        let listed: Vec<String> = get_pool_list().into_iter().map(|p| p.address).collect();
+        assert_eq!(get_pool_addresses(), listed);
+        assert_eq!(listed, vec!["bc1qpoola", "bc1qpoolb", "bc1qpoolc"]);
+    }
+
+    #[test]
+    fn updated_metadata_shows_in_pool_info_and_list() {
+        use crate::ree::token::update_token_meta;
+
+        let pool = stored_pool("bc1qpoola");
+        assert!(update_token_meta(pool.addr.clone(), Some(" ".to_string()), None).is_err());
+        assert!(update_token_meta("bc1qunknown".to_string(), Some("CANVAS".to_string()), None).is_err());
+        update_token_meta(pool.addr.clone(), Some("CANVAS".to_string()), Some(7)).unwrap();
+
+        let info = get_pool_info(GetPoolInfoArgs { pool_address: pool.addr.clone() }).unwrap();
+        assert_eq!((info.name, info.address), ("CANVAS".to_string(), pool.addr.clone()));
+        assert_eq!(get_pool_list()[0].name, "CANVAS");
+        let token = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        assert_eq!(token.meta.min_amount, 7);
+        assert_eq!(token.states, pool.states);
+
+        // Leaving a field out keeps it
+        update_token_meta(pool.addr.clone(), None, Some(9)).unwrap();
+        assert_eq!(get_pool_list()[0].name, "CANVAS");
+    }
+
+    #[test]
+    fn a_rolled_back_buy_leaves_no_volume_behind() {
+        use crate::ree::token::{PoolStats, get_pool_stats};
+
+        let pool = stored_pool("bc1qpoola");
+        let stats = || get_pool_stats(pool.addr.clone()).unwrap();
+        let idle = PoolStats { cumulative_btc_volume: 0, trade_count: 0 };
+        assert_eq!(stats(), idle);
+
+        let psbt = deposit_psbt(&[&pool], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&pool, &psbt, 0, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).unwrap();
+        commit_intentions(psbt_txid, &prepared).unwrap();
+        drop(prepared);
+        assert_eq!(stats(), PoolStats { cumulative_btc_volume: 1_000, trade_count: 1 });
+
+        rollback_txid(psbt_txid).unwrap();
+        assert_eq!(stats(), idle);
+    }
+
+    #[test]
+    fn pool_infos_answer_each_address_in_place() {
+        stored_pool("bc1qpoola");
+        stored_pool("bc1qpoolb");
+        let addresses = ["bc1qpoolb", "bc1qunknown", "bc1qpoola"].map(String::from).to_vec();
+        let infos: Vec<Option<String>> = get_pool_infos(addresses)
+            .unwrap()
+            .into_iter()
+            .map(|info| info.map(|i| i.address))
+            .collect();
+        assert_eq!(infos, vec![Some("bc1qpoolb".to_string()), None, Some("bc1qpoola".to_string())]);
+
+        assert!(get_pool_infos(vec!["bc1qpoola".to_string(); MAX_POOL_INFOS_BATCH]).is_ok());
+        assert!(get_pool_infos(vec!["bc1qpoola".to_string(); MAX_POOL_INFOS_BATCH + 1]).is_err());
+    }
+
+    #[test]
+    fn a_rolled_back_txid_is_refused_until_the_replay_is_allowed() {
+        use crate::ree::token::allow_txid_replay;
+
+        let pool = stored_pool("bc1qpoola");
+        let psbt = deposit_psbt(&[&pool], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&pool, &psbt, 0, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set.clone(), 0).unwrap()).unwrap();
+        commit_intentions(psbt_txid, &prepared).unwrap();
+        drop(prepared);
+        rollback_txid(psbt_txid).unwrap();
+
+        let replay = || prepare_intentions(&psbt, psbt_txid, addressed_intentions(set.clone(), 0).unwrap()).map(drop);
+        assert_eq!(replay().err(), Some(ExchangeError::RolledBackTxid(psbt_txid.to_string()).to_string()));
+
+        allow_txid_replay(psbt_txid).unwrap();
+        assert!(replay().is_ok());
+        // Once allowed, the txid no longer counts as rolled back
+        assert!(allow_txid_replay(psbt_txid).is_err());
+    }
+
+    #[test]
+    fn the_total_reserved_sums_every_pool_reserve() {
+        assert_eq!(total_btc_reserved(), Ok(0));
+        let (a, b) = (stored_pool("bc1qpoola"), stored_pool("bc1qpoolb"));
+        let psbt = deposit_psbt(&[&a, &b], 1_000);
+        let psbt_txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let set = intention_set(vec![buy_intention(&a, &psbt, 0, 1_000), buy_intention(&b, &psbt, 1, 1_000)]);
+        let prepared = prepare_intentions(&psbt, psbt_txid, addressed_intentions(set, 0).unwrap()).unwrap();
+        commit_intentions(psbt_txid, &prepared).unwrap();
+        drop(prepared);
+
+        let reserved: u64 = get_pool_list()
+            .into_iter()
+            .map(|p| get_pool_info(GetPoolInfoArgs { pool_address: p.address }).unwrap().btc_reserved)
+            .sum();
+        assert_eq!(reserved, 2 * 101_000);
+        assert_eq!(total_btc_reserved(), Ok(reserved));
+    }
+
+    #[test]
+    fn the_strict_pool_info_tells_unknown_and_empty_tokens_apart() {
+        assert!(matches!(try_get_pool_info("bc1qunknown".to_string()), Err(ExchangeError::InvalidToken)));
+
+        let mut empty = token(PricingMode::FixedRate);
+        empty.addr = "bc1qempty".to_string();
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(empty.addr.clone(), empty.clone()));
+        assert!(matches!(try_get_pool_info(empty.addr.clone()), Err(ExchangeError::EmptyToken)));
+
+        let pool = stored_pool("bc1qpoola");
+        let strict = try_get_pool_info(pool.addr.clone()).unwrap();
+        let lenient = get_pool_info(GetPoolInfoArgs { pool_address: pool.addr.clone() }).unwrap();
+        assert_eq!(
+            (strict.address, strict.nonce, strict.btc_reserved, strict.utxos),
+            (lenient.address, lenient.nonce, lenient.btc_reserved, lenient.utxos)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+// Leveled logging for the ree module, on top of ic_cdk::println
+// Messages more verbose than the configured level are dropped before being formatted,
+// so operators can mute the per-block and per-transaction chatter in production
+
+use crate::state::{MEMORY_MANAGER, Memory};
+use candid::CandidType;
+use ic_stable_structures::{StableCell, memory_manager::MemoryId};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+// Ordered from least to most verbose
+#[derive(Clone, Copy, CandidType, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum LogLevel {
+    Error,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => LogLevel::Error,
+            2 => LogLevel::Debug,
+            _ => LogLevel::Info,
+        }
+    }
+}
+
+thread_local! {
+  // LOG_LEVEL is the most verbose level printed, stored as the LogLevel discriminant
+  static LOG_LEVEL: RefCell<StableCell<u8, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+          LogLevel::default() as u8,
+      )
+      .expect("failed to init LOG_LEVEL"),
+  );
+}
+
+pub fn level() -> LogLevel {
+    LOG_LEVEL.with_borrow(|l| LogLevel::from_u8(*l.get()))
+}
+
+pub fn set_level(level: LogLevel) {
+    LOG_LEVEL.with_borrow_mut(|l| {
+        l.set(level as u8).expect("failed to persist LOG_LEVEL");
+    });
+}
+
+// Prints the message built by `message` if `level` is enabled
+pub fn log(level: LogLevel, message: impl FnOnce() -> String) {
+    if level <= self::level() {
+        emit(format!("[{:?}] {}", level, message()));
+    }
+}
+
+// Where log lines go: the replica's log, or a buffer tests can read back
+#[cfg(not(test))]
+fn emit(line: String) {
+    ic_cdk::println!("{}", line);
+}
+
+#[cfg(test)]
+thread_local! {
+  static CAPTURED: RefCell<Vec<String>> = const { RefCell::new(vec![]) };
+}
+
+#[cfg(test)]
+fn emit(line: String) {
+    CAPTURED.with_borrow_mut(|c| c.push(line));
+}
+
+// Takes the lines logged so far on this thread
+#[cfg(test)]
+pub(crate) fn take_captured() -> Vec<String> {
+    CAPTURED.with_borrow_mut(std::mem::take)
+}
+
+// Failures an operator has to look at
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::ree::log::log($crate::ree::log::LogLevel::Error, || format!($($arg)*))
+    };
+}
+
+// State changes worth keeping in the log: rollbacks, reorgs, resets
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::ree::log::log($crate::ree::log::LogLevel::Info, || format!($($arg)*))
+    };
+}
+
+// Step-by-step tracing of blocks and transactions
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::ree::log::log($crate::ree::log::LogLevel::Debug, || format!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_messages_more_verbose_than_the_level() {
+        take_captured();
+        set_level(LogLevel::Info);
+        error!("failed {}", 1);
+        info!("rolled back {}", 2);
+        debug!("step {}", 3);
+        assert_eq!(take_captured(), vec!["[Error] failed 1", "[Info] rolled back 2"]);
+
+        set_level(LogLevel::Debug);
+        debug!("step {}", 4);
+        assert_eq!(take_captured(), vec!["[Debug] step 4"]);
+
+        set_level(LogLevel::Error);
+        info!("muted");
+        assert!(take_captured().is_empty());
+        assert_eq!(level(), LogLevel::Error);
+    }
+
+    #[test]
+    fn skips_formatting_disabled_messages() {
+        set_level(LogLevel::Error);
+        let mut formatted = false;
+        log(LogLevel::Debug, || {
+            formatted = true;
+            String::new()
+        });
+        assert!(!formatted);
+    }
+}
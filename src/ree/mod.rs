@@ -1,29 +1,51 @@
 pub mod exchange;
+pub mod log;
 pub mod token;
 pub mod token_pool;
 
 // 公开导出主要类型和接口
-pub use self::token::{BuyTokenOffer, SellTokenOffer, CanvasTokenInfo};
-pub use self::token_pool::{CanvasToken, TokenMeta, TokenState};
+pub use self::token::{BuyTokenOffer, SellTokenOffer, CanvasTokenInfo, TokenSeed};
+pub use self::token_pool::{CanvasToken, PriceImpact, PricingMode, Side, TokenMeta, TokenState};
 
+use crate::state::{MEMORY_MANAGER, Memory};
 use candid::CandidType;
-use ic_stable_structures::{
-    DefaultMemoryImpl, StableBTreeMap,
-    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-};
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_stable_structures::{StableBTreeMap, StableCell, Storable, memory_manager::MemoryId, storable::Bound};
 use ree_types::{
     TxRecord, Txid,
+    bitcoin::Network,
     exchange_interfaces::{
         NewBlockInfo,
     },
+    orchestrator_interfaces::{ensure_orchestrator as ensure_mainnet_orchestrator, ensure_testnet4_orchestrator},
 };
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 
+// Default threshold Schnorr key; production subnets hold `key_1`
 pub const SCHNORR_KEY_NAME: &str = "key_1";
 
+// Key names the management canister knows: `test_key_1` on the test key subnet,
+// `dfx_test_key` on a local replica
+pub const SCHNORR_KEY_NAMES: [&str; 3] = ["key_1", "test_key_1", "dfx_test_key"];
+
+// Default smallest BTC amount, in sats, a trade may move; controllers can tune it per deployment
+pub const DEFAULT_MIN_BTC_VALUE: u64 = 10_000;
+
+// Outputs below this many sats are non-standard dust, so MIN_BTC_VALUE may not go lower
+pub const DUST_LIMIT: u64 = 546;
+
+// Blocks a transaction must be buried under before its state is finalized
+pub const DEFAULT_CONFIRMATION_DEPTH: u32 = 6;
+
+// States a token may hold before new commits are refused until blocks finalize some
+pub const DEFAULT_MAX_PENDING_STATES: u64 = 1_000;
+
+// Blocks a finalized txid stays queryable in FINALIZED_TXIDS, about a week of blocks
+pub const DEFAULT_FINALIZED_RETENTION: u32 = 1_008;
+
 #[derive(Debug, Error, CandidType, Clone)]
 pub enum ExchangeError {
     #[error("overflow")]
@@ -44,6 +66,22 @@ pub enum ExchangeError {
     TokenStateExpired(u64),
     #[error("insufficient btc balance for sell")]
     InsufficientBtc,
+    #[error("insufficient token reserve for buy")]
+    InsufficientTokenReserve,
+    #[error("slippage exceeded: output is below the requested minimum")]
+    SlippageExceeded,
+    #[error("the token is paused")]
+    TokenPaused,
+    #[error("the mint would exceed the token's max supply")]
+    SupplyCapExceeded,
+    #[error("the trade exceeds the pool's max trade size")]
+    TradeTooLarge,
+    #[error("stale nonce {0}, the token state has moved on to nonce {1}")]
+    StaleNonce(u64, u64),
+    #[error("this intention of txid {0} has already been executed")]
+    DuplicateTxid(String),
+    #[error("txid {0} has been rolled back, replaying it must be allowed first")]
+    RolledBackTxid(String),
 }
 
 #[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
@@ -53,18 +91,118 @@ pub struct TxRecordInfo {
     pub records: Vec<String>,
 }
 
+// Where a transaction stands as far as this canister knows, with the pools it touched
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub enum TxStatus {
+    Unconfirmed(Vec<String>),
+    Confirmed(Vec<String>),
+    // Already finalized, rolled back, or never seen
+    Unknown,
+}
+
 #[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
 pub struct BlockInfo {
     pub height: u32,
     pub hash: String,
 }
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+// What execute_tx would do with a transaction, as reported by validate_tx
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TxPreview {
+    pub txid: String,
+    pub intentions: Vec<IntentionPreview>, // 本 canister 将执行的各意图，顺序同 intention_set
+}
 
-thread_local! {
-  static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-      RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct IntentionPreview {
+    pub pool_address: String,
+    pub action: String,
+    pub btc_amount: u64,     // 买入支付 / 卖出获得的 BTC（聪）
+    pub token_amount: u128,  // 将铸造 / 销毁的代币数量
+    pub exchange_rate: u64,  // 成交使用的汇率
+    pub new_nonce: u64,      // 执行后池子状态的 nonce
+}
+
+// What new_block did with a block, kept for get_last_block_summary
+#[derive(Eq, PartialEq, CandidType, Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct NewBlockSummary {
+    pub block_height: u32,
+    pub confirmed_count: u64, // 本区块确认的、本 canister 记录过的交易数
+    pub finalized_count: u64, // 本次达到确认深度并最终确定的交易数
+    pub dropped_count: u64,   // 已不在代币状态链中、放弃最终确定的交易数
+    pub pruned_blocks: u64,   // 本次清理掉的旧区块数
+}
+
+// The Bitcoin network pool addresses are derived for
+#[derive(Clone, Copy, CandidType, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum BtcNetwork {
+    Bitcoin,
+    #[default]
+    Testnet4,
+    Regtest,
+}
+
+impl BtcNetwork {
+    pub fn network(&self) -> Network {
+        match self {
+            BtcNetwork::Bitcoin => Network::Bitcoin,
+            BtcNetwork::Testnet4 => Network::Testnet4,
+            BtcNetwork::Regtest => Network::Regtest,
+        }
+    }
+
+    // The network as the bitcoin canister names it; its testnet is testnet4
+    pub fn bitcoin_network(&self) -> BitcoinNetwork {
+        match self {
+            BtcNetwork::Bitcoin => BitcoinNetwork::Mainnet,
+            BtcNetwork::Testnet4 => BitcoinNetwork::Testnet,
+            BtcNetwork::Regtest => BitcoinNetwork::Regtest,
+        }
+    }
+}
+
+impl Storable for BtcNetwork {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![];
+        let _ = ciborium::ser::into_writer(self, &mut bytes);
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode BtcNetwork")
+    }
+}
+
+// One executed trade, as appended to TRADE_EVENTS by execute_tx
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TradeEvent {
+    pub txid: Txid,
+    pub pool: String,
+    pub action: String,          // buy_token 或 sell_token
+    pub btc_amount: u64,         // 买入支付 / 卖出获得的 BTC（聪）
+    pub token_amount: u128,      // 铸造 / 销毁的代币数量
+    pub rate: u64,               // 成交时的汇率
+    pub timestamp: u64,          // 成交时间戳
+    pub rolled_back: bool,       // 交易被回滚后置为 true，客户端不应再展示
+}
+
+impl Storable for TradeEvent {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![];
+        let _ = ciborium::ser::into_writer(self, &mut bytes);
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode TradeEvent")
+    }
+}
 
+thread_local! {
   // CANVAS_TOKENS stores all canvas token configurations
   // It's a mapping from token_address (String) to CanvasToken information
   pub static CANVAS_TOKENS: RefCell<StableBTreeMap<String, token_pool::CanvasToken, Memory>> = RefCell::new(
@@ -90,7 +228,113 @@ thread_local! {
       )
   );
 
-  pub static EXECUTING_TOKENS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+  // CONFIRMATION_DEPTH is the reorg depth new_block waits for before finalizing
+  // Controller-settable so regtest/testnet setups can finalize sooner
+  pub static CONFIRMATION_DEPTH: RefCell<StableCell<u32, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+          DEFAULT_CONFIRMATION_DEPTH,
+      )
+      .expect("failed to init CONFIRMATION_DEPTH"),
+  );
+
+  // TRADE_EVENTS is the append-only log of executed trades
+  // Key: monotonic sequence number, so clients can poll for events after the last one they saw
+  pub static TRADE_EVENTS: RefCell<StableBTreeMap<u64, TradeEvent, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+      )
+  );
+
+  // TRADE_EVENTS_BY_TXID indexes TRADE_EVENTS by the txid of each event: (txid, seq)
+  // Lets rollbacks and per-transaction queries find their events without scanning the log
+  pub static TRADE_EVENTS_BY_TXID: RefCell<StableBTreeMap<(Txid, u64), (), Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+      )
+  );
+
+  // MAX_PENDING_STATES caps the length of each token's state chain
+  // Only finalize prunes the chain, so this bounds memory while confirmations lag behind
+  pub static MAX_PENDING_STATES: RefCell<StableCell<u64, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+          DEFAULT_MAX_PENDING_STATES,
+      )
+      .expect("failed to init MAX_PENDING_STATES"),
+  );
+
+  // MIN_BTC_VALUE is the smallest BTC amount, in sats, a trade may move unless the token sets its own
+  pub static MIN_BTC_VALUE: RefCell<StableCell<u64, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+          DEFAULT_MIN_BTC_VALUE,
+      )
+      .expect("failed to init MIN_BTC_VALUE"),
+  );
+
+  // NETWORK is the Bitcoin network this exchange runs on, Testnet4 unless set by a controller
+  // Fixed once the first token exists, since token addresses are derived for it
+  pub static NETWORK: RefCell<StableCell<BtcNetwork, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+          BtcNetwork::default(),
+      )
+      .expect("failed to init NETWORK"),
+  );
+
+  // SCHNORR_KEY is the threshold key pool addresses are derived from and signed with
+  // Fixed once the first token exists, like NETWORK
+  pub static SCHNORR_KEY: RefCell<StableCell<String, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+          SCHNORR_KEY_NAME.to_string(),
+      )
+      .expect("failed to init SCHNORR_KEY"),
+  );
+
+  // FINALIZED_TXIDS maps each recently finalized txid to the height of the block that confirmed it
+  // Pruned once the height falls FINALIZED_RETENTION blocks behind the finalization threshold
+  pub static FINALIZED_TXIDS: RefCell<StableBTreeMap<Txid, u32, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+      )
+  );
+
+  pub static FINALIZED_RETENTION: RefCell<StableCell<u32, Memory>> = RefCell::new(
+      StableCell::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+          DEFAULT_FINALIZED_RETENTION,
+      )
+      .expect("failed to init FINALIZED_RETENTION"),
+  );
+
+  // ROLLED_BACK_TXIDS maps each rolled back txid to the latest block height known at the time
+  // execute_tx refuses these txids until a controller allows the replay; pruned like FINALIZED_TXIDS
+  pub static ROLLED_BACK_TXIDS: RefCell<StableBTreeMap<Txid, u32, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+      )
+  );
+
+  // EXECUTING_TOKENS holds the (pool, txid) pairs currently inside execute_tx
+  pub static EXECUTING_TOKENS: RefCell<HashSet<(String, Txid)>> = RefCell::new(HashSet::new());
+
+  // EXECUTED_INTENTIONS maps each (txid, intention_index) executed and not yet finalized or
+  // rolled back to the pool it touched, so a re-delivered intention is refused while other
+  // intentions of the same transaction still go through
+  pub static EXECUTED_INTENTIONS: RefCell<StableBTreeMap<(Txid, u32), String, Memory>> = RefCell::new(
+      StableBTreeMap::init(
+          MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+      )
+  );
+
+  // LAST_BLOCK_SUMMARY is the summary of the latest block new_block processed, lost on upgrade
+  pub static LAST_BLOCK_SUMMARY: Cell<Option<NewBlockSummary>> = const { Cell::new(None) };
+
+  // PENDING_PAYOUTS holds the BTC promised to sells that passed validation but are not
+  // committed yet, per (pool, txid); their states only take the BTC out once committed
+  pub static PENDING_PAYOUTS: RefCell<HashMap<(String, Txid), u64>> = RefCell::new(HashMap::new());
 }
 
 // 公开的辅助函数
@@ -98,22 +342,206 @@ pub fn get_canvas_tokens() -> Vec<token_pool::CanvasToken> {
     CANVAS_TOKENS.with_borrow(|p| p.iter().map(|p| p.1.clone()).collect::<Vec<_>>())
 }
 
+// Address and metadata of a page of tokens, in address order
+// The state chains are dropped as each token is read, for callers needing only metadata
+pub fn get_token_metas(offset: usize, limit: usize) -> Vec<(String, token_pool::TokenMeta)> {
+    CANVAS_TOKENS.with_borrow(|p| {
+        p.iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(addr, token)| (addr, token.meta))
+            .collect()
+    })
+}
+
 pub fn get_canvas_token(addr: &String) -> Option<token_pool::CanvasToken> {
     CANVAS_TOKENS.with_borrow(|p| p.get(addr))
 }
 
+pub fn get_max_pending_states() -> u64 {
+    MAX_PENDING_STATES.with_borrow(|m| *m.get())
+}
+
+pub fn get_min_btc_value() -> u64 {
+    MIN_BTC_VALUE.with_borrow(|m| *m.get())
+}
+
+// Canister time in nanoseconds; native unit tests run without the replica clock and read 0
+#[cfg(not(test))]
+pub(crate) fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+pub(crate) fn now() -> u64 {
+    0
+}
+
+pub fn get_network() -> BtcNetwork {
+    NETWORK.with_borrow(|n| *n.get())
+}
+
+pub fn get_schnorr_key_name() -> String {
+    SCHNORR_KEY.with_borrow(|k| k.get().clone())
+}
+
+// Guard for the orchestrator-only endpoints, matching the configured network
+// Regtest has no public orchestrator, so a local one is expected to be a controller
+pub fn ensure_orchestrator() -> Result<(), String> {
+    match get_network() {
+        BtcNetwork::Bitcoin => ensure_mainnet_orchestrator(),
+        BtcNetwork::Testnet4 => ensure_testnet4_orchestrator(),
+        BtcNetwork::Regtest => ic_cdk::api::is_controller(&ic_cdk::api::caller())
+            .then(|| ())
+            .ok_or("Access denied".to_string()),
+    }
+}
+
+// Pool health in the Prometheus text exposition format, served at `/metrics`
+// pending_tx_count counts the transactions not finalized yet, confirmed or not
+pub fn metrics() -> String {
+    render_metrics(ic_cdk::api::stable::stable_size())
+}
+
+// The body of `/metrics`, given the stable memory size, which is only readable in a canister
+fn render_metrics(stable_memory_pages: u64) -> String {
+    use std::fmt::Write;
+
+    let mut out = String::new();
+    let pools: Vec<(String, u64)> = CANVAS_TOKENS.with_borrow(|p| {
+        p.iter()
+            .map(|(addr, token)| {
+                let btc_reserved = token.current_state().btc_balance;
+                (addr, btc_reserved)
+            })
+            .collect()
+    });
+
+    let _ = writeln!(out, "# HELP pool_count Number of canvas token pools");
+    let _ = writeln!(out, "# TYPE pool_count gauge");
+    let _ = writeln!(out, "pool_count {}", pools.len());
+
+    let _ = writeln!(out, "# HELP btc_reserved BTC balance of a pool in satoshis");
+    let _ = writeln!(out, "# TYPE btc_reserved gauge");
+    for (addr, btc_reserved) in pools {
+        let _ = writeln!(out, "btc_reserved{{pool=\"{}\"}} {}", addr, btc_reserved);
+    }
+
+    let _ = writeln!(out, "# HELP pending_tx_count Transactions awaiting finalization");
+    let _ = writeln!(out, "# TYPE pending_tx_count gauge");
+    let _ = writeln!(out, "pending_tx_count {}", TX_RECORDS.with_borrow(|t| t.len()));
+
+    let _ = writeln!(out, "# HELP block_count Blocks kept for finalization");
+    let _ = writeln!(out, "# TYPE block_count gauge");
+    let _ = writeln!(out, "block_count {}", BLOCKS.with_borrow(|b| b.len()));
+
+    let _ = writeln!(out, "# HELP stable_memory_pages Stable memory size in 64KiB pages");
+    let _ = writeln!(out, "# TYPE stable_memory_pages gauge");
+    let _ = writeln!(out, "stable_memory_pages {}", stable_memory_pages);
+
+    out
+}
+
+// Appends a trade to the event log under the next sequence number
+pub(crate) fn record_trade_event(event: TradeEvent) {
+    let txid = event.txid;
+    let seq = TRADE_EVENTS.with_borrow_mut(|e| {
+        let seq = e.last_key_value().map(|(seq, _)| seq + 1).unwrap_or_default();
+        e.insert(seq, event);
+        seq
+    });
+    TRADE_EVENTS_BY_TXID.with_borrow_mut(|i| {
+        i.insert((txid, seq), ());
+    });
+}
+
+// The events of a transaction, in sequence order, looked up through TRADE_EVENTS_BY_TXID
+pub(crate) fn trade_events_of(txid: Txid) -> Vec<(u64, TradeEvent)> {
+    let seqs: Vec<u64> = TRADE_EVENTS_BY_TXID.with_borrow(|i| {
+        i.range((txid, 0)..=(txid, u64::MAX))
+            .map(|((_, seq), _)| seq)
+            .collect()
+    });
+    TRADE_EVENTS.with_borrow(|e| {
+        seqs.into_iter()
+            .filter_map(|seq| e.get(&seq).map(|event| (seq, event)))
+            .collect()
+    })
+}
+
+// Builds TRADE_EVENTS_BY_TXID for events logged before the index existed
+// Runs once after the upgrade that introduces the index; a no-op once it is populated
+pub fn index_trade_events() {
+    if !TRADE_EVENTS_BY_TXID.with_borrow(|i| i.is_empty()) {
+        return;
+    }
+    TRADE_EVENTS.with_borrow(|e| {
+        TRADE_EVENTS_BY_TXID.with_borrow_mut(|i| {
+            for (seq, event) in e.iter() {
+                i.insert((event.txid, seq), ());
+            }
+        });
+    });
+}
+
+// Flags the trades of a rolled-back transaction so clients stop showing them
+// Events are kept rather than removed so sequence numbers stay stable for pollers
+pub(crate) fn mark_trade_events_rolled_back(txid: Txid) {
+    let affected: Vec<_> = trade_events_of(txid)
+        .into_iter()
+        .filter(|(_, event)| !event.rolled_back)
+        .collect();
+    TRADE_EVENTS.with_borrow_mut(|e| {
+        for (seq, mut event) in affected {
+            event.rolled_back = true;
+            e.insert(seq, event);
+        }
+    });
+}
+
+// Drops the executed intentions of a transaction once it is finalized or rolled back
+pub(crate) fn forget_executed_intentions(txid: Txid) {
+    EXECUTED_INTENTIONS.with_borrow_mut(|e| {
+        let keys: Vec<(Txid, u32)> = e
+            .range((txid, 0)..=(txid, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            e.remove(&key);
+        }
+    });
+}
+
+// Whether any transaction is currently executing against the token
+pub fn is_executing(token_address: &String) -> bool {
+    EXECUTING_TOKENS.with_borrow(|e| e.iter().any(|(pool, _)| pool == token_address))
+}
+
+// BTC the token has promised to sells still being signed, on top of its latest state
+pub fn pending_payouts(token_address: &String) -> u64 {
+    PENDING_PAYOUTS.with_borrow(|p| {
+        p.iter()
+            .filter(|((pool, _), _)| pool == token_address)
+            .fold(0u64, |total, (_, amount)| total.saturating_add(*amount))
+    })
+}
+
 // 公开的守护结构
+// Locks a (pool, txid) pair: different transactions may run against the same pool
+// concurrently, the same transaction may not. Concurrent transactions are kept
+// consistent by execute_tx re-checking the pool nonce when committing
 #[must_use]
-pub struct ExecuteTxGuard(String);
+pub struct ExecuteTxGuard((String, Txid));
 
 impl ExecuteTxGuard {
-    pub fn new(token_address: String) -> Option<Self> {
+    pub fn new(token_address: String, txid: Txid) -> Option<Self> {
         EXECUTING_TOKENS.with(|executing_tokens| {
-            if executing_tokens.borrow().contains(&token_address) {
+            let key = (token_address, txid);
+            if executing_tokens.borrow().contains(&key) {
                 return None;
             }
-            executing_tokens.borrow_mut().insert(token_address.clone());
-            return Some(ExecuteTxGuard(token_address));
+            executing_tokens.borrow_mut().insert(key.clone());
+            return Some(ExecuteTxGuard(key));
         })
     }
 }
@@ -124,4 +552,125 @@ impl Drop for ExecuteTxGuard {
             executing_tokens.remove(&self.0);
         });
     }
-}
\ No newline at end of file
+}
+
+// Reserves a sell's payout in PENDING_PAYOUTS until the sell is committed or abandoned
+#[must_use]
+pub struct PayoutGuard((String, Txid));
+
+impl PayoutGuard {
+    pub fn new(token_address: String, txid: Txid, btc_amount: u64) -> Self {
+        let key = (token_address, txid);
+        PENDING_PAYOUTS.with_borrow_mut(|p| {
+            p.insert(key.clone(), btc_amount);
+        });
+        PayoutGuard(key)
+    }
+}
+
+impl Drop for PayoutGuard {
+    fn drop(&mut self) {
+        PENDING_PAYOUTS.with_borrow_mut(|p| {
+            p.remove(&self.0);
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use super::token_pool::tests::txid;
+
+    pub(crate) fn trade(txid: Txid) -> TradeEvent {
+        TradeEvent {
+            txid,
+            pool: "pool".to_string(),
+            action: "buy_token".to_string(),
+            btc_amount: 1_000,
+            token_amount: 10_000,
+            rate: 10,
+            timestamp: 0,
+            rolled_back: false,
+        }
+    }
+
+    #[test]
+    fn rollback_flags_only_the_trades_of_its_txid() {
+        for n in [1, 2, 1] {
+            record_trade_event(trade(txid(n)));
+        }
+        let seqs = |n| trade_events_of(txid(n)).into_iter().map(|(seq, _)| seq).collect::<Vec<_>>();
+        assert_eq!((seqs(1), seqs(2)), (vec![0, 2], vec![1]));
+
+        mark_trade_events_rolled_back(txid(1));
+        let flags: Vec<bool> = TRADE_EVENTS.with_borrow(|e| e.iter().map(|(_, event)| event.rolled_back).collect());
+        assert_eq!(flags, vec![true, false, true]);
+    }
+
+    // Whether a sample line reads `name{labels} value`, the labels being optional
+    fn is_sample(line: &str) -> bool {
+        let Some((series, value)) = line.rsplit_once(' ') else {
+            return false;
+        };
+        let (name, labels) = match series.split_once('{') {
+            Some((name, labels)) => match labels.strip_suffix('}') {
+                Some(labels) => (name, Some(labels)),
+                None => return false,
+            },
+            None => (series, None),
+        };
+        let is_name = |s: &str| {
+            s.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        let is_label = |pair: &str| {
+            pair.split_once('=').is_some_and(|(key, value)| {
+                is_name(key) && value.len() >= 2 && value.starts_with('"') && value.ends_with('"')
+            })
+        };
+        is_name(name) && labels.into_iter().all(|l| l.split(',').all(is_label)) && value.parse::<f64>().is_ok()
+    }
+
+    #[test]
+    fn every_metrics_sample_is_a_name_labels_and_value() {
+        let pool = token_pool::tests::funded(token_pool::PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool));
+        let out = render_metrics(3);
+
+        let samples: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(samples.len(), 5);
+        for line in &samples {
+            assert!(is_sample(line), "malformed sample: {}", line);
+        }
+        assert!(samples.contains(&"btc_reserved{pool=\"pool\"} 100000"));
+        assert!(samples.contains(&"stable_memory_pages 3"));
+        assert!(!is_sample("btc_reserved{pool=pool} 1") && !is_sample("pool count 1"));
+    }
+
+    #[test]
+    fn index_backfills_events_logged_before_it_existed() {
+        TRADE_EVENTS.with_borrow_mut(|e| {
+            e.insert(0, trade(txid(1)));
+            e.insert(1, trade(txid(2)));
+        });
+        assert!(trade_events_of(txid(2)).is_empty());
+        index_trade_events();
+        assert_eq!(trade_events_of(txid(2)), vec![(1, trade(txid(2)))]);
+    }
+
+    #[test]
+    fn the_guard_locks_a_txid_not_the_whole_pool() {
+        let pool = "pool".to_string();
+        let first = ExecuteTxGuard::new(pool.clone(), txid(1)).unwrap();
+        let second = ExecuteTxGuard::new(pool.clone(), txid(2)).unwrap();
+        assert!(ExecuteTxGuard::new(pool.clone(), txid(1)).is_none());
+        assert!(ExecuteTxGuard::new("other".to_string(), txid(1)).is_some());
+        assert!(is_executing(&pool));
+
+        drop(first);
+        assert!(is_executing(&pool));
+        let again = ExecuteTxGuard::new(pool.clone(), txid(1)).unwrap();
+        drop((again, second));
+        assert!(!is_executing(&pool));
+    }
+}
@@ -1,14 +1,30 @@
-use super::{ExchangeError, token_pool::TokenMeta};
+use super::{ExchangeError, token_pool::{PricingMode, TokenMeta}};
 use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::bitcoin::{
+    GetUtxosRequest, Utxo as BtcUtxo, UtxoFilter, bitcoin_get_utxos,
+};
 use ic_cdk_macros::{query, update};
-use ree_types::{CoinId, Txid, bitcoin::Network, schnorr::request_ree_pool_address};
+use ree_types::{
+    CoinBalance, CoinId, TxRecord, Txid, Utxo,
+    bitcoin::{
+        Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+        absolute::LockTime, address::NetworkUnchecked, psbt::Psbt, transaction::Version,
+    },
+    schnorr::{ree_pool_sign, request_ree_pool_address},
+};
 use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
 #[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
 pub struct BuyTokenOffer {
     pub nonce: u64,
     pub token_amount: u128,        
     pub current_btc_balance: u64,  
+    pub btc_amount: u64,           // 需支付的 BTC（含手续费）
+    pub remaining_supply: Option<u128>, // 距供应上限还可铸造的代币数量，None 表示不设上限
+    pub exchange_rate: u64,        // 报价使用的汇率
+    pub fee: u64,                  // 从支付的 BTC 中扣除的手续费
 }
 
 #[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
@@ -16,6 +32,9 @@ pub struct SellTokenOffer {
     pub nonce: u64,
     pub btc_amount: u64,           
     pub current_btc_balance: u64, 
+    pub token_remainder: u128,     // 因向下取整而换不到 BTC 的代币数量
+    pub exchange_rate: u64,        // 报价使用的汇率
+    pub fee: u64,                  // 从获得的 BTC 中扣除的手续费
 }
 
 #[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
@@ -24,6 +43,20 @@ pub struct CanvasTokenInfo {
     pub symbol: String,
     pub exchange_rate: u64,
     pub token_id: CoinId,
+    pub decimals: u8,
+}
+
+// 创建代币时预置的初始状态，使池子创建后即可交易
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TokenSeed {
+    pub utxos: Vec<Utxo>,     // 已转入池子地址的 UTXO，btc_balance 为其 sats 之和
+    pub token_reserve: u128,  // 初始代币储备，仅 ConstantProduct 模式使用且必须大于 0
+}
+
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct PoolStats {
+    pub cumulative_btc_volume: u64, // 累计成交的 BTC 数量（聪），不含已回滚的交易
+    pub trade_count: u64,           // 累计成交笔数
 }
 
 #[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
@@ -35,61 +68,166 @@ pub struct ExchangeRateInfo {
     pub nonce: u64,              // 状态版本号
 }
 
-// 
+#[derive(Eq, PartialEq, CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TokenStateView {
+    pub txid: Option<String>,    // 产生该状态的交易ID，管理操作产生的状态为空
+    pub nonce: u64,              // 状态版本号
+    pub btc_balance: u64,        // BTC余额
+    pub exchange_rate: Option<u64>, // 汇率（价格）
+    pub timestamp: u64,          // 时间戳
+}
+
+// Upper bound on the events returned by one get_trade_events call
+pub const MAX_TRADE_EVENTS_PAGE: u64 = 100;
+
+// Upper bound on the states returned by one get_token_states call
+pub const MAX_TOKEN_STATES_PAGE: u64 = 100;
+
 #[query]
+// `expected_nonce`, when given, is the nonce the caller is about to build its tx against,
+// see CanvasToken::check_expected_nonce
 pub fn pre_buy_token(
     token_address: String,
     btc_amount: u64,
+    min_token_out: Option<u128>,
+    expected_nonce: Option<u64>,
 ) -> Result<BuyTokenOffer, ExchangeError> {
-    if btc_amount < super::token_pool::MIN_BTC_VALUE {
-        return Err(ExchangeError::TooSmallFunds);
+    let token = super::get_canvas_token(&token_address).ok_or(ExchangeError::InvalidToken)?;
+    (!token.paused).then(|| ()).ok_or(ExchangeError::TokenPaused)?;
+    token.check_expected_nonce(expected_nonce)?;
+    
+    let offer = buy_offer(&token, btc_amount)?;
+    
+    if offer.token_amount < min_token_out.unwrap_or_default() {
+        return Err(ExchangeError::SlippageExceeded);
     }
     
+    Ok(offer)
+}
+
+#[query]
+// pre_buy_token_exact_out quotes the BTC needed to get at least `token_amount` tokens
+// The BTC is rounded up, so the offer's token_amount may slightly exceed the request
+pub fn pre_buy_token_exact_out(
+    token_address: String,
+    token_amount: u128,
+    expected_nonce: Option<u64>,
+) -> Result<BuyTokenOffer, ExchangeError> {
     let token = super::get_canvas_token(&token_address).ok_or(ExchangeError::InvalidToken)?;
-    let state = token.states.last().cloned().unwrap_or_default();
+    (!token.paused).then(|| ()).ok_or(ExchangeError::TokenPaused)?;
+    token.check_expected_nonce(expected_nonce)?;
+    
+    let btc_amount = token.calculate_buy_cost(token_amount)?;
+    buy_offer(&token, btc_amount)
+}
+
+// Quotes a buy of `btc_amount` against the token's latest state
+fn buy_offer(
+    token: &super::CanvasToken,
+    btc_amount: u64,
+) -> Result<BuyTokenOffer, ExchangeError> {
+    if btc_amount < token.min_tx_value() {
+        return Err(ExchangeError::TooSmallFunds);
+    }
     
-    let token_amount = token.calculate_buy_amount(btc_amount);
+    let state = token.current_state();
     
+    let token_amount = token.calculate_buy_amount(btc_amount)?;
+    let remaining_supply = token.remaining_supply();
+    
+    if remaining_supply.is_some_and(|remaining| token_amount > remaining) {
+        return Err(ExchangeError::SupplyCapExceeded);
+    }
+    
+    // The returned nonce is the one the tx must be built against
     Ok(BuyTokenOffer {
         nonce: state.nonce,
         token_amount,
         current_btc_balance: state.btc_balance,
+        btc_amount,
+        remaining_supply,
+        exchange_rate: token.get_current_exchange_rate(),
+        fee: token.calculate_fee(btc_amount),
     })
 }
 
+#[query]
+// estimate_price_impact previews how a trade would move the price; `amount` is the
+// satoshis paid for a buy or the tokens paid for a sell
+pub fn estimate_price_impact(
+    token_address: String,
+    side: super::Side,
+    amount: u128,
+) -> Result<super::PriceImpact, ExchangeError> {
+    let token = super::get_canvas_token(&token_address).ok_or(ExchangeError::InvalidToken)?;
+    token.estimate_price_impact(side, amount)
+}
+
 #[query]
 pub fn pre_sell_token(
     token_address: String, 
-    token_amount: u128
+    token_amount: u128,
+    min_btc_out: Option<u64>,
+    expected_nonce: Option<u64>,
 ) -> Result<SellTokenOffer, ExchangeError> {
     let token = super::get_canvas_token(&token_address).ok_or(ExchangeError::InvalidToken)?;
-    let state = token.states.last().ok_or(ExchangeError::EmptyToken)?;
+    (!token.paused).then(|| ()).ok_or(ExchangeError::TokenPaused)?;
+    token.check_expected_nonce(expected_nonce)?;
+    let state = token.current_state();
     
-    let btc_amount = token.calculate_sell_amount(token_amount);
+    // Quote with the rate execute_tx will validate against and run the checks of
+    // validate_sell_token in the same order, so a quote it would reject is rejected here
+    // with the same error
+    let exchange_rate = token.get_current_exchange_rate();
+    let (btc_amount, fee) = token
+        .quote_sell(&state, token_amount, exchange_rate)
+        .ok_or(ExchangeError::Overflow)?;
     
-    if btc_amount < super::token_pool::MIN_BTC_VALUE {
+    if btc_amount < token.min_tx_value() {
         return Err(ExchangeError::TooSmallFunds);
     }
     
-    if state.btc_balance < btc_amount {
-        return Err(ExchangeError::InsufficientBtc);
+    if btc_amount < min_btc_out.unwrap_or_default() {
+        return Err(ExchangeError::SlippageExceeded);
     }
     
+    state
+        .btc_reserve()
+        .saturating_sub(super::pending_payouts(&token_address))
+        .checked_sub(btc_amount)
+        .ok_or(ExchangeError::InsufficientBtc)?;
+    
+    token.check_trade_size(&state, btc_amount)?;
+    
+    // The offer only holds at this nonce: the tx must be built against it
     Ok(SellTokenOffer {
         nonce: state.nonce,
         btc_amount,
         current_btc_balance: state.btc_balance,
+        token_remainder: token.sell_remainder(token_amount),
+        exchange_rate,
+        fee,
     })
 }
 
 #[update]
-// init_canvas_token creates a new canvas token with fixed exchange rate
+// init_canvas_token creates a new canvas token, priced at a fixed exchange rate unless
+// `pricing_mode` selects the constant-product AMM
 // This allows users to mint tokens by sending BTC and burn tokens to get BTC back
+// `max_supply` optionally caps how many tokens can ever be outstanding
+// `seed` optionally starts the pool with a BTC reserve (and a token reserve for the AMM) held
+// by UTXOs already sent to the pool address; without it the pool has no state until its first
+// buy or add_liquidity
 pub async fn init_canvas_token(
     block: u64,
     tx: u64,
     symbol: String,
     exchange_rate: u64,
+    pricing_mode: Option<PricingMode>,
+    fee_bps: Option<u16>,
+    max_supply: Option<u128>,
+    decimals: Option<u8>,
+    seed: Option<TokenSeed>,
 ) -> Result<CanvasTokenInfo, String> {
     let caller = ic_cdk::api::caller();
     if !ic_cdk::api::is_controller(&caller) {
@@ -100,27 +238,60 @@ pub async fn init_canvas_token(
         return Err("Exchange rate must be greater than 0".to_string());
     }
 
+    let fee_bps = fee_bps.unwrap_or_default();
+    if fee_bps > 10_000 {
+        return Err("Fee must not exceed 10000 bps".to_string());
+    }
+
+    // exchange_rate is in whole tokens, scaled by 10^decimals which must fit in a u128
+    let decimals = decimals.unwrap_or_default();
+    if decimals > 38 {
+        return Err("Decimals must not exceed 38".to_string());
+    }
+
+    let pricing_mode = pricing_mode.unwrap_or_default();
+
     let id = CoinId::rune(block, tx as u32);
     let meta = TokenMeta {
         id,
         symbol: symbol.clone(),
         exchange_rate,
         min_amount: 1,
+        pricing_mode,
+        fee_bps,
+        max_supply,
+        decimals,
+        max_trade_fraction_bps: None,
+        min_tx_value: None,
     };
 
     let (untweaked, tweaked, addr) = request_ree_pool_address(
-        super::SCHNORR_KEY_NAME,
+        &super::get_schnorr_key_name(),
         vec![id.to_string().as_bytes().to_vec()],
-        Network::Testnet4,
+        super::get_network().network(),
     )
     .await?;
 
+    // The seed UTXOs have to be locked by the pool address, which is only known from here on
+    let initial_state = match seed {
+        Some(seed) => {
+            let state = seed_state(seed, pricing_mode, exchange_rate)?;
+            let pool_utxos = pool_address_utxos(&addr.to_string()).await?;
+            check_seed_utxos(&state.utxos, &pool_utxos)?;
+            Some(state)
+        }
+        None => None,
+    };
+
     let canvas_token = super::token_pool::CanvasToken {
         meta: meta.clone(),
         pubkey: untweaked.clone(),
         tweaked,
         addr: addr.to_string(),
-        states: vec![],
+        states: initial_state.into_iter().collect(),
+        paused: false,
+        state_index: Default::default(),
+        operator_rate: None,
     };
     
     super::CANVAS_TOKENS.with_borrow_mut(|p| {
@@ -132,9 +303,646 @@ pub async fn init_canvas_token(
         symbol,
         exchange_rate,
         token_id: id,
+        decimals,
+    })
+}
+
+// Validates a seed and turns it into the token's first state
+fn seed_state(
+    seed: TokenSeed,
+    pricing_mode: PricingMode,
+    exchange_rate: u64,
+) -> Result<super::TokenState, String> {
+    if seed.utxos.is_empty() {
+        return Err("Seed must hold at least one UTXO".to_string());
+    }
+    let btc_balance = seed
+        .utxos
+        .iter()
+        .try_fold(0u64, |acc, u| acc.checked_add(u.sats))
+        .ok_or("Seed UTXOs overflow".to_string())?;
+    if btc_balance < super::get_min_btc_value() {
+        return Err("Seed BTC balance is below the minimal transaction value".to_string());
+    }
+    match pricing_mode {
+        PricingMode::ConstantProduct if seed.token_reserve == 0 => {
+            return Err("Constant-product pools must be seeded with a token reserve".to_string());
+        }
+        PricingMode::FixedRate if seed.token_reserve != 0 => {
+            return Err("Fixed-rate pools hold no token reserve".to_string());
+        }
+        _ => {}
+    }
+
+    Ok(super::TokenState {
+        btc_balance,
+        exchange_rate: Some(exchange_rate),
+        timestamp: ic_cdk::api::time(),
+        utxos: seed.utxos,
+        token_reserve: seed.token_reserve,
+        ..Default::default()
+    })
+}
+
+// The unspent outputs the bitcoin canister knows for `address`, i.e. those locked by its script_pubkey
+async fn pool_address_utxos(address: &str) -> Result<Vec<BtcUtxo>, String> {
+    let mut utxos = vec![];
+    let mut page = None;
+    loop {
+        let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+            address: address.to_string(),
+            network: super::get_network().bitcoin_network(),
+            filter: page.map(UtxoFilter::Page),
+        })
+        .await
+        .map_err(|(code, message)| format!("bitcoin_get_utxos failed: {:?} {}", code, message))?;
+        utxos.extend(response.utxos);
+        page = response.next_page;
+        if page.is_none() {
+            return Ok(utxos);
+        }
+    }
+}
+
+// Refuses seed UTXOs that are not among the pool address's unspent outputs, or misstate their sats:
+// the pool could never spend them as recorded, so its btc_balance would be unbacked
+fn check_seed_utxos(seed_utxos: &[Utxo], pool_utxos: &[BtcUtxo]) -> Result<(), String> {
+    for utxo in seed_utxos {
+        // The bitcoin canister returns txids in internal byte order, the reverse of the displayed one
+        let held = pool_utxos.iter().any(|u| {
+            let txid: Vec<u8> = u.outpoint.txid.iter().rev().copied().collect();
+            hex::encode(txid) == utxo.txid.to_string() && u.outpoint.vout == utxo.vout && u.value == utxo.sats
+        });
+        if !held {
+            return Err(format!(
+                "Seed UTXO {}:{} is not held by the pool address",
+                utxo.txid, utxo.vout
+            ));
+        }
+    }
+    Ok(())
+}
+
+
+#[update]
+// remove_canvas_token deletes a deprecated token together with all its per-pool state
+// Refused while an execute_tx for the token is in flight
+pub fn remove_canvas_token(token_address: String) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if super::is_executing(&token_address) {
+        return Err(format!("Token {0} Executing", token_address));
+    }
+
+    super::CANVAS_TOKENS
+        .with_borrow_mut(|p| p.remove(&token_address))
+        .ok_or("Token not found".to_string())?;
+
+    purge_pool_state(&token_address);
+
+    Ok(())
+}
+
+#[update]
+// set_token_paused is the per-token kill switch: while paused, quotes and trades are rejected
+// but the token state is kept, so it can be resumed as is
+pub fn set_token_paused(token_address: String, paused: bool) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    super::CANVAS_TOKENS.with_borrow_mut(|p| {
+        let mut token = p.get(&token_address).ok_or("Token not found".to_string())?;
+        token.paused = paused;
+        p.insert(token_address, token);
+        Ok(())
+    })
+}
+
+// Network fee, at `fee_rate` sats per vbyte, of a transaction spending `inputs` taproot
+// key-path inputs into `outputs` outputs: about 11 vbytes of header, 58 per input and 43 per output
+fn withdraw_fee(fee_rate: u64, inputs: usize, outputs: usize) -> Result<u64, String> {
+    (fee_rate > 0)
+        .then(|| ())
+        .ok_or("Fee rate must be greater than 0".to_string())?;
+    fee_rate
+        .checked_mul(11 + 58 * inputs as u64 + 43 * outputs as u64)
+        .ok_or(ExchangeError::Overflow.to_string())
+}
+
+fn parse_address(address: &str) -> Result<Address, String> {
+    Address::<NetworkUnchecked>::from_str(address)
+        .map_err(|_| format!("invalid address {}", address))?
+        .require_network(super::get_network().network())
+        .map_err(|_| format!("address {} is for another network", address))
+}
+
+// Builds a transaction spending every one of `utxos` into `outputs` and signs the pool inputs
+async fn sign_pool_withdrawal(
+    token: &super::CanvasToken,
+    utxos: &[Utxo],
+    outputs: Vec<TxOut>,
+) -> Result<Psbt, String> {
+    let pool_script = parse_address(&token.addr)?.script_pubkey();
+    let input = utxos
+        .iter()
+        .map(|u| {
+            Ok(TxIn {
+                previous_output: OutPoint::from_str(&format!("{}:{}", u.txid, u.vout))
+                    .map_err(|_| "invalid pool utxo".to_string())?,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input,
+        output: outputs,
+    };
+    let mut psbt = Psbt::from_unsigned_tx(tx).map_err(|_| "failed to build psbt".to_string())?;
+    for (input, utxo) in psbt.inputs.iter_mut().zip(utxos.iter()) {
+        input.witness_utxo = Some(TxOut {
+            value: Amount::from_sat(utxo.sats),
+            script_pubkey: pool_script.clone(),
+        });
+    }
+
+    ree_pool_sign(
+        &mut psbt,
+        utxos.iter().collect(),
+        &super::get_schnorr_key_name(),
+        token.derivation_path(),
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(psbt)
+}
+
+// Refuses to withdraw while a transaction of the pool is executing or unconfirmed
+// A pending transaction may still be rolled back, taking the UTXOs a withdrawal spends with it
+fn ensure_settled(token: &super::CanvasToken, token_address: &String) -> Result<(), String> {
+    if super::is_executing(token_address) || token.states.iter().skip(1).any(|s| s.id.is_some()) {
+        return Err("Token has unconfirmed transactions, retry once they are finalized".to_string());
+    }
+    Ok(())
+}
+
+// Appends the state left by a withdrawal signed against the state with nonce `nonce`
+// The pool may have moved on while signing (e.g. a rollback), in which case the signed
+// transaction no longer spends the current UTXOs
+// The withdrawal is recorded like an executed transaction, so new_block finalizes its state
+// and rollback_tx can undo it
+fn commit_withdrawal(
+    token_address: String,
+    nonce: u64,
+    psbt: &Psbt,
+    mut new_state: super::TokenState,
+) -> Result<(), String> {
+    let txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string())
+        .map_err(|_| "invalid withdrawal txid".to_string())?;
+    new_state.id = Some(txid);
+    super::CANVAS_TOKENS.with_borrow_mut(|p| {
+        let mut token = p.get(&token_address).ok_or("Token not found".to_string())?;
+        let current_nonce = token.current_state().nonce;
+        if current_nonce != nonce {
+            return Err(ExchangeError::TokenStateExpired(current_nonce).to_string());
+        }
+        token.commit(new_state).map_err(|e| e.to_string())?;
+        p.insert(token_address.clone(), token);
+        Ok::<(), String>(())
+    })?;
+    super::TX_RECORDS.with_borrow_mut(|m| {
+        super::log::info!("withdrawal {} from token {}", txid, token_address);
+        m.insert((txid, false), TxRecord { pools: vec![token_address] });
+    });
+    Ok(())
+}
+
+#[update]
+// emergency_withdraw winds a paused, settled pool down: it builds a transaction spending every
+// pool UTXO to `to_address`, signs the pool inputs and appends a state with an empty BTC reserve
+// `fee_rate` is the network fee rate to pay, in sats per vbyte
+// Returns the signed PSBT hex; the caller finalizes and broadcasts it
+pub async fn emergency_withdraw(token_address: String, to_address: String, fee_rate: u64) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    let token = super::get_canvas_token(&token_address).ok_or("Token not found".to_string())?;
+    if !token.paused {
+        return Err("Token must be paused before withdrawing".to_string());
+    }
+    let state = token.current_state();
+    if state.utxos.is_empty() {
+        return Err("Nothing to withdraw".to_string());
+    }
+    ensure_settled(&token, &token_address)?;
+
+    let to = parse_address(&to_address)?;
+    let total: u64 = state.utxos.iter().map(|u| u.sats).sum();
+    let payout = total
+        .checked_sub(withdraw_fee(fee_rate, state.utxos.len(), 1)?)
+        .filter(|v| *v >= super::get_min_btc_value())
+        .ok_or("Reserve too small to cover the withdrawal fee".to_string())?;
+
+    let psbt = sign_pool_withdrawal(
+        &token,
+        &state.utxos,
+        vec![TxOut {
+            value: Amount::from_sat(payout),
+            script_pubkey: to.script_pubkey(),
+        }],
+    )
+    .await?;
+
+    let new_state = withdrawn_state(&state, ic_cdk::api::time());
+    commit_withdrawal(token_address, state.nonce, &psbt, new_state)?;
+
+    Ok(psbt.serialize_hex())
+}
+
+// The state an emergency withdrawal leaves behind: every UTXO is spent, so the BTC reserve and
+// the fees it held are gone, while the token balances carry over
+fn withdrawn_state(state: &super::TokenState, now: u64) -> super::TokenState {
+    let mut new_state = state.clone();
+    new_state.nonce += 1;
+    new_state.id = None;
+    new_state.btc_balance = 0;
+    new_state.fees_accrued = 0;
+    new_state.utxos = vec![];
+    new_state.timestamp = now;
+    new_state
+}
+
+#[update]
+// collect_fees pays the fees a pool has accrued out to `to_address`, minus the network fee
+// Everything else goes back to the pool as a single change output, so the reserve backing
+// sells is untouched and the accumulator restarts from zero
+// `fee_rate` is the network fee rate to pay, in sats per vbyte
+// Returns the signed PSBT hex; the caller finalizes and broadcasts it
+pub async fn collect_fees(token_address: String, to_address: String, fee_rate: u64) -> Result<String, String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    let token = super::get_canvas_token(&token_address).ok_or("Token not found".to_string())?;
+    let state = token.current_state();
+    if state.fees_accrued == 0 {
+        return Err("No fees to collect".to_string());
+    }
+    ensure_settled(&token, &token_address)?;
+
+    let to = parse_address(&to_address)?;
+    let pool_script = parse_address(&token.addr)?.script_pubkey();
+    let total: u64 = state.utxos.iter().map(|u| u.sats).sum();
+    let change = total
+        .checked_sub(state.fees_accrued)
+        .ok_or("Pool UTXOs do not cover the accrued fees".to_string())?;
+    let output_count = if change > 0 { 2 } else { 1 };
+    let payout = state
+        .fees_accrued
+        .checked_sub(withdraw_fee(fee_rate, state.utxos.len(), output_count)?)
+        .filter(|v| *v >= super::get_min_btc_value())
+        .ok_or("Accrued fees too small to cover the withdrawal fee".to_string())?;
+
+    // The change comes first, so runes held by the pool UTXOs stay with the pool
+    let mut outputs = vec![];
+    if change > 0 {
+        outputs.push(TxOut {
+            value: Amount::from_sat(change),
+            script_pubkey: pool_script,
+        });
+    }
+    outputs.push(TxOut {
+        value: Amount::from_sat(payout),
+        script_pubkey: to.script_pubkey(),
+    });
+    let psbt = sign_pool_withdrawal(&token, &state.utxos, outputs).await?;
+
+    let txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string())
+        .map_err(|_| "invalid withdrawal txid".to_string())?;
+    let new_state = fees_collected_state(&state, txid, change, ic_cdk::api::time());
+    commit_withdrawal(token_address, state.nonce, &psbt, new_state)?;
+
+    Ok(psbt.serialize_hex())
+}
+
+// The state a fee collection leaves behind: the fees leave the reserve and the accumulator
+// restarts from zero; what is left of the pool UTXOs, `change` sats, comes back as output 0
+// of `txid`, carrying every rune the spent UTXOs held
+fn fees_collected_state(state: &super::TokenState, txid: Txid, change: u64, now: u64) -> super::TokenState {
+    let mut new_state = state.clone();
+    new_state.nonce += 1;
+    new_state.id = None;
+    new_state.btc_balance = state.btc_balance.saturating_sub(state.fees_accrued);
+    new_state.fees_accrued = 0;
+    new_state.utxos = vec![];
+    if change > 0 {
+        let mut coins: Vec<CoinBalance> = vec![];
+        for coin in state.utxos.iter().flat_map(|u| u.coins.iter()) {
+            match coins.iter_mut().find(|c| c.id == coin.id) {
+                Some(c) => c.value += coin.value,
+                None => coins.push(*coin),
+            }
+        }
+        new_state.utxos.push(Utxo {
+            txid,
+            vout: 0,
+            coins,
+            sats: change,
+        });
+    }
+    new_state.timestamp = now;
+    new_state
+}
+
+#[update]
+// update_exchange_rate changes the rate of a token by appending a new state, so earlier
+// states are kept for rollback and quotes made at the old rate go stale
+// The rate itself is also kept on the token, so rolling back a transaction made before
+// the update, which drops the appended state, does not revert it
+pub fn update_exchange_rate(token_address: String, new_rate: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if new_rate == 0 {
+        return Err("Exchange rate must be greater than 0".to_string());
+    }
+
+    if super::is_executing(&token_address) {
+        return Err(format!("Token {0} Executing", token_address));
+    }
+
+    super::CANVAS_TOKENS.with_borrow_mut(|p| {
+        let mut token = p.get(&token_address).ok_or("Token not found".to_string())?;
+        let state = token.rate_update_state(new_rate);
+        token.commit(state).map_err(|e| e.to_string())?;
+        token.operator_rate = Some(new_rate);
+        p.insert(token_address, token);
+        Ok(())
+    })
+}
+
+#[update]
+// set_max_trade_fraction sets the circuit breaker of a token: the largest share of its
+// BTC reserve (fees excluded), in basis points, a single sell may pay out
+// 10000 turns the breaker off, e.g. for fixed-rate pools
+pub fn set_max_trade_fraction(token_address: String, max_trade_fraction_bps: u16) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if max_trade_fraction_bps > 10_000 {
+        return Err("Max trade fraction must not exceed 10000 bps".to_string());
+    }
+
+    super::CANVAS_TOKENS.with_borrow_mut(|p| {
+        let mut token = p.get(&token_address).ok_or("Token not found".to_string())?;
+        token.meta.max_trade_fraction_bps = Some(max_trade_fraction_bps);
+        p.insert(token_address, token);
+        Ok(())
     })
 }
 
+#[update]
+// update_token_meta renames a token or changes its min_amount; only the metadata changes,
+// the state chain and the pool address stay as they are
+pub fn update_token_meta(
+    token_address: String,
+    symbol: Option<String>,
+    min_amount: Option<u128>,
+) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if symbol.as_ref().is_some_and(|s| s.trim().is_empty()) {
+        return Err("Symbol must not be empty".to_string());
+    }
+
+    super::CANVAS_TOKENS.with_borrow_mut(|p| {
+        let mut token = p.get(&token_address).ok_or("Token not found".to_string())?;
+        if let Some(symbol) = symbol {
+            token.meta.symbol = symbol;
+        }
+        if let Some(min_amount) = min_amount {
+            token.meta.min_amount = min_amount;
+        }
+        p.insert(token_address, token);
+        Ok(())
+    })
+}
+
+#[update]
+// set_min_tx_value sets the smallest BTC amount, in sats, a single trade with the token may move
+// None falls back to the global MIN_BTC_VALUE, see set_min_btc_value
+pub fn set_min_tx_value(token_address: String, min_tx_value: Option<u64>) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    super::CANVAS_TOKENS.with_borrow_mut(|p| {
+        let mut token = p.get(&token_address).ok_or("Token not found".to_string())?;
+        token.meta.min_tx_value = min_tx_value;
+        p.insert(token_address, token);
+        Ok(())
+    })
+}
+
+#[update]
+pub fn set_confirmation_depth(depth: u32) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if depth == 0 {
+        return Err("Confirmation depth must be at least 1".to_string());
+    }
+
+    super::CONFIRMATION_DEPTH.with_borrow_mut(|d| {
+        d.set(depth).expect("failed to persist CONFIRMATION_DEPTH");
+    });
+    Ok(())
+}
+
+#[update]
+// set_finalized_retention sets how many blocks get_finalizing_height remembers a finalized txid
+pub fn set_finalized_retention(retention: u32) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    super::FINALIZED_RETENTION.with_borrow_mut(|r| {
+        r.set(retention).expect("failed to persist FINALIZED_RETENTION");
+    });
+    Ok(())
+}
+
+#[query]
+// get_last_block_summary reports how many txids the latest new_block confirmed and finalized
+// and how many blocks it pruned, None until a block arrives after an upgrade
+pub fn get_last_block_summary() -> Option<super::NewBlockSummary> {
+    super::LAST_BLOCK_SUMMARY.get()
+}
+
+#[query]
+// get_finalizing_height returns the height of the block that confirmed a finalized txid,
+// as long as it is within the retention window
+pub fn get_finalizing_height(txid: Txid) -> Option<u32> {
+    super::FINALIZED_TXIDS.with_borrow(|f| f.get(&txid))
+}
+
+#[update]
+// allow_txid_replay lets execute_tx apply a rolled back txid once more
+pub fn allow_txid_replay(txid: Txid) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    super::ROLLED_BACK_TXIDS
+        .with_borrow_mut(|r| r.remove(&txid))
+        .ok_or(format!("txid {} has not been rolled back", txid))?;
+    Ok(())
+}
+
+#[update]
+pub fn set_max_pending_states(max_pending_states: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if max_pending_states == 0 {
+        return Err("Max pending states must be at least 1".to_string());
+    }
+
+    super::MAX_PENDING_STATES.with_borrow_mut(|m| {
+        m.set(max_pending_states).expect("failed to persist MAX_PENDING_STATES");
+    });
+    Ok(())
+}
+
+#[query]
+pub fn get_max_pending_states() -> u64 {
+    super::get_max_pending_states()
+}
+
+#[update]
+// set_min_btc_value sets the global smallest BTC amount, in sats, a trade may move
+// Tokens with their own min_tx_value keep it
+pub fn set_min_btc_value(min_btc_value: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if min_btc_value < super::DUST_LIMIT {
+        return Err(format!("Min BTC value must be at least the dust limit of {} sats", super::DUST_LIMIT));
+    }
+
+    super::MIN_BTC_VALUE.with_borrow_mut(|m| {
+        m.set(min_btc_value).expect("failed to persist MIN_BTC_VALUE");
+    });
+    Ok(())
+}
+
+#[query]
+pub fn get_min_btc_value() -> u64 {
+    super::get_min_btc_value()
+}
+
+#[update]
+// set_network picks the Bitcoin network pool addresses are derived for
+// Only allowed before the first token is created, as existing addresses would not match
+pub fn set_network(network: super::BtcNetwork) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if super::CANVAS_TOKENS.with_borrow(|p| !p.is_empty()) {
+        return Err("Network cannot be changed once a token exists".to_string());
+    }
+
+    super::NETWORK.with_borrow_mut(|n| {
+        n.set(network).expect("failed to persist NETWORK");
+    });
+    Ok(())
+}
+
+#[update]
+// set_schnorr_key_name picks the threshold key pool addresses are derived from, e.g.
+// `dfx_test_key` on a local replica
+// Only allowed before the first token is created, as existing pools could no longer sign
+pub fn set_schnorr_key_name(key_name: String) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    if !super::SCHNORR_KEY_NAMES.contains(&key_name.as_str()) {
+        return Err(format!("Unknown key name, expected one of {:?}", super::SCHNORR_KEY_NAMES));
+    }
+
+    if super::CANVAS_TOKENS.with_borrow(|p| !p.is_empty()) {
+        return Err("Key name cannot be changed once a token exists".to_string());
+    }
+
+    super::SCHNORR_KEY.with_borrow_mut(|k| {
+        k.set(key_name).expect("failed to persist SCHNORR_KEY");
+    });
+    Ok(())
+}
+
+#[query]
+pub fn get_schnorr_key_name() -> String {
+    super::get_schnorr_key_name()
+}
+
+#[update]
+// set_log_level sets the most verbose level the ree module logs, e.g. Error to mute the
+// per-block tracing in production
+pub fn set_log_level(level: super::log::LogLevel) -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+
+    super::log::set_level(level);
+    Ok(())
+}
+
+#[query]
+pub fn get_log_level() -> super::log::LogLevel {
+    super::log::level()
+}
+
+#[query]
+pub fn get_network() -> super::BtcNetwork {
+    super::get_network()
+}
+
+#[query]
+pub fn get_confirmation_depth() -> u32 {
+    super::CONFIRMATION_DEPTH.with_borrow(|d| *d.get())
+}
 
 #[update]
 pub async fn reset_blocks() -> Result<(), String> {
@@ -160,6 +968,85 @@ pub async fn reset_tx_records() -> Result<(), String> {
     Ok(())
 }
 
+#[update]
+// reset_canvas_tokens wipes every token, e.g. for a fresh testnet deployment
+pub async fn reset_canvas_tokens() -> Result<(), String> {
+    let caller = ic_cdk::api::caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Not authorized".to_string());
+    }
+    let removed = wipe_canvas_tokens()?;
+    super::log::info!("reset canvas tokens, {} removed", removed);
+    Ok(())
+}
+
+// Removes every token together with all the per-pool state that would only reference them:
+// transaction records, pending payouts, executed intentions, rollbacks and the trade log
+// Refused while a transaction is executing, since its commit would land on the wiped pools
+fn wipe_canvas_tokens() -> Result<u64, String> {
+    if super::EXECUTING_TOKENS.with_borrow(|e| !e.is_empty()) {
+        return Err("Transactions are executing, retry once they complete".to_string());
+    }
+    let removed: Vec<String> = super::CANVAS_TOKENS.with_borrow_mut(|p| {
+        let removed = p.iter().map(|(addr, _)| addr).collect();
+        p.clear_new();
+        removed
+    });
+    for token_address in &removed {
+        purge_pool_state(token_address);
+    }
+    // Rollbacks are kept per txid rather than per pool, so they go all at once
+    super::ROLLED_BACK_TXIDS.with_borrow_mut(|r| r.clear_new());
+    Ok(removed.len() as u64)
+}
+
+// Drops everything a removed pool leaves behind: the pool is taken out of its transaction
+// records (records left without any pool are removed), and its pending payouts, executed
+// intentions and trade events, together with their txid index entries, are deleted
+fn purge_pool_state(token_address: &String) {
+    super::TX_RECORDS.with_borrow_mut(|t| {
+        let affected: Vec<_> = t
+            .iter()
+            .filter(|(_, record)| record.pools.contains(token_address))
+            .collect();
+        for (key, mut record) in affected {
+            record.pools.retain(|p| p != token_address);
+            if record.pools.is_empty() {
+                t.remove(&key);
+            } else {
+                t.insert(key, record);
+            }
+        }
+    });
+    super::PENDING_PAYOUTS.with_borrow_mut(|p| p.retain(|(pool, _), _| pool != token_address));
+    super::EXECUTED_INTENTIONS.with_borrow_mut(|e| {
+        let keys: Vec<_> = e
+            .iter()
+            .filter(|(_, pool)| pool == token_address)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            e.remove(&key);
+        }
+    });
+    let events: Vec<_> = super::TRADE_EVENTS.with_borrow(|e| {
+        e.iter()
+            .filter(|(_, event)| event.pool == *token_address)
+            .map(|(seq, event)| (seq, event.txid))
+            .collect()
+    });
+    super::TRADE_EVENTS.with_borrow_mut(|e| {
+        for (seq, _) in &events {
+            e.remove(seq);
+        }
+    });
+    super::TRADE_EVENTS_BY_TXID.with_borrow_mut(|i| {
+        for (seq, txid) in events {
+            i.remove(&(txid, seq));
+        }
+    });
+}
+
 #[query]
 pub fn query_tx_records() -> Result<Vec<super::TxRecordInfo>, String> {
     let res = super::TX_RECORDS.with_borrow(|t| {
@@ -175,6 +1062,38 @@ pub fn query_tx_records() -> Result<Vec<super::TxRecordInfo>, String> {
     Ok(res)
 }
 
+#[query]
+pub fn get_tx_status(txid: String) -> Result<super::TxStatus, String> {
+    let txid = Txid::from_str(&txid).map_err(|_| "Invalid txid".to_string())?;
+    let status = super::TX_RECORDS.with_borrow(|t| {
+        if let Some(record) = t.get(&(txid, true)) {
+            super::TxStatus::Confirmed(record.pools)
+        } else if let Some(record) = t.get(&(txid, false)) {
+            super::TxStatus::Unconfirmed(record.pools)
+        } else {
+            super::TxStatus::Unknown
+        }
+    });
+    Ok(status)
+}
+
+#[query]
+// get_pending_pools lists every pool with unconfirmed transactions, with how many it has
+pub fn get_pending_pools() -> Vec<(String, u64)> {
+    let mut pending: BTreeMap<String, u64> = BTreeMap::new();
+    super::TX_RECORDS.with_borrow(|t| {
+        for ((_, confirmed), record) in t.iter() {
+            if confirmed {
+                continue;
+            }
+            for pool in record.pools {
+                *pending.entry(pool).or_default() += 1;
+            }
+        }
+    });
+    pending.into_iter().collect()
+}
+
 #[query]
 pub fn query_blocks() -> Result<Vec<super::BlockInfo>, String> {
     let res = super::BLOCKS.with_borrow(|b| {
@@ -195,6 +1114,37 @@ pub fn get_exchange_rate(token_address: String) -> Result<u64, String> {
     Ok(token.get_current_exchange_rate())
 }
 
+#[query]
+pub fn get_token_by_coin_id(block: u64, tx: u64) -> Option<CanvasTokenInfo> {
+    let id = CoinId::rune(block, tx as u32);
+    find_token_info(|token| token.meta.id == id)
+}
+
+#[query]
+// Symbols are not unique; on a collision the token with the lowest pool address is returned
+pub fn get_token_by_symbol(symbol: String) -> Option<CanvasTokenInfo> {
+    find_token_info(|token| token.meta.symbol == symbol)
+}
+
+// Scans CANVAS_TOKENS in address order for the first token matching `f`
+fn find_token_info(f: impl Fn(&super::CanvasToken) -> bool) -> Option<CanvasTokenInfo> {
+    super::CANVAS_TOKENS.with_borrow(|p| {
+        p.iter().find(|(_, token)| f(token)).map(|(addr, token)| CanvasTokenInfo {
+            address: addr,
+            symbol: token.meta.symbol.clone(),
+            exchange_rate: token.get_current_exchange_rate(),
+            token_id: token.meta.id,
+            decimals: token.meta.decimals,
+        })
+    })
+}
+
+#[query]
+pub fn get_token_supply(token_address: String) -> Result<u128, String> {
+    let token = super::get_canvas_token(&token_address).ok_or("Token not found".to_string())?;
+    Ok(token.current_state().total_supply)
+}
+
 #[query]
 pub fn get_exchange_rate_history(token_address: String) -> Result<Vec<ExchangeRateInfo>, String> {
     let token = super::get_canvas_token(&token_address).ok_or("Token not found".to_string())?;
@@ -215,9 +1165,544 @@ pub fn get_exchange_rate_history(token_address: String) -> Result<Vec<ExchangeRa
     Ok(history)
 }
 
+#[query]
+// get_pool_stats returns a token's trade volume and count, as of its latest state
+pub fn get_pool_stats(token_address: String) -> Result<PoolStats, String> {
+    let token = super::get_canvas_token(&token_address).ok_or("Token not found".to_string())?;
+    let state = token.current_state();
+    Ok(PoolStats {
+        cumulative_btc_volume: state.cumulative_btc_volume,
+        trade_count: state.trade_count,
+    })
+}
+
+#[query]
+// get_twap returns the time-weighted average exchange rate of the last `window_ns` nanoseconds,
+// which unlike the spot rate cannot be moved by a single trade
+pub fn get_twap(token_address: String, window_ns: u64) -> Result<u64, String> {
+    let token = super::get_canvas_token(&token_address).ok_or("Token not found".to_string())?;
+    token
+        .twap(ic_cdk::api::time(), window_ns)
+        .map_err(|e| e.to_string())
+}
+
+#[query]
+// get_token_states pages through a token's state chain, oldest first
+// `limit` is capped at MAX_TOKEN_STATES_PAGE to bound the response size
+pub fn get_token_states(
+    token_address: String,
+    offset: u64,
+    limit: u64,
+) -> Result<Vec<TokenStateView>, String> {
+    let token = super::get_canvas_token(&token_address).ok_or("Token not found".to_string())?;
+
+    let states = token.states
+        .iter()
+        .skip(offset as usize)
+        .take(limit.min(MAX_TOKEN_STATES_PAGE) as usize)
+        .map(|state| TokenStateView {
+            txid: state.id.map(|txid| txid.to_string()),
+            nonce: state.nonce,
+            btc_balance: state.btc_balance,
+            exchange_rate: state.exchange_rate,
+            timestamp: state.timestamp,
+        })
+        .collect();
+
+    Ok(states)
+}
+
+#[query]
+// get_trade_events returns trades with a sequence number greater than `after_seq`
+// (all trades when None), oldest first; clients poll with the last sequence they saw
+// Trades of rolled-back transactions are kept with `rolled_back` set
+pub fn get_trade_events(after_seq: Option<u64>, limit: u64) -> Vec<(u64, super::TradeEvent)> {
+    let start = match after_seq {
+        Some(seq) => match seq.checked_add(1) {
+            Some(start) => start,
+            None => return vec![],
+        },
+        None => 0,
+    };
+    super::TRADE_EVENTS.with_borrow(|e| {
+        e.range(start..)
+            .take(limit.min(MAX_TRADE_EVENTS_PAGE) as usize)
+            .collect()
+    })
+}
+
+#[query]
+// get_tx_trades returns what execute_tx actually minted, burned and paid out for a transaction,
+// one trade per pool it touched, so clients can reconcile against their quote
+// The orchestrator interface fixes execute_tx's response to the signed PSBT, hence the query
+pub fn get_tx_trades(txid: Txid) -> Vec<super::TradeEvent> {
+    super::trade_events_of(txid)
+        .into_iter()
+        .map(|(_, event)| event)
+        .collect()
+}
+
 #[query]
 pub fn blocks_tx_records_count() -> Result<(u64, u64), String> {
     let tx_records_count = super::TX_RECORDS.with_borrow(|t| t.len());
     let blocks_count = super::BLOCKS.with_borrow(|b| b.len());
     Ok((blocks_count, tx_records_count))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ree::{
+        CANVAS_TOKENS, EXECUTED_INTENTIONS, ExecuteTxGuard, PENDING_PAYOUTS, ROLLED_BACK_TXIDS, TRADE_EVENTS,
+        TX_RECORDS, TradeEvent, exchange::get_pool_list, pending_payouts, record_trade_event, tests::trade,
+        trade_events_of,
+        TokenState,
+        token_pool::{CanvasToken, tests::{funded, input, output, state, token, txid, utxo}},
+    };
+
+    #[test]
+    fn reset_waits_for_executing_transactions_then_clears_every_pool_store() {
+        let pool = token(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        record_trade_event(trade(txid(1)));
+        EXECUTED_INTENTIONS.with_borrow_mut(|e| e.insert((txid(1), 0), pool.addr.clone()));
+        ROLLED_BACK_TXIDS.with_borrow_mut(|r| r.insert(txid(2), 0));
+        TX_RECORDS.with_borrow_mut(|r| r.insert((txid(1), false), TxRecord { pools: vec![pool.addr.clone()] }));
+
+        let guard = ExecuteTxGuard::new(pool.addr.clone(), txid(3)).unwrap();
+        assert!(wipe_canvas_tokens().is_err());
+        assert!(CANVAS_TOKENS.with_borrow(|t| t.contains_key(&pool.addr)));
+
+        drop(guard);
+        assert_eq!(wipe_canvas_tokens(), Ok(1));
+        assert!(CANVAS_TOKENS.with_borrow(|t| t.is_empty()));
+        assert!(TX_RECORDS.with_borrow(|r| r.is_empty()));
+        assert!(EXECUTED_INTENTIONS.with_borrow(|e| e.is_empty()));
+        assert!(ROLLED_BACK_TXIDS.with_borrow(|r| r.is_empty()));
+        assert!(TRADE_EVENTS.with_borrow(|e| e.is_empty()));
+        assert!(trade_events_of(txid(1)).is_empty());
+    }
+
+    #[test]
+    fn removing_a_token_purges_only_its_own_pool_state() {
+        let pool = token(PricingMode::FixedRate);
+        let other = CanvasToken {
+            addr: "other".to_string(),
+            ..token(PricingMode::FixedRate)
+        };
+        for t in [&pool, &other] {
+            CANVAS_TOKENS.with_borrow_mut(|c| c.insert(t.addr.clone(), t.clone()));
+        }
+        record_trade_event(trade(txid(1)));
+        record_trade_event(TradeEvent {
+            pool: other.addr.clone(),
+            ..trade(txid(1))
+        });
+        EXECUTED_INTENTIONS.with_borrow_mut(|e| {
+            e.insert((txid(1), 0), pool.addr.clone());
+            e.insert((txid(1), 1), other.addr.clone());
+        });
+        PENDING_PAYOUTS.with_borrow_mut(|p| {
+            p.insert((pool.addr.clone(), txid(2)), 500);
+            p.insert((other.addr.clone(), txid(2)), 700);
+        });
+        TX_RECORDS.with_borrow_mut(|r| {
+            r.insert((txid(1), false), TxRecord { pools: vec![pool.addr.clone(), other.addr.clone()] });
+            r.insert((txid(3), false), TxRecord { pools: vec![pool.addr.clone()] });
+        });
+
+        remove_canvas_token(pool.addr.clone()).unwrap();
+
+        let listed: Vec<String> = get_pool_list().into_iter().map(|p| p.address).collect();
+        assert_eq!(listed, vec![other.addr.clone()]);
+        assert_eq!(trade_events_of(txid(1)).len(), 1);
+        assert!(TRADE_EVENTS.with_borrow(|e| e.iter().all(|(_, event)| event.pool == other.addr)));
+        assert_eq!(
+            EXECUTED_INTENTIONS.with_borrow(|e| e.iter().collect::<Vec<_>>()),
+            vec![((txid(1), 1), other.addr.clone())]
+        );
+        assert_eq!(pending_payouts(&pool.addr), 0);
+        assert_eq!(pending_payouts(&other.addr), 700);
+        assert_eq!(TX_RECORDS.with_borrow(|r| r.get(&(txid(1), false))).unwrap().pools, vec![other.addr.clone()]);
+        assert!(TX_RECORDS.with_borrow(|r| r.get(&(txid(3), false))).is_none());
+        assert_eq!(remove_canvas_token(pool.addr.clone()), Err("Token not found".to_string()));
+    }
+
+    #[test]
+    fn withdrawals_wait_for_pending_transactions_and_are_recorded() {
+        let mut pool = funded(PricingMode::FixedRate);
+        assert!(ensure_settled(&pool, &pool.addr).is_ok());
+        let guard = ExecuteTxGuard::new(pool.addr.clone(), txid(2)).unwrap();
+        assert!(ensure_settled(&pool, &pool.addr).is_err());
+        drop(guard);
+        pool.commit(state(2)).unwrap();
+        assert!(ensure_settled(&pool, &pool.addr).is_err());
+
+        let pool = funded(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        let psbt = Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap();
+        let withdrawn = TokenState {
+            nonce: 2,
+            ..Default::default()
+        };
+        assert!(commit_withdrawal(pool.addr.clone(), 0, &psbt, withdrawn.clone()).is_err());
+        commit_withdrawal(pool.addr.clone(), 1, &psbt, withdrawn).unwrap();
+
+        let txid = Txid::from_str(&psbt.unsigned_tx.compute_txid().to_string()).unwrap();
+        let record = TX_RECORDS.with_borrow(|r| r.get(&(txid, false))).unwrap();
+        assert_eq!(record.pools, vec![pool.addr.clone()]);
+        let token = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        assert!(token.holds_txid(txid));
+    }
+
+    #[test]
+    fn emergency_withdrawal_empties_the_reserve_at_the_given_fee_rate() {
+        let state = TokenState {
+            fees_accrued: 300,
+            ..funded(PricingMode::FixedRate).current_state()
+        };
+        let withdrawn = withdrawn_state(&state, 42);
+        assert_eq!(withdrawn.btc_balance, 0);
+        assert_eq!(withdrawn.fees_accrued, 0);
+        assert!(withdrawn.utxos.is_empty());
+        assert_eq!(withdrawn.nonce, state.nonce + 1);
+        assert_eq!(withdrawn.id, None);
+        assert_eq!(withdrawn.timestamp, 42);
+        assert_eq!((withdrawn.token_reserve, withdrawn.total_supply), (state.token_reserve, state.total_supply));
+
+        assert_eq!(withdraw_fee(1, 1, 1), Ok(112));
+        assert_eq!(withdraw_fee(25, 1, 1), Ok(25 * 112));
+        assert!(withdraw_fee(0, 1, 1).is_err());
+        assert!(withdraw_fee(u64::MAX, 1, 1).is_err());
+    }
+
+    #[test]
+    fn collecting_fees_takes_exactly_the_fees_out_of_the_reserve() {
+        let funded = funded(PricingMode::FixedRate).current_state();
+        let state = TokenState {
+            fees_accrued: 2_000,
+            ..funded.clone()
+        };
+        let change = state.btc_balance - state.fees_accrued;
+        let collected = fees_collected_state(&state, txid(7), change, 42);
+        assert_eq!(collected.fees_accrued, 0);
+        assert_eq!(collected.btc_balance, state.btc_balance - 2_000);
+        assert_eq!(collected.nonce, state.nonce + 1);
+        assert_eq!(collected.timestamp, 42);
+        assert_eq!(collected.utxos.len(), 1);
+        let back = &collected.utxos[0];
+        assert_eq!((back.txid, back.vout, back.sats), (txid(7), 0, change));
+        assert_eq!(back.coins, funded.utxos[0].coins);
+        assert_eq!(collected.utxos.iter().map(|u| u.sats).sum::<u64>(), collected.btc_balance);
+
+        // Fees that are the whole reserve leave nothing to send back
+        let drained = TokenState {
+            fees_accrued: state.btc_balance,
+            ..state
+        };
+        let collected = fees_collected_state(&drained, txid(7), 0, 42);
+        assert_eq!((collected.btc_balance, collected.fees_accrued), (0, 0));
+        assert!(collected.utxos.is_empty());
+    }
+
+    #[test]
+    fn a_never_traded_token_is_read_through_its_genesis_state() {
+        let pool = token(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        assert!(pool.states.is_empty());
+
+        assert!(matches!(
+            pre_sell_token(pool.addr.clone(), 1_000_000, None, Some(0)),
+            Err(ExchangeError::InsufficientBtc)
+        ));
+        assert!(matches!(
+            pre_sell_token(pool.addr.clone(), 1_000_000, None, Some(1)),
+            Err(ExchangeError::StaleNonce(1, 0))
+        ));
+        // Same for the sell itself
+        let sold = pool.validate_sell_token(
+            txid(1),
+            0,
+            vec![format!("{}:0", txid(9))],
+            vec![],
+            input(pool.token_id(), 1_000_000),
+            vec![output(CoinId::btc(), 100_000)],
+            pool.get_current_exchange_rate(),
+            None,
+            0,
+        );
+        assert!(matches!(sold, Err(ExchangeError::InsufficientBtc)));
+    }
+
+    #[test]
+    fn tx_trades_are_the_events_of_that_txid() {
+        for n in [1, 2, 1] {
+            record_trade_event(trade(txid(n)));
+        }
+        assert_eq!(get_tx_trades(txid(1)), vec![trade(txid(1)), trade(txid(1))]);
+        assert_eq!(get_tx_trades(txid(3)), vec![]);
+    }
+
+    #[test]
+    fn seed_utxos_must_be_unspent_outputs_of_the_pool_address() {
+        use ic_cdk::api::management_canister::bitcoin::Outpoint;
+
+        let bytes: Vec<u8> = (0..32).collect();
+        let seed = utxo(Txid::from_str(&hex::encode(&bytes)).unwrap(), 5_000);
+        let held = |txid: Vec<u8>, vout, value| BtcUtxo {
+            outpoint: Outpoint { txid, vout },
+            value,
+            height: 1,
+        };
+        let reversed: Vec<u8> = bytes.iter().rev().copied().collect();
+
+        assert_eq!(check_seed_utxos(std::slice::from_ref(&seed), &[held(reversed.clone(), 0, 5_000)]), Ok(()));
+        assert_eq!(check_seed_utxos(&[], &[]), Ok(()));
+        for pool_utxos in [
+            vec![],
+            vec![held(bytes.clone(), 0, 5_000)],
+            vec![held(reversed.clone(), 1, 5_000)],
+            vec![held(reversed.clone(), 0, 4_999)],
+        ] {
+            assert!(check_seed_utxos(std::slice::from_ref(&seed), &pool_utxos).is_err());
+        }
+    }
+
+    #[test]
+    fn a_rate_update_reprices_quotes_and_stales_their_nonces() {
+        let pool = funded(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        let offer = pre_buy_token(pool.addr.clone(), 1_000, None, None).unwrap();
+        assert_eq!((offer.nonce, offer.token_amount), (1, 10_000));
+
+        assert!(update_exchange_rate(pool.addr.clone(), 0).is_err());
+        assert!(update_exchange_rate("unknown".to_string(), 20).is_err());
+        update_exchange_rate(pool.addr.clone(), 20).unwrap();
+        assert_eq!(get_exchange_rate(pool.addr.clone()), Ok(20));
+
+        let updated = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        let state = updated.current_state();
+        assert_eq!((state.nonce, state.id, state.exchange_rate), (2, None, Some(20)));
+        assert_eq!(state.btc_balance, pool.current_state().btc_balance);
+        assert_eq!(updated.states.len(), 2);
+
+        // The quote made at nonce 1 no longer holds
+        assert!(matches!(
+            pre_buy_token(pool.addr.clone(), 1_000, None, Some(offer.nonce)),
+            Err(ExchangeError::StaleNonce(1, 2))
+        ));
+        let stale = updated.validate_buy_token(
+            txid(2),
+            offer.nonce,
+            vec![],
+            vec![utxo(txid(2), 1_000)],
+            input(CoinId::btc(), 1_000),
+            vec![output(updated.token_id(), offer.token_amount)],
+            updated.get_current_exchange_rate(),
+            None,
+        );
+        assert!(matches!(stale, Err(ExchangeError::TokenStateExpired(2))));
+        assert_eq!(pre_buy_token(pool.addr.clone(), 1_000, None, Some(2)).unwrap().token_amount, 20_000);
+    }
+
+    #[test]
+    fn the_network_is_configurable_until_the_first_token() {
+        use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+        use ree_types::bitcoin::Network;
+
+        assert_eq!(get_network(), crate::ree::BtcNetwork::Testnet4);
+        set_network(crate::ree::BtcNetwork::Regtest).unwrap();
+        let network = get_network();
+        assert_eq!((network.network(), network.bitcoin_network()), (Network::Regtest, BitcoinNetwork::Regtest));
+
+        // Pool addresses are derived for the network, so it is fixed once one exists
+        let pool = token(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool));
+        assert!(set_network(crate::ree::BtcNetwork::Bitcoin).is_err());
+        assert_eq!(get_network(), crate::ree::BtcNetwork::Regtest);
+    }
+
+    #[test]
+    fn an_operator_rate_survives_rolling_back_the_states_around_it() {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.commit(TokenState {
+            exchange_rate: Some(15),
+            ..state(2)
+        })
+        .unwrap();
+        // The latest state's rate beats the meta default
+        assert_eq!(pool.get_current_exchange_rate(), 15);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+
+        update_exchange_rate(pool.addr.clone(), 20).unwrap();
+        let mut pool = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+        assert_eq!(pool.states.len(), 3);
+
+        // Rolling back txid(2) also drops the state the update appended
+        pool.rollback(txid(2)).unwrap();
+        assert_eq!(pool.states.len(), 1);
+        assert_eq!(pool.get_current_exchange_rate(), 20);
+        pool.rollback(txid(1)).unwrap();
+        assert!(pool.states.is_empty());
+        assert_eq!(pool.get_current_exchange_rate(), 20);
+
+        pool.operator_rate = None;
+        assert_eq!(pool.get_current_exchange_rate(), pool.meta.exchange_rate);
+    }
+
+    #[test]
+    fn quotes_fail_early_on_a_stale_expected_nonce() {
+        let pool = funded(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        let addr = || pool.addr.clone();
+
+        for expected_nonce in [None, Some(1)] {
+            assert_eq!(pre_buy_token(addr(), 1_000, None, expected_nonce).unwrap().nonce, 1);
+            assert_eq!(pre_buy_token_exact_out(addr(), 10_000, expected_nonce).unwrap().nonce, 1);
+            assert_eq!(pre_sell_token(addr(), 1_000, None, expected_nonce).unwrap().nonce, 1);
+        }
+        for stale in [0, 2] {
+            let is_stale = |e: ExchangeError| matches!(e, ExchangeError::StaleNonce(n, 1) if n == stale);
+            assert!(pre_buy_token(addr(), 1_000, None, Some(stale)).err().is_some_and(is_stale));
+            assert!(pre_buy_token_exact_out(addr(), 10_000, Some(stale)).err().is_some_and(is_stale));
+            assert!(pre_sell_token(addr(), 1_000, None, Some(stale)).err().is_some_and(is_stale));
+        }
+    }
+
+    #[test]
+    fn tokens_are_found_by_coin_id_or_symbol() {
+        let pool = token(PricingMode::FixedRate);
+        let namesake = CanvasToken {
+            addr: "a-namesake".to_string(),
+            meta: TokenMeta {
+                id: CoinId::rune(840_000, 2),
+                ..pool.meta.clone()
+            },
+            ..pool.clone()
+        };
+        for t in [&pool, &namesake] {
+            CANVAS_TOKENS.with_borrow_mut(|c| c.insert(t.addr.clone(), t.clone()));
+        }
+
+        let found = get_token_by_coin_id(840_000, 1).unwrap();
+        assert_eq!(
+            found,
+            CanvasTokenInfo {
+                address: pool.addr.clone(),
+                symbol: "PIXEL".to_string(),
+                exchange_rate: 10,
+                token_id: CoinId::rune(840_000, 1),
+                decimals: 0,
+            }
+        );
+        assert_eq!(get_token_by_coin_id(840_000, 2).unwrap().address, namesake.addr);
+        assert_eq!(get_token_by_coin_id(840_000, 3), None);
+
+        // Both are PIXEL; the lower address wins
+        assert_eq!(get_token_by_symbol("PIXEL".to_string()).unwrap().address, namesake.addr);
+        assert_eq!(get_token_by_symbol("pixel".to_string()), None);
+    }
+
+    #[test]
+    fn only_known_key_names_are_accepted_before_the_first_token() {
+        assert_eq!(get_schnorr_key_name(), "key_1");
+        assert!(set_schnorr_key_name("my_key".to_string()).is_err());
+        assert_eq!(get_schnorr_key_name(), "key_1");
+        set_schnorr_key_name("dfx_test_key".to_string()).unwrap();
+        assert_eq!(get_schnorr_key_name(), "dfx_test_key");
+
+        // Existing pools could no longer sign with another key
+        let pool = token(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool));
+        assert!(set_schnorr_key_name("test_key_1".to_string()).is_err());
+        assert_eq!(get_schnorr_key_name(), "dfx_test_key");
+    }
+
+    #[test]
+    fn the_sell_quote_and_its_execution_agree_on_the_reserve() {
+        let pool = funded(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        let execute = |btc_out: u64| {
+            let token = CANVAS_TOKENS.with_borrow(|t| t.get(&pool.addr)).unwrap();
+            let state = token.current_state();
+            token.validate_sell_token(
+                txid(9),
+                state.nonce,
+                vec![format!("{}:0", txid(1))],
+                vec![utxo(txid(9), 100_000u64.saturating_sub(btc_out))],
+                input(token.token_id(), 1_000_000),
+                vec![output(CoinId::btc(), btc_out as u128)],
+                token.get_current_exchange_rate(),
+                None,
+                pending_payouts(&pool.addr),
+            )
+        };
+
+        // The whole supply is worth 200_000 sats at rate 5, twice the reserve
+        update_exchange_rate(pool.addr.clone(), 5).unwrap();
+        assert!(matches!(pre_sell_token(pool.addr.clone(), 1_000_000, None, None), Err(ExchangeError::InsufficientBtc)));
+        assert!(matches!(execute(200_000), Err(ExchangeError::InsufficientBtc)));
+
+        // At rate 100 both price the sell at 10_000 sats and accept it
+        update_exchange_rate(pool.addr.clone(), 100).unwrap();
+        let offer = pre_sell_token(pool.addr.clone(), 1_000_000, None, None).unwrap();
+        assert_eq!((offer.nonce, offer.btc_amount, offer.exchange_rate), (3, 10_000, 100));
+        let (state, btc_amount, _) = execute(offer.btc_amount).unwrap();
+        assert_eq!((state.nonce, btc_amount), (offer.nonce + 1, offer.btc_amount));
+    }
+
+    #[test]
+    fn btc_promised_to_an_unsigned_sell_is_not_offered_twice() {
+        use crate::ree::PayoutGuard;
+
+        let pool = funded(PricingMode::FixedRate);
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        // 600_000 tokens fetch 60_000 of the 100_000 sats reserve at rate 10
+        let execute = || {
+            pool.validate_sell_token(
+                txid(9),
+                1,
+                vec![format!("{}:0", txid(1))],
+                vec![utxo(txid(9), 40_000)],
+                input(pool.token_id(), 600_000),
+                vec![output(CoinId::btc(), 60_000)],
+                10,
+                None,
+                pending_payouts(&pool.addr),
+            )
+            .map(drop)
+        };
+        assert!(pre_sell_token(pool.addr.clone(), 600_000, None, None).is_ok());
+        assert!(execute().is_ok());
+
+        // A first sell of the same size is still being signed
+        let guard = PayoutGuard::new(pool.addr.clone(), txid(8), 60_000);
+        assert!(matches!(pre_sell_token(pool.addr.clone(), 600_000, None, None), Err(ExchangeError::InsufficientBtc)));
+        assert!(matches!(execute(), Err(ExchangeError::InsufficientBtc)));
+
+        drop(guard);
+        assert_eq!(pending_payouts(&pool.addr), 0);
+        assert!(pre_sell_token(pool.addr.clone(), 600_000, None, None).is_ok());
+        assert!(execute().is_ok());
+    }
+
+    #[test]
+    fn tokens_without_their_own_minimum_follow_the_global_one() {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.meta.min_tx_value = None;
+        CANVAS_TOKENS.with_borrow_mut(|t| t.insert(pool.addr.clone(), pool.clone()));
+        assert_eq!(get_min_btc_value(), crate::ree::DEFAULT_MIN_BTC_VALUE);
+        assert!(matches!(pre_buy_token(pool.addr.clone(), 5_000, None, None), Err(ExchangeError::TooSmallFunds)));
+
+        assert!(set_min_btc_value(545).is_err());
+        set_min_btc_value(546).unwrap();
+        assert_eq!(get_min_btc_value(), 546);
+        assert_eq!(pre_buy_token(pool.addr.clone(), 5_000, None, None).unwrap().token_amount, 50_000);
+    }
+}
@@ -3,8 +3,38 @@ use candid::{CandidType, Deserialize};
 use ic_stable_structures::{Storable, storable::Bound};
 use ree_types::{CoinId, InputCoin, OutputCoin, Pubkey, Txid, Utxo};
 use serde::Serialize;
+use std::collections::BTreeMap;
 
-pub const MIN_BTC_VALUE: u64 = 10000;
+// How a pool prices trades
+#[derive(Clone, Copy, CandidType, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PricingMode {
+    // Fixed `exchange_rate` tokens per satoshi, independent of liquidity
+    #[default]
+    FixedRate,
+    // x * y = k over the pool's `btc_balance` and `token_reserve`
+    ConstantProduct,
+}
+
+// Direction of a prospective trade, from the user's side
+#[derive(Clone, Copy, CandidType, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Side {
+    // Pays `amount` satoshis for tokens
+    Buy,
+    // Pays `amount` tokens for satoshis
+    Sell,
+}
+
+// Rates in PriceImpact are token units per this many satoshis (1 BTC)
+pub const PRICE_IMPACT_RATE_SCALE: u128 = 100_000_000;
+
+// How a prospective trade moves the price, rates in token units per BTC
+#[derive(Clone, CandidType, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PriceImpact {
+    pub spot_rate: u128,      // 交易前的即时汇率
+    pub effective_rate: u128, // 本笔交易的实际成交汇率（含手续费）
+    pub post_trade_rate: u128, // 交易后的即时汇率
+    pub impact_bps: u64,      // 实际成交汇率偏离即时汇率的幅度（基点），固定汇率池恒为 0
+}
 
 #[derive(Clone, CandidType, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct TokenMeta {
@@ -12,6 +42,18 @@ pub struct TokenMeta {
     pub symbol: String,
     pub exchange_rate: u64,
     pub min_amount: u128,
+    #[serde(default)]
+    pub pricing_mode: PricingMode,
+    #[serde(default)]
+    pub fee_bps: u16, // 每笔交易收取的手续费（基点，按 BTC 计）
+    #[serde(default)]
+    pub max_supply: Option<u128>, // 代币供应上限，None 表示不设上限
+    #[serde(default)]
+    pub decimals: u8, // 代币精度，exchange_rate 按整币计，链上数量按最小单位计
+    #[serde(default)]
+    pub max_trade_fraction_bps: Option<u16>, // 单笔卖出最多可取走 BTC 储备（btc_balance 减去 fees_accrued）的比例（基点），None 表示不限制
+    #[serde(default)]
+    pub min_tx_value: Option<u64>, // 单笔交易的最小 BTC 金额（sats），None 表示使用全局的 MIN_BTC_VALUE
 }
 
 impl TokenMeta {
@@ -21,6 +63,12 @@ impl TokenMeta {
             symbol: "BTC".to_string(),
             exchange_rate: 1,
             min_amount: 546,
+            pricing_mode: PricingMode::FixedRate,
+            fee_bps: 0,
+            max_supply: None,
+            decimals: 0,
+            max_trade_fraction_bps: None,
+            min_tx_value: None,
         }
     }
 }
@@ -32,11 +80,43 @@ pub struct CanvasToken {
     pub pubkey: Pubkey,
     pub tweaked: Pubkey,
     pub addr: String,
+    #[serde(default)]
+    pub paused: bool, // 暂停后拒绝所有报价和交易
+    #[serde(default)]
+    pub state_index: BTreeMap<Txid, u64>, // 交易ID到其状态 nonce 的索引，用于快速定位状态
+    #[serde(default)]
+    pub operator_rate: Option<u64>, // 运营方通过 update_exchange_rate 设置的汇率，不随回滚丢失
 }
 
 impl CanvasToken {
+    // The state a token starts from before its first commit: nonce 0, no reserves,
+    // priced at the rate it was created with
+    pub fn genesis_state(&self) -> TokenState {
+        TokenState {
+            id: None,
+            nonce: 0,
+            btc_balance: 0,
+            exchange_rate: Some(self.meta.exchange_rate),
+            timestamp: 0,
+            ..Default::default()
+        }
+    }
+
+    // The latest state, or the genesis state while the chain is empty
+    pub fn current_state(&self) -> TokenState {
+        self.states.last().cloned().unwrap_or_else(|| self.genesis_state())
+    }
+
     pub fn attrs(&self) -> String {
-        format!("exchange_rate:{}", self.meta.exchange_rate)
+        let state = self.current_state();
+        format!(
+            "exchange_rate:{},decimals:{},fee_bps:{},fees_accrued:{},total_supply:{}",
+            self.get_current_exchange_rate(),
+            self.meta.decimals,
+            self.meta.fee_bps,
+            state.fees_accrued,
+            state.total_supply
+        )
     }
 }
 
@@ -47,6 +127,34 @@ pub struct TokenState {
     pub btc_balance: u64,
     pub exchange_rate: Option<u64>, // 此次交易时使用的汇率（价格）
     pub timestamp: u64,             // 交易时间戳
+    #[serde(default)]
+    pub utxos: Vec<Utxo>,           // 此状态下池子持有的 UTXO 集合
+    #[serde(default)]
+    pub token_reserve: u128,        // 池子持有的代币储备（仅 ConstantProduct 模式使用）
+    #[serde(default)]
+    pub fees_accrued: u64,          // 已累计、尚未提取的手续费（包含在 btc_balance 中）
+    #[serde(default)]
+    pub total_supply: u128,         // 已铸造减去已销毁的代币总量
+    #[serde(default)]
+    pub cumulative_rate_time: u128, // 汇率对时间的累计积分（汇率 × 纳秒），用于计算 TWAP
+    #[serde(default)]
+    pub cumulative_btc_volume: u64, // 累计成交的 BTC 数量（聪），回滚时随状态一并撤销
+    #[serde(default)]
+    pub trade_count: u64,           // 累计成交笔数（买入与卖出）
+}
+
+impl TokenState {
+    // Counts a trade of `btc_amount` sats into the running volume stats
+    // Kept on the state, so rolling the trade back drops it from the stats as well
+    fn record_trade(&mut self, btc_amount: u64) {
+        self.cumulative_btc_volume = self.cumulative_btc_volume.saturating_add(btc_amount);
+        self.trade_count += 1;
+    }
+
+    // The part of btc_balance backing trades, i.e. excluding fees kept for the operator
+    pub fn btc_reserve(&self) -> u64 {
+        self.btc_balance.saturating_sub(self.fees_accrued)
+    }
 }
 
 impl Storable for TokenState {
@@ -90,33 +198,321 @@ impl CanvasToken {
         vec![self.token_id().to_string().as_bytes().to_vec()]
     }
 
-    // Calculate how many tokens can be bought with the given BTC amount using current rate
-    pub fn calculate_buy_amount(&self, btc_amount: u64) -> u128 {
-        let rate = self.get_current_exchange_rate();
-        (btc_amount as u128) * (rate as u128)
+    // Smallest BTC amount a single trade with this pool may move
+    // Falls back to the global MIN_BTC_VALUE unless the token configures its own
+    pub fn min_tx_value(&self) -> u64 {
+        self.meta.min_tx_value.unwrap_or_else(super::get_min_btc_value)
+    }
+
+    // Calculate how many tokens can be bought with the given BTC amount (after fee)
+    // Uses the current rate, or the current reserves for constant-product pools
+    pub fn calculate_buy_amount(&self, btc_amount: u64) -> Result<u128, ExchangeError> {
+        let state = self.current_state();
+        self.quote_buy(&state, btc_amount, self.get_current_exchange_rate())
+            .map(|(token_amount, _fee)| token_amount)
+            .ok_or(ExchangeError::Overflow)
     }
 
-    // Calculate how much BTC can be obtained by selling the given token amount using current rate
+    // Calculate the BTC (fee included) needed to buy at least `token_amount` tokens
+    // Inverts the buy pricing and rounds up, so the forward quote for the result never falls short
+    pub fn calculate_buy_cost(&self, token_amount: u128) -> Result<u64, ExchangeError> {
+        let state = self.current_state();
+        let exchange_rate = self.get_current_exchange_rate();
+        let net_btc = match self.meta.pricing_mode {
+            PricingMode::FixedRate => {
+                let units = self
+                    .units_per_sat(exchange_rate)
+                    .filter(|units| *units > 0)
+                    .ok_or(ExchangeError::Overflow)?;
+                token_amount.div_ceil(units)
+            }
+            // reserve_out * x / (reserve_in + x) >= token_amount
+            // <=> x >= token_amount * reserve_in / (reserve_out - token_amount)
+            PricingMode::ConstantProduct => {
+                let reserve_left = state
+                    .token_reserve
+                    .checked_sub(token_amount)
+                    .filter(|left| *left > 0)
+                    .ok_or(ExchangeError::InvalidState("not enough token reserve".to_string()))?;
+                mul_div(token_amount, state.btc_reserve() as u128, reserve_left)
+                    .and_then(|x| x.checked_add(1))
+                    .ok_or(ExchangeError::Overflow)?
+            }
+        };
+
+        // Gross up by the fee for a first estimate
+        let fee_bps = self.meta.fee_bps as u128;
+        (fee_bps < 10_000)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState("fee takes the whole amount".to_string()))?;
+        let estimate: u64 = mul_div(net_btc, 10_000, 10_000 - fee_bps)
+            .and_then(|gross| u64::try_from(gross).ok())
+            .ok_or(ExchangeError::Overflow)?;
+
+        least_buying_amount(estimate, |btc_amount| {
+            self.quote_buy(&state, btc_amount, exchange_rate)
+                .map(|(tokens, _fee)| tokens >= token_amount)
+                .ok_or(ExchangeError::Overflow)
+        })
+    }
+
+    // Calculate how much BTC can be obtained by selling the given token amount (after fee)
+    // Uses the current rate, or the current reserves for constant-product pools
     pub fn calculate_sell_amount(&self, token_amount: u128) -> u64 {
-        let rate = self.get_current_exchange_rate();
-        (token_amount / (rate as u128)) as u64
+        let state = self.current_state();
+        self.quote_sell(&state, token_amount, self.get_current_exchange_rate())
+            .map(|(btc_amount, _fee)| btc_amount)
+            .unwrap_or_default()
+    }
+
+    // The BTC fee withheld from the payout when selling the given token amount
+    pub fn calculate_sell_fee(&self, token_amount: u128) -> u64 {
+        let state = self.current_state();
+        self.quote_sell(&state, token_amount, self.get_current_exchange_rate())
+            .map(|(_btc_amount, fee)| fee)
+            .unwrap_or_default()
     }
 
-    // Calculate buy amount with specific exchange rate
-    pub fn calculate_buy_amount_with_rate(&self, btc_amount: u64, exchange_rate: u64) -> u128 {
-        (btc_amount as u128) * (exchange_rate as u128)
+    // Calculate buy amount with specific exchange rate, after deducting the fee from the BTC paid
+    pub fn calculate_buy_amount_with_rate(
+        &self,
+        btc_amount: u64,
+        exchange_rate: u64,
+    ) -> Result<u128, ExchangeError> {
+        let (net_btc, _fee) = self.split_fee(btc_amount);
+        self.units_per_sat(exchange_rate)
+            .and_then(|units| (net_btc as u128).checked_mul(units))
+            .ok_or(ExchangeError::Overflow)
     }
 
-    // Calculate sell amount with specific exchange rate
+    // Calculate sell amount with specific exchange rate, after deducting the fee from the BTC paid out
+    // Rounds down, see `sell_remainder` for the part of `token_amount` that buys no satoshi
     pub fn calculate_sell_amount_with_rate(&self, token_amount: u128, exchange_rate: u64) -> u64 {
-        (token_amount / (exchange_rate as u128)) as u64
+        self.units_per_sat(exchange_rate)
+            .and_then(|units| fixed_rate_btc_out(token_amount, units))
+            .map(|gross_btc| self.split_fee(gross_btc).0)
+            .unwrap_or_default()
+    }
+
+    // The tokens left over when selling `token_amount` at the current rate
+    // Sells round down to whole satoshis, so these would be burned for nothing;
+    // callers can sell `token_amount - remainder` instead and keep the dust, or spend the whole
+    // input and take the remainder back as a change output
+    // Constant-product pools also round down but have no exact remainder, so this is zero for them
+    pub fn sell_remainder(&self, token_amount: u128) -> u128 {
+        match self.meta.pricing_mode {
+            PricingMode::FixedRate => self
+                .units_per_sat(self.get_current_exchange_rate())
+                .and_then(|units| token_amount.checked_rem(units))
+                .unwrap_or_default(),
+            PricingMode::ConstantProduct => 0,
+        }
+    }
+
+    // Smallest token units exchanged for one satoshi at a fixed rate
+    // `exchange_rate` counts whole tokens, so it is scaled by 10^decimals; None on overflow
+    fn units_per_sat(&self, exchange_rate: u64) -> Option<u128> {
+        10u128
+            .checked_pow(self.meta.decimals as u32)?
+            .checked_mul(exchange_rate as u128)
+    }
+
+    // Estimates how a trade of `amount` would move the price, without touching any state
+    // Fixed-rate pools have no slippage: their rate stays put and the impact is zero,
+    // the fee only shows in the effective rate
+    pub fn estimate_price_impact(&self, side: Side, amount: u128) -> Result<PriceImpact, ExchangeError> {
+        let state = self.current_state();
+        let exchange_rate = self.get_current_exchange_rate();
+        let spot_rate = self.spot_rate(state.btc_reserve(), state.token_reserve, exchange_rate)?;
+
+        let (btc_amount, token_amount, btc_reserve, token_reserve) = match side {
+            Side::Buy => {
+                let btc_amount = u64::try_from(amount).map_err(|_| ExchangeError::Overflow)?;
+                let (token_amount, fee) = self
+                    .quote_buy(&state, btc_amount, exchange_rate)
+                    .ok_or(ExchangeError::Overflow)?;
+                (
+                    btc_amount,
+                    token_amount,
+                    state.btc_reserve().checked_add(btc_amount - fee).ok_or(ExchangeError::Overflow),
+                    state
+                        .token_reserve
+                        .checked_sub(token_amount)
+                        .ok_or(ExchangeError::InsufficientTokenReserve),
+                )
+            }
+            Side::Sell => {
+                let (btc_amount, fee) = self
+                    .quote_sell(&state, amount, exchange_rate)
+                    .ok_or(ExchangeError::Overflow)?;
+                (
+                    btc_amount,
+                    amount,
+                    state.btc_reserve().checked_sub(btc_amount + fee).ok_or(ExchangeError::InsufficientBtc),
+                    state.token_reserve.checked_add(amount).ok_or(ExchangeError::Overflow),
+                )
+            }
+        };
+        (btc_amount > 0 && token_amount > 0)
+            .then(|| ())
+            .ok_or(ExchangeError::TooSmallFunds)?;
+
+        let effective_rate = mul_div(token_amount, PRICE_IMPACT_RATE_SCALE, btc_amount as u128)
+            .ok_or(ExchangeError::Overflow)?;
+        let (post_trade_rate, impact_bps) = match self.meta.pricing_mode {
+            PricingMode::FixedRate => (spot_rate, 0),
+            PricingMode::ConstantProduct => {
+                let post_trade_rate = self.spot_rate(btc_reserve?, token_reserve?, exchange_rate)?;
+                let impact_bps = mul_div(spot_rate.abs_diff(effective_rate), 10_000, spot_rate)
+                    .map(|bps| bps.min(u64::MAX as u128) as u64)
+                    .unwrap_or_default();
+                (post_trade_rate, impact_bps)
+            }
+        };
+
+        Ok(PriceImpact {
+            spot_rate,
+            effective_rate,
+            post_trade_rate,
+            impact_bps,
+        })
     }
 
-    // Get current exchange rate (from latest state or fallback to meta)
+    // Marginal rate, in token units per BTC, of a pool holding the given reserves
+    fn spot_rate(&self, btc_reserve: u64, token_reserve: u128, exchange_rate: u64) -> Result<u128, ExchangeError> {
+        match self.meta.pricing_mode {
+            PricingMode::FixedRate => self
+                .units_per_sat(exchange_rate)
+                .and_then(|units| units.checked_mul(PRICE_IMPACT_RATE_SCALE))
+                .ok_or(ExchangeError::Overflow),
+            PricingMode::ConstantProduct => {
+                Ok(mul_div(token_reserve, PRICE_IMPACT_RATE_SCALE, btc_reserve as u128).unwrap_or_default())
+            }
+        }
+    }
+
+    // Early staleness check for the pre_* quotes: fails if the caller expects a nonce other than
+    // the live one, so a stale quote fails with StaleNonce before a tx is built rather than
+    // later in execute_tx
+    pub fn check_expected_nonce(&self, expected_nonce: Option<u64>) -> Result<(), ExchangeError> {
+        let nonce = self.current_state().nonce;
+        expected_nonce
+            .is_none_or(|expected| expected == nonce)
+            .then(|| ())
+            .ok_or(ExchangeError::StaleNonce(expected_nonce.unwrap_or_default(), nonce))
+    }
+
+    // Circuit breaker: rejects a payout larger than `max_trade_fraction_bps` of the BTC reserve
+    // Accrued fees are not the pool's to pay out, so they do not count towards the limit
+    pub(crate) fn check_trade_size(&self, state: &TokenState, btc_amount: u64) -> Result<(), ExchangeError> {
+        let max_bps = self.meta.max_trade_fraction_bps.unwrap_or(10_000) as u128;
+        ((btc_amount as u128) * 10_000 <= (state.btc_reserve() as u128) * max_bps)
+            .then(|| ())
+            .ok_or(ExchangeError::TradeTooLarge)
+    }
+
+    // Trading fee charged on a BTC amount, rounded down
+    pub fn calculate_fee(&self, btc_amount: u64) -> u64 {
+        ((btc_amount as u128) * (self.meta.fee_bps as u128) / 10_000) as u64
+    }
+
+    // Splits a gross BTC amount into (amount after fee, fee)
+    fn split_fee(&self, btc_amount: u64) -> (u64, u64) {
+        let fee = self.calculate_fee(btc_amount);
+        (btc_amount - fee, fee)
+    }
+
+    // Prices a buy of `btc_amount` against `state`
+    // Returns (tokens out, BTC fee kept by the pool), None on overflow
+    fn quote_buy(&self, state: &TokenState, btc_amount: u64, exchange_rate: u64) -> Option<(u128, u64)> {
+        let (net_btc, fee) = self.split_fee(btc_amount);
+        let token_amount = match self.meta.pricing_mode {
+            PricingMode::FixedRate => self.calculate_buy_amount_with_rate(btc_amount, exchange_rate).ok()?,
+            PricingMode::ConstantProduct => constant_product_out(
+                state.btc_reserve() as u128,
+                state.token_reserve,
+                net_btc as u128,
+            )?,
+        };
+        Some((token_amount, fee))
+    }
+
+    // Prices a sell of `token_amount` against `state`
+    // Returns (BTC out, BTC fee kept by the pool), None on overflow
+    pub(crate) fn quote_sell(&self, state: &TokenState, token_amount: u128, exchange_rate: u64) -> Option<(u64, u64)> {
+        let gross_btc = match self.meta.pricing_mode {
+            PricingMode::FixedRate => {
+                fixed_rate_btc_out(token_amount, self.units_per_sat(exchange_rate)?)?
+            }
+            // The output is bounded by the BTC reserve, so it always fits in a u64
+            PricingMode::ConstantProduct => constant_product_out(
+                state.token_reserve,
+                state.btc_reserve() as u128,
+                token_amount,
+            )? as u64,
+        };
+        Some(self.split_fee(gross_btc))
+    }
+
+    // Returns the pool UTXOs of the latest state referenced by the given outpoints
+    // These are the inputs the pool has to sign for when a transaction spends them
+    pub(crate) fn find_utxos(&self, outpoints: &[String]) -> Result<Vec<Utxo>, ExchangeError> {
+        let current = self.current_state().utxos;
+        outpoints
+            .iter()
+            .map(|outpoint| {
+                current
+                    .iter()
+                    .find(|u| utxo_outpoint(u) == *outpoint)
+                    .cloned()
+                    .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                        "pool utxo {} not found",
+                        outpoint
+                    )))
+            })
+            .collect()
+    }
+
+    // Computes the pool UTXO set after spending `spent` and receiving `received`
+    // The satoshis moved by the UTXOs must match `btc_delta`, the change of btc_balance,
+    // so the balance keeps tracking what the pool actually holds
+    fn next_utxos(
+        &self,
+        spent: &[String],
+        received: Vec<Utxo>,
+        btc_delta: i128,
+    ) -> Result<Vec<Utxo>, ExchangeError> {
+        let spent_sats: i128 = self.find_utxos(spent)?.iter().map(|u| u.sats as i128).sum();
+        let received_sats: i128 = received.iter().map(|u| u.sats as i128).sum();
+        (received_sats - spent_sats == btc_delta)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(
+                "pool utxo values mismatch with the btc amount".to_string(),
+            ))?;
+        let current = self.current_state().utxos;
+        let mut utxos: Vec<Utxo> = current
+            .iter()
+            .filter(|u| !spent.contains(&utxo_outpoint(u)))
+            .cloned()
+            .collect();
+        utxos.extend(received);
+        Ok(utxos)
+    }
+
+    // How many more tokens can be minted before hitting `max_supply`, None if uncapped
+    pub fn remaining_supply(&self) -> Option<u128> {
+        let total_supply = self.current_state().total_supply;
+        self.meta
+            .max_supply
+            .map(|cap| cap.saturating_sub(total_supply))
+    }
+
+    // Get current exchange rate
+    // Precedence: the operator's rate > the rate of the latest state > the meta default
+    // The operator's rate lives outside the state chain, so rollback and finalize keep it
     pub fn get_current_exchange_rate(&self) -> u64 {
-        self.states
-            .last()
-            .and_then(|state| state.exchange_rate)
+        self.operator_rate
+            .or_else(|| self.states.last().and_then(|state| state.exchange_rate))
             .unwrap_or(self.meta.exchange_rate)
     }
 
@@ -127,11 +523,12 @@ impl CanvasToken {
         &self,
         txid: Txid,
         nonce: u64,
-        _token_utxo_spent: Vec<String>,
-        _token_utxo_received: Vec<Utxo>,
+        token_utxo_spent: Vec<String>,
+        token_utxo_received: Vec<Utxo>,
         input_coins: Vec<InputCoin>,
         output_coins: Vec<OutputCoin>,
         exchange_rate: u64,  // 新增：交易时使用的汇率
+        min_token_out: Option<u128>, // 用户可接受的最少代币数量
     ) -> Result<(TokenState, u128), ExchangeError> {
         // Verify transaction structure (1 input coin BTC, 1 output coin Token)
         (input_coins.len() == 1 && output_coins.len() == 1)
@@ -140,6 +537,14 @@ impl CanvasToken {
                 "invalid input/output_coins, buy_token requires 1 BTC input and 1 Token output".to_string(),
             ))?;
 
+        // A buy only pays into the pool; nothing signs pool inputs for it, so it may not spend any
+        token_utxo_spent
+            .is_empty()
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(
+                "buy_token may not spend pool utxos".to_string(),
+            ))?;
+
         let btc_input = &input_coins[0].coin;
         let token_output = &output_coins[0].coin;
 
@@ -157,8 +562,8 @@ impl CanvasToken {
                 "invalid output_coin, wrong token type".to_string(),
             ))?;
 
-        // Get the current token state or use default if empty
-        let mut state = self.states.last().cloned().unwrap_or_default();
+        // Get the current token state, the genesis state for a never-traded token
+        let mut state = self.current_state();
 
         // Verify nonce matches to prevent replay attacks
         (state.nonce == nonce)
@@ -167,12 +572,29 @@ impl CanvasToken {
 
         // Verify minimum BTC amount
         let btc_amount: u64 = btc_input.value.try_into().map_err(|_| ExchangeError::Overflow)?;
-        (btc_amount >= MIN_BTC_VALUE)
+        (btc_amount >= self.min_tx_value())
+            .then(|| ())
+            .ok_or(ExchangeError::TooSmallFunds)?;
+
+        // A constant-product pool with nothing in reserve has nothing to sell
+        (self.meta.pricing_mode == PricingMode::FixedRate || state.token_reserve > 0)
+            .then(|| ())
+            .ok_or(ExchangeError::InsufficientTokenReserve)?;
+
+        // Calculate expected token amount using provided exchange rate, or the reserves for AMM pools
+        let (expected_token_amount, fee) = self
+            .quote_buy(&state, btc_amount, exchange_rate)
+            .ok_or(ExchangeError::Overflow)?;
+
+        // Paying for nothing would only move BTC into the pool
+        (expected_token_amount > 0)
             .then(|| ())
             .ok_or(ExchangeError::TooSmallFunds)?;
 
-        // Calculate expected token amount using provided exchange rate
-        let expected_token_amount = self.calculate_buy_amount_with_rate(btc_amount, exchange_rate);
+        // Reject the fill if the state moved and it is now worse than the user's floor
+        (expected_token_amount >= min_token_out.unwrap_or_default())
+            .then(|| ())
+            .ok_or(ExchangeError::SlippageExceeded)?;
         
         // Verify the output token amount matches calculation
         (token_output.value == expected_token_amount)
@@ -186,69 +608,127 @@ impl CanvasToken {
             .checked_add(btc_amount)
             .ok_or(ExchangeError::Overflow)?;
 
+        // Constant-product pools hand the bought tokens out of their reserve
+        if self.meta.pricing_mode == PricingMode::ConstantProduct {
+            state.token_reserve = state
+                .token_reserve
+                .checked_sub(expected_token_amount)
+                .ok_or(ExchangeError::Overflow)?;
+        }
+
+        // The fee stays in the pool but is earmarked for the operator
+        state.fees_accrued = state
+            .fees_accrued
+            .checked_add(fee)
+            .ok_or(ExchangeError::Overflow)?;
+
+        // The bought tokens are minted, within the supply cap if there is one
+        state.total_supply = state
+            .total_supply
+            .checked_add(expected_token_amount)
+            .ok_or(ExchangeError::Overflow)?;
+        (self.meta.max_supply.is_none_or(|cap| state.total_supply <= cap))
+            .then(|| ())
+            .ok_or(ExchangeError::SupplyCapExceeded)?;
+
         // Update the state
         state.btc_balance = new_btc_balance;
         state.nonce += 1;
         state.id = Some(txid);
         state.exchange_rate = Some(exchange_rate);
-        state.timestamp = ic_cdk::api::time();
+        state.timestamp = super::now();
+        state.utxos = self.next_utxos(&token_utxo_spent, token_utxo_received, btc_amount as i128)?;
+        state.record_trade(btc_amount);
 
         Ok((state, expected_token_amount))
     }
 
     // Validates a sell token transaction (Token burn -> BTC)
     // If valid, generates the new token state that would result from executing the transaction
-    // Returns the new state and BTC amount to pay
+    // A second, token output may hand part of the input back to the seller as change;
+    // only the rest is burned and priced
+    // Returns the new state, the BTC amount to pay and the token amount burned
     pub(crate) fn validate_sell_token(
         &self,
         txid: Txid,
         nonce: u64,
-        _token_utxo_spent: Vec<String>,
-        _token_utxo_received: Vec<Utxo>,
+        token_utxo_spent: Vec<String>,
+        token_utxo_received: Vec<Utxo>,
         input_coins: Vec<InputCoin>,
         output_coins: Vec<OutputCoin>,
         exchange_rate: u64,  
-    ) -> Result<(TokenState, u64), ExchangeError> {
-        (input_coins.len() == 1 && output_coins.len() == 1)
+        min_btc_out: Option<u64>, // 用户可接受的最少 BTC 数量
+        pending_payouts: u64,     // 已通过校验、尚未提交的其他卖单应付的 BTC
+    ) -> Result<(TokenState, u64, u128), ExchangeError> {
+        (input_coins.len() == 1 && (1..=2).contains(&output_coins.len()))
             .then(|| ())
             .ok_or(ExchangeError::InvalidSignPsbtArgs(
-                "invalid input/output_coins, sell_token requires 1 Token input and 1 BTC output".to_string(),
+                "invalid input/output_coins, sell_token requires 1 Token input, 1 BTC output and optionally 1 Token change output".to_string(),
             ))?;
 
         let token_input = &input_coins[0].coin;
-        let btc_output = &output_coins[0].coin;
-
-        // Verify input coin is the correct token
-        (token_input.id == self.token_id())
+        let (btc_outputs, change_outputs): (Vec<&OutputCoin>, Vec<&OutputCoin>) =
+            output_coins.iter().partition(|o| o.coin.id == CoinId::btc());
+        (btc_outputs.len() == 1)
             .then(|| ())
             .ok_or(ExchangeError::InvalidSignPsbtArgs(
-                "invalid input_coin, wrong token type".to_string(),
+                "invalid output_coins, sell_token requires exactly 1 BTC output".to_string(),
             ))?;
+        let btc_output = &btc_outputs[0].coin;
+
+        // Change must be some of the sold token, going back to the address it came from
+        let change = match change_outputs.first() {
+            None => 0,
+            Some(change) => {
+                (change.coin.id == self.token_id() && change.to == input_coins[0].from)
+                    .then(|| ())
+                    .ok_or(ExchangeError::InvalidSignPsbtArgs(
+                        "invalid change output, it must return the input token to the seller".to_string(),
+                    ))?;
+                change.coin.value
+            }
+        };
 
-        // Verify output coin is BTC
-        (btc_output.id == CoinId::btc())
+        // Verify input coin is the correct token
+        (token_input.id == self.token_id())
             .then(|| ())
             .ok_or(ExchangeError::InvalidSignPsbtArgs(
-                "invalid output_coin, sell_token requires BTC output".to_string(),
+                "invalid input_coin, wrong token type".to_string(),
             ))?;
 
-        // Get the current token state
-        let mut state = self.states.last().cloned().ok_or(ExchangeError::EmptyToken)?;
+        // Get the current token state; a never-traded token has no BTC to pay out, so a sell
+        // against its genesis state fails the reserve checks below
+        let mut state = self.current_state();
 
         // Verify nonce matches to prevent replay attacks
         (state.nonce == nonce)
             .then(|| ())
             .ok_or(ExchangeError::TokenStateExpired(state.nonce))?;
 
-        // Calculate expected BTC amount using provided exchange rate
-        let token_amount = token_input.value;
-        let expected_btc_amount = self.calculate_sell_amount_with_rate(token_amount, exchange_rate);
+        // Only the tokens not handed back are sold, and something has to be
+        let token_amount = token_input
+            .value
+            .checked_sub(change)
+            .filter(|amount| *amount > 0)
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(
+                "invalid change output, it must be less than the token input".to_string(),
+            ))?;
+
+        // Calculate expected BTC amount using provided exchange rate, or the reserves for AMM pools
+        let (expected_btc_amount, fee) = self
+            .quote_sell(&state, token_amount, exchange_rate)
+            .ok_or(ExchangeError::Overflow)?;
 
         // Verify minimum BTC amount
-        (expected_btc_amount >= MIN_BTC_VALUE)
+        (expected_btc_amount >= self.min_tx_value())
             .then(|| ())
             .ok_or(ExchangeError::TooSmallFunds)?;
 
+        // Reject the fill if the state moved and it is now worse than the user's floor
+        (expected_btc_amount >= min_btc_out.unwrap_or_default())
+            .then(|| ())
+            .ok_or(ExchangeError::SlippageExceeded)?;
+
         // Verify the output BTC amount matches calculation
         let btc_amount: u64 = btc_output.value.try_into().map_err(|_| ExchangeError::Overflow)?;
         (btc_amount == expected_btc_amount)
@@ -257,34 +737,130 @@ impl CanvasToken {
                 "BTC output amount mismatch with exchange rate".to_string(),
             ))?;
 
-        // Verify sufficient BTC balance for payment
-        (state.btc_balance >= expected_btc_amount)
+        // Verify sufficient BTC balance for payment, never paying out accrued fees
+        // nor the BTC already promised to sells that are still being signed
+        (state.btc_reserve().saturating_sub(pending_payouts) >= expected_btc_amount)
             .then(|| ())
             .ok_or(ExchangeError::InsufficientBtc)?;
 
+        // Refuse a single sell draining too much of the pool
+        self.check_trade_size(&state, expected_btc_amount)?;
+
         // Update BTC balance (subtract paid BTC)
         let new_btc_balance = state.btc_balance
             .checked_sub(expected_btc_amount)
             .ok_or(ExchangeError::Overflow)?;
 
+        // Constant-product pools take the sold tokens back into their reserve
+        if self.meta.pricing_mode == PricingMode::ConstantProduct {
+            state.token_reserve = state
+                .token_reserve
+                .checked_add(token_amount)
+                .ok_or(ExchangeError::Overflow)?;
+        }
+
+        // The fee stays in the pool but is earmarked for the operator
+        state.fees_accrued = state
+            .fees_accrued
+            .checked_add(fee)
+            .ok_or(ExchangeError::Overflow)?;
+
+        // The sold tokens are burned; burning more than was ever minted means the
+        // supply accounting is off, so the sell is refused rather than clamped
+        state.total_supply = state
+            .total_supply
+            .checked_sub(token_amount)
+            .ok_or(ExchangeError::Overflow)?;
+
         // Update the state
         state.btc_balance = new_btc_balance;
         state.nonce += 1;
         state.id = Some(txid);
         state.exchange_rate = Some(exchange_rate);
-        state.timestamp = ic_cdk::api::time();
+        state.timestamp = super::now();
+        state.utxos = self.next_utxos(
+            &token_utxo_spent,
+            token_utxo_received,
+            -(expected_btc_amount as i128),
+        )?;
+        state.record_trade(expected_btc_amount);
+
+        Ok((state, expected_btc_amount, token_amount))
+    }
+
+    // Validates an add_liquidity transaction (BTC donated to the pool, nothing minted)
+    // Lets operators seed btc_balance so sells can be honored early
+    // For constant-product pools the donation raises the token price
+    // Returns the new state and the BTC amount added
+    pub(crate) fn validate_add_liquidity(
+        &self,
+        txid: Txid,
+        nonce: u64,
+        token_utxo_spent: Vec<String>,
+        token_utxo_received: Vec<Utxo>,
+        input_coins: Vec<InputCoin>,
+        output_coins: Vec<OutputCoin>,
+    ) -> Result<(TokenState, u64), ExchangeError> {
+        // Verify transaction structure (1 input coin BTC, no output coin)
+        (input_coins.len() == 1 && output_coins.is_empty())
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(
+                "invalid input/output_coins, add_liquidity requires 1 BTC input and no output".to_string(),
+            ))?;
+
+        // Like a buy, a donation only pays into the pool and may not spend pool utxos
+        token_utxo_spent
+            .is_empty()
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(
+                "add_liquidity may not spend pool utxos".to_string(),
+            ))?;
 
-        Ok((state, expected_btc_amount))
+        let btc_input = &input_coins[0].coin;
+
+        // Verify input coin is BTC
+        (btc_input.id == CoinId::btc())
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(
+                "invalid input_coin, add_liquidity requires BTC".to_string(),
+            ))?;
+
+        // Get the current token state, the genesis state for a never-traded token
+        let mut state = self.current_state();
+
+        // Verify nonce matches to prevent replay attacks
+        (state.nonce == nonce)
+            .then(|| ())
+            .ok_or(ExchangeError::TokenStateExpired(state.nonce))?;
+
+        // Verify minimum BTC amount
+        let btc_amount: u64 = btc_input.value.try_into().map_err(|_| ExchangeError::Overflow)?;
+        (btc_amount >= self.min_tx_value())
+            .then(|| ())
+            .ok_or(ExchangeError::TooSmallFunds)?;
+
+        // Update the state; no tokens are minted and no fee is taken
+        state.btc_balance = state
+            .btc_balance
+            .checked_add(btc_amount)
+            .ok_or(ExchangeError::Overflow)?;
+        state.nonce += 1;
+        state.id = Some(txid);
+        state.exchange_rate = Some(self.get_current_exchange_rate());
+        state.timestamp = super::now();
+        state.utxos = self.next_utxos(&token_utxo_spent, token_utxo_received, btc_amount as i128)?;
+
+        Ok((state, btc_amount))
     }
 
     // Rollback the token state to before the specified transaction
     // Removes the state created by txid and all subsequent states
     pub(crate) fn rollback(&mut self, txid: Txid) -> Result<(), ExchangeError> {
         let idx = self
-            .states
-            .iter()
-            .position(|state| state.id == Some(txid))
+            .state_position(txid)
             .ok_or(ExchangeError::InvalidState("txid not found".to_string()))?;
+        let cutoff = self.states[idx].nonce;
+        self.state_index.retain(|_, nonce| *nonce < cutoff);
         if idx == 0 {
             self.states.clear();
             return Ok(());
@@ -297,20 +873,823 @@ impl CanvasToken {
     // Removes all states before the specified transaction
     pub(crate) fn finalize(&mut self, txid: Txid) -> Result<(), ExchangeError> {
         let idx = self
-            .states
-            .iter()
-            .position(|state| state.id == Some(txid))
+            .state_position(txid)
             .ok_or(ExchangeError::InvalidState("txid not found".to_string()))?;
-        if idx == 0 {
-            return Ok(());
-        }
-        self.states.rotate_left(idx);
-        self.states.truncate(self.states.len() - idx);
+        let cutoff = self.states[idx].nonce;
+        self.state_index.retain(|_, nonce| *nonce >= cutoff);
+        self.states.drain(..idx);
         Ok(())
     }
 
+    // Builds the state that switches the pool to `exchange_rate`
+    // Balances and UTXOs carry over; the nonce is bumped so quotes made at the old rate go stale
+    pub(crate) fn rate_update_state(&self, exchange_rate: u64) -> TokenState {
+        let mut state = self.current_state();
+        state.nonce += 1;
+        state.id = None;
+        state.exchange_rate = Some(exchange_rate);
+        state.timestamp = super::now();
+        state
+    }
+
     // Adds a new TokenState to the chain after a transaction is executed
-    pub(crate) fn commit(&mut self, state: TokenState) {
+    // Refused once the chain holds `max_pending_states` states, until finalize catches up
+    // Also accumulates the previous state's rate over the time it was current, for the TWAP
+    pub(crate) fn commit(&mut self, mut state: TokenState) -> Result<(), ExchangeError> {
+        ((self.states.len() as u64) < super::get_max_pending_states())
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState(
+                "too many pending states, waiting for confirmations".to_string(),
+            ))?;
+        if let Some(prev) = self.states.last() {
+            let elapsed = state.timestamp.saturating_sub(prev.timestamp);
+            state.cumulative_rate_time = prev
+                .cumulative_rate_time
+                .saturating_add(self.state_rate(prev) as u128 * elapsed as u128);
+        }
+        // A transaction with several intentions on this pool is indexed by its first state,
+        // so rollback and finalize treat its states as one
+        if let Some(txid) = state.id {
+            self.state_index.entry(txid).or_insert(state.nonce);
+        }
         self.states.push(state);
+        Ok(())
+    }
+
+    // Time-weighted average rate over the `window_ns` nanoseconds before `now`
+    // The retained state chain must reach back over the whole window, since finalized
+    // states are pruned
+    pub fn twap(&self, now: u64, window_ns: u64) -> Result<u64, ExchangeError> {
+        (window_ns > 0)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState("window must not be empty".to_string()))?;
+        let start = now
+            .checked_sub(window_ns)
+            .ok_or(ExchangeError::InvalidState("window exceeds the recorded history".to_string()))?;
+        let first = self.states.first().ok_or(ExchangeError::EmptyToken)?;
+        (first.timestamp <= start)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState("window exceeds the recorded history".to_string()))?;
+
+        // Saturating, as states written before the accumulator existed all carry zero
+        let total = self
+            .cumulative_rate_time_at(now)
+            .saturating_sub(self.cumulative_rate_time_at(start));
+        Ok((total / window_ns as u128) as u64)
+    }
+
+    // Rate-time integral up to `t`, extrapolating from the last state at or before `t`
+    // Callers make sure the first state is not later than `t`
+    fn cumulative_rate_time_at(&self, t: u64) -> u128 {
+        let idx = self.states.partition_point(|s| s.timestamp <= t);
+        let state = &self.states[idx - 1];
+        state
+            .cumulative_rate_time
+            .saturating_add(self.state_rate(state) as u128 * (t - state.timestamp) as u128)
+    }
+
+    // The rate a state priced trades at
+    fn state_rate(&self, state: &TokenState) -> u64 {
+        state.exchange_rate.unwrap_or(self.meta.exchange_rate)
+    }
+
+    // Whether the chain still holds the state created by txid
+    // Once a rollback or an earlier finalize has cut it out, finalizing txid can never succeed
+    pub(crate) fn holds_txid(&self, txid: Txid) -> bool {
+        self.state_position(txid).is_some()
+    }
+
+    // Locates the state created by `txid`
+    // Nonces grow by one per state, so the indexed nonce gives the position directly;
+    // falls back to a scan for states recorded before the index existed
+    // Either way the position is that of a state holding txid, so it always indexes the chain
+    fn state_position(&self, txid: Txid) -> Option<usize> {
+        let first_nonce = self.states.first()?.nonce;
+        self.state_index
+            .get(&txid)
+            .and_then(|nonce| nonce.checked_sub(first_nonce))
+            .map(|idx| idx as usize)
+            .filter(|idx| self.states.get(*idx).is_some_and(|s| s.id == Some(txid)))
+            .or_else(|| self.states.iter().position(|state| state.id == Some(txid)))
+    }
+}
+
+// The smallest BTC amount for which `buys` holds, searched from `estimate`
+// The forward quote only grows with the BTC paid, and the rounded estimate may land on either
+// side of the answer: widen the bracket above it by doubling steps, then bisect
+// Both loops are bounded by the 64 bits of the amount; no amount buying at all is an Overflow
+fn least_buying_amount(
+    estimate: u64,
+    buys: impl Fn(u64) -> Result<bool, ExchangeError>,
+) -> Result<u64, ExchangeError> {
+    let (mut low, mut high, mut step) = (0, estimate, 1u64);
+    while !buys(high)? {
+        low = high.checked_add(1).ok_or(ExchangeError::Overflow)?;
+        high = high.checked_add(step).ok_or(ExchangeError::Overflow)?;
+        step = step.saturating_mul(2);
+    }
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if buys(mid)? {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    Ok(high)
+}
+
+// Formats a UTXO as the `txid:vout` outpoint string used in intentions
+fn utxo_outpoint(utxo: &Utxo) -> String {
+    format!("{}:{}", utxo.txid, utxo.vout)
+}
+
+// Satoshis paid for `token_amount` at `units_per_sat` token units per satoshi, rounded down
+// Returns None for a zero rate or an amount that does not fit in a u64
+fn fixed_rate_btc_out(token_amount: u128, units_per_sat: u128) -> Option<u64> {
+    token_amount
+        .checked_div(units_per_sat)
+        .and_then(|btc| u64::try_from(btc).ok())
+}
+
+// Constant-product (x * y = k) output for swapping `amount_in` into a pool
+// holding `reserve_in` / `reserve_out`: reserve_out * amount_in / (reserve_in + amount_in)
+// Returns None if the input reserve overflows; an empty pool yields zero
+pub(crate) fn constant_product_out(reserve_in: u128, reserve_out: u128, amount_in: u128) -> Option<u128> {
+    let denominator = reserve_in.checked_add(amount_in)?;
+    if denominator == 0 {
+        return Some(0);
+    }
+    mul_div(reserve_out, amount_in, denominator)
+}
+
+// Computes floor(a * b / c) with a 256-bit intermediate product
+// Returns None if c is zero or the quotient does not fit in a u128
+pub(crate) fn mul_div(a: u128, b: u128, c: u128) -> Option<u128> {
+    if c == 0 {
+        return None;
+    }
+    if let Some(product) = a.checked_mul(b) {
+        return Some(product / c);
+    }
+
+    // Schoolbook multiplication on 64-bit limbs into (hi, lo)
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+    let mid = (lo_lo >> 64) + (hi_lo & MASK) + (lo_hi & MASK);
+    let lo = (lo_lo & MASK) | (mid << 64);
+    let hi = hi_hi + (hi_lo >> 64) + (lo_hi >> 64) + (mid >> 64);
+    if hi >= c {
+        return None;
+    }
+
+    // Binary long division of (hi, lo) by c; the remainder always stays below c
+    let mut rem = hi;
+    let mut quotient: u128 = 0;
+    for i in (0..128).rev() {
+        let carry = rem >> 127;
+        rem = (rem << 1) | ((lo >> i) & 1);
+        quotient <<= 1;
+        if carry == 1 || rem >= c {
+            rem = rem.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    Some(quotient)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ree_types::CoinBalance;
+    use std::str::FromStr;
+
+    // A pool selling token 840000:1 at 10 tokens per satoshi, with no states yet
+    pub(crate) fn token(pricing_mode: PricingMode) -> CanvasToken {
+        CanvasToken {
+            states: vec![],
+            meta: TokenMeta {
+                id: CoinId::rune(840_000, 1),
+                symbol: "PIXEL".to_string(),
+                exchange_rate: 10,
+                min_amount: 1,
+                pricing_mode,
+                fee_bps: 0,
+                max_supply: None,
+                decimals: 0,
+                max_trade_fraction_bps: None,
+                min_tx_value: Some(1),
+            },
+            pubkey: Pubkey::from_raw(vec![2; 33]).unwrap(),
+            tweaked: Pubkey::from_raw(vec![3; 33]).unwrap(),
+            addr: "pool".to_string(),
+            paused: false,
+            state_index: Default::default(),
+            operator_rate: None,
+        }
     }
-}
\ No newline at end of file
+
+    pub(crate) fn txid(n: u8) -> Txid {
+        Txid::from_str(&format!("{:02x}", n).repeat(32)).unwrap()
+    }
+
+    // A committed state created by txid(n), the n-th of the chain
+    pub(crate) fn state(n: u8) -> TokenState {
+        TokenState {
+            id: Some(txid(n)),
+            nonce: n as u64,
+            ..Default::default()
+        }
+    }
+
+    // `token(pricing_mode)` after a first trade left it 100_000 sats in one UTXO,
+    // with 1_000_000 tokens minted and as many in reserve
+    pub(crate) fn funded(pricing_mode: PricingMode) -> CanvasToken {
+        let mut pool = token(pricing_mode);
+        pool.commit(TokenState {
+            btc_balance: 100_000,
+            utxos: vec![utxo(txid(1), 100_000)],
+            token_reserve: 1_000_000,
+            total_supply: 1_000_000,
+            exchange_rate: Some(10),
+            ..state(1)
+        })
+        .unwrap();
+        pool
+    }
+
+    pub(crate) fn utxo(txid: Txid, sats: u64) -> Utxo {
+        Utxo {
+            txid,
+            vout: 0,
+            coins: vec![],
+            sats,
+        }
+    }
+
+    pub(crate) fn input(id: CoinId, value: u128) -> Vec<InputCoin> {
+        vec![InputCoin {
+            from: "user".to_string(),
+            coin: CoinBalance { id, value },
+        }]
+    }
+
+    pub(crate) fn output(id: CoinId, value: u128) -> OutputCoin {
+        OutputCoin {
+            to: "user".to_string(),
+            coin: CoinBalance { id, value },
+        }
+    }
+
+    // Sells `token_amount` against `pool`, paying out of its single UTXO
+    // and sending the change back to the pool
+    pub(crate) fn sell(
+        pool: &CanvasToken,
+        token_amount: u128,
+        btc_out: u64,
+    ) -> Result<(TokenState, u64, u128), ExchangeError> {
+        let state = pool.current_state();
+        let spent = state.utxos[0].clone();
+        pool.validate_sell_token(
+            txid(state.nonce as u8 + 1),
+            state.nonce,
+            vec![utxo_outpoint(&spent)],
+            vec![utxo(txid(state.nonce as u8 + 1), spent.sats - btc_out)],
+            input(pool.token_id(), token_amount),
+            vec![output(CoinId::btc(), btc_out as u128)],
+            pool.get_current_exchange_rate(),
+            None,
+            0,
+        )
+    }
+
+    #[test]
+    fn sell_burns_the_sold_tokens() {
+        let pool = funded(PricingMode::FixedRate);
+        let (state, btc_amount, burned) = sell(&pool, 1_000, 100).unwrap();
+        assert_eq!((btc_amount, burned), (100, 1_000));
+        assert_eq!(state.total_supply, 999_000);
+        assert_eq!(state.btc_balance, 99_900);
+        assert_eq!(state.utxos, vec![utxo(txid(2), 99_900)]);
+    }
+
+    #[test]
+    fn sell_refuses_to_burn_more_than_the_supply() {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.states[0].total_supply = 500;
+        assert!(matches!(sell(&pool, 1_000, 100), Err(ExchangeError::Overflow)));
+    }
+
+    // The cost is the least BTC whose forward quote buys at least the amount
+    fn assert_least_cost(pool: &CanvasToken, token_amount: u128) {
+        let state = pool.current_state();
+        let rate = pool.get_current_exchange_rate();
+        let cost = pool.calculate_buy_cost(token_amount).unwrap();
+        assert!(pool.quote_buy(&state, cost, rate).unwrap().0 >= token_amount);
+        assert!(pool.quote_buy(&state, cost - 1, rate).unwrap().0 < token_amount);
+    }
+
+    #[test]
+    fn buy_cost_inverts_the_buy_quote() {
+        for pricing_mode in [PricingMode::FixedRate, PricingMode::ConstantProduct] {
+            for fee_bps in [0, 30, 9_999] {
+                let mut pool = funded(pricing_mode);
+                pool.meta.fee_bps = fee_bps;
+                for token_amount in [1, 9, 10, 11, 12_345, 999_999] {
+                    assert_least_cost(&pool, token_amount);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn buy_cost_refuses_what_the_reserve_cannot_cover() {
+        let pool = funded(PricingMode::ConstantProduct);
+        assert!(pool.calculate_buy_cost(1_000_000).is_err());
+        let pool = funded(PricingMode::FixedRate);
+        assert!(matches!(pool.calculate_buy_cost(u128::MAX), Err(ExchangeError::Overflow)));
+    }
+
+    #[test]
+    fn the_least_buying_amount_stops_at_the_top_of_the_range() {
+        for estimate in [0, 1_000, 5_000] {
+            assert_eq!(least_buying_amount(estimate, |btc| Ok(btc >= 1_234)).ok(), Some(1_234));
+        }
+        assert_eq!(least_buying_amount(u64::MAX, |btc| Ok(btc == u64::MAX)).ok(), Some(u64::MAX));
+        for estimate in [0, u64::MAX - 1, u64::MAX] {
+            assert!(matches!(least_buying_amount(estimate, |_| Ok(false)), Err(ExchangeError::Overflow)));
+        }
+    }
+
+    // Buys with `btc_amount` sats at the current rate, expecting `token_amount` tokens out
+    pub(crate) fn buy(
+        pool: &CanvasToken,
+        btc_amount: u64,
+        token_amount: u128,
+    ) -> Result<(TokenState, u128), ExchangeError> {
+        let state = pool.current_state();
+        pool.validate_buy_token(
+            txid(state.nonce as u8 + 1),
+            state.nonce,
+            vec![],
+            vec![utxo(txid(state.nonce as u8 + 1), btc_amount)],
+            input(CoinId::btc(), btc_amount as u128),
+            vec![output(pool.token_id(), token_amount)],
+            pool.get_current_exchange_rate(),
+            None,
+        )
+    }
+
+    #[test]
+    fn buy_mints_at_the_quoted_amount() {
+        let pool = funded(PricingMode::FixedRate);
+        let (state, minted) = buy(&pool, 1_000, 10_000).unwrap();
+        assert_eq!(minted, 10_000);
+        assert_eq!((state.btc_balance, state.total_supply), (101_000, 1_010_000));
+        assert!(matches!(buy(&pool, 1_000, 9_999), Err(ExchangeError::InvalidSignPsbtArgs(_))));
+
+        let pool = funded(PricingMode::ConstantProduct);
+        let (state, minted) = buy(&pool, 100_000, 500_000).unwrap();
+        assert_eq!(minted, 500_000);
+        assert_eq!((state.btc_balance, state.token_reserve), (200_000, 500_000));
+    }
+
+    #[test]
+    fn constant_product_buy_needs_tokens_in_reserve() {
+        let mut pool = funded(PricingMode::ConstantProduct);
+        pool.states[0].token_reserve = 10;
+        assert!(matches!(buy(&pool, 1_000, 0), Err(ExchangeError::TooSmallFunds)));
+        pool.states[0].token_reserve = 0;
+        assert!(matches!(buy(&pool, 1_000, 0), Err(ExchangeError::InsufficientTokenReserve)));
+    }
+
+    #[test]
+    fn buy_may_not_spend_pool_utxos() {
+        let pool = funded(PricingMode::FixedRate);
+        let spent = utxo_outpoint(&pool.current_state().utxos[0]);
+        let result = pool.validate_buy_token(
+            txid(2),
+            1,
+            vec![spent],
+            vec![utxo(txid(2), 1_000)],
+            input(CoinId::btc(), 1_000),
+            vec![output(pool.token_id(), 10_000)],
+            10,
+            None,
+        );
+        assert!(matches!(result, Err(ExchangeError::InvalidSignPsbtArgs(_))));
+    }
+
+    #[test]
+    fn add_liquidity_only_pays_into_the_pool() {
+        let pool = funded(PricingMode::ConstantProduct);
+        let add = |spent: Vec<String>| {
+            pool.validate_add_liquidity(
+                txid(2),
+                1,
+                spent,
+                vec![utxo(txid(2), 5_000)],
+                input(CoinId::btc(), 5_000),
+                vec![],
+            )
+        };
+        let (state, btc_amount) = add(vec![]).unwrap();
+        assert_eq!((btc_amount, state.btc_balance), (5_000, 105_000));
+        assert_eq!((state.token_reserve, state.total_supply), (1_000_000, 1_000_000));
+
+        let spent = utxo_outpoint(&pool.current_state().utxos[0]);
+        assert!(matches!(add(vec![spent]), Err(ExchangeError::InvalidSignPsbtArgs(_))));
+    }
+
+    #[test]
+    fn fixed_rate_trades_have_no_price_impact() {
+        // A never-traded pool holds no token reserve, which fixed-rate buys do not need
+        let mut pool = token(PricingMode::FixedRate);
+        pool.meta.fee_bps = 100;
+        let impact = pool.estimate_price_impact(Side::Buy, 10_000).unwrap();
+        assert_eq!(impact.spot_rate, 10 * PRICE_IMPACT_RATE_SCALE);
+        assert_eq!(impact.post_trade_rate, impact.spot_rate);
+        assert_eq!(impact.effective_rate, 99_000 * PRICE_IMPACT_RATE_SCALE / 10_000);
+        assert_eq!(impact.impact_bps, 0);
+
+        let pool = funded(PricingMode::FixedRate);
+        let impact = pool.estimate_price_impact(Side::Sell, 10_000).unwrap();
+        assert_eq!((impact.effective_rate, impact.impact_bps), (10 * PRICE_IMPACT_RATE_SCALE, 0));
+    }
+
+    #[test]
+    fn constant_product_trades_move_the_price() {
+        let pool = funded(PricingMode::ConstantProduct);
+        let impact = pool.estimate_price_impact(Side::Buy, 100_000).unwrap();
+        assert_eq!(impact.spot_rate, 10 * PRICE_IMPACT_RATE_SCALE);
+        assert_eq!(impact.effective_rate, 5 * PRICE_IMPACT_RATE_SCALE);
+        assert_eq!(impact.post_trade_rate, 25 * PRICE_IMPACT_RATE_SCALE / 10);
+        assert_eq!(impact.impact_bps, 5_000);
+
+        let impact = pool.estimate_price_impact(Side::Sell, 1_000_000).unwrap();
+        assert_eq!(impact.effective_rate, 20 * PRICE_IMPACT_RATE_SCALE);
+        assert_eq!(impact.post_trade_rate, 40 * PRICE_IMPACT_RATE_SCALE);
+        assert_eq!(impact.impact_bps, 10_000);
+    }
+
+    #[test]
+    fn price_impact_refuses_trades_too_small_to_fill() {
+        let pool = funded(PricingMode::ConstantProduct);
+        assert!(matches!(pool.estimate_price_impact(Side::Sell, 1), Err(ExchangeError::TooSmallFunds)));
+        let pool = funded(PricingMode::FixedRate);
+        assert!(matches!(pool.estimate_price_impact(Side::Buy, 0), Err(ExchangeError::TooSmallFunds)));
+    }
+
+    #[test]
+    fn trade_size_is_bounded_by_the_reserve_without_fees() {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.meta.max_trade_fraction_bps = Some(5_000);
+        pool.states[0].fees_accrued = 20_000;
+        let state = pool.current_state();
+        assert!(pool.check_trade_size(&state, 40_000).is_ok());
+        assert!(matches!(pool.check_trade_size(&state, 40_001), Err(ExchangeError::TradeTooLarge)));
+        assert!(matches!(sell(&pool, 450_000, 45_000), Err(ExchangeError::TradeTooLarge)));
+    }
+
+    #[test]
+    fn empty_pool_reads_the_genesis_state() {
+        let mut pool = token(PricingMode::FixedRate);
+        pool.meta.max_supply = Some(100);
+        assert_eq!(pool.current_state(), pool.genesis_state());
+        assert_eq!(pool.current_state().exchange_rate, Some(10));
+        assert!(pool.check_expected_nonce(Some(0)).is_ok());
+        assert!(matches!(pool.check_expected_nonce(Some(1)), Err(ExchangeError::StaleNonce(1, 0))));
+        assert_eq!(pool.remaining_supply(), Some(100));
+        assert!(pool.find_utxos(&[]).unwrap().is_empty());
+    }
+
+    // A pool whose chain holds the states of txid(1) to txid(n)
+    fn chain(n: u8) -> CanvasToken {
+        let mut pool = token(PricingMode::FixedRate);
+        for i in 1..=n {
+            pool.commit(state(i)).unwrap();
+        }
+        pool
+    }
+
+    fn nonces(pool: &CanvasToken) -> Vec<u64> {
+        pool.states.iter().map(|s| s.nonce).collect()
+    }
+
+    #[test]
+    fn finalize_at_either_end_of_the_chain() {
+        let mut pool = chain(3);
+        pool.finalize(txid(1)).unwrap();
+        assert_eq!(nonces(&pool), vec![1, 2, 3]);
+        pool.finalize(txid(3)).unwrap();
+        assert_eq!(nonces(&pool), vec![3]);
+        assert_eq!(pool.state_index.keys().collect::<Vec<_>>(), vec![&txid(3)]);
+        pool.finalize(txid(3)).unwrap();
+        assert_eq!(nonces(&pool), vec![3]);
+    }
+
+    #[test]
+    fn rollback_at_either_end_of_the_chain() {
+        let mut pool = chain(3);
+        pool.rollback(txid(3)).unwrap();
+        assert_eq!(nonces(&pool), vec![1, 2]);
+        pool.rollback(txid(1)).unwrap();
+        assert!(pool.states.is_empty() && pool.state_index.is_empty());
+    }
+
+    #[test]
+    fn indexed_lookups_match_the_linear_scan_and_beat_it_on_long_chains() {
+        let long_txid = |n: u64| Txid::from_str(&format!("{:016x}", n).repeat(4)).unwrap();
+        let mut pool = token(PricingMode::FixedRate);
+        for n in 1..=crate::ree::DEFAULT_MAX_PENDING_STATES {
+            pool.commit(TokenState {
+                id: Some(long_txid(n)),
+                nonce: n,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let linear = |pool: &CanvasToken, txid: Txid| pool.states.iter().position(|s| s.id == Some(txid));
+        let txids: Vec<Txid> = (0..=crate::ree::DEFAULT_MAX_PENDING_STATES + 1).map(long_txid).collect();
+
+        for txid in &txids {
+            assert_eq!(pool.state_position(*txid), linear(&pool, *txid));
+        }
+
+        let started = std::time::Instant::now();
+        let indexed: Vec<_> = txids.iter().map(|txid| pool.state_position(*txid)).collect();
+        let indexed_time = started.elapsed();
+        let started = std::time::Instant::now();
+        let scanned: Vec<_> = txids.iter().map(|txid| linear(&pool, *txid)).collect();
+        let scanned_time = started.elapsed();
+        assert_eq!(indexed, scanned);
+        println!("{} lookups: indexed {:?}, linear {:?}", txids.len(), indexed_time, scanned_time);
+        assert!(indexed_time <= scanned_time);
+
+        // Finalize and rollback cut the chain where the linear scan would
+        let mut reference = pool.states.clone();
+        pool.finalize(long_txid(400)).unwrap();
+        reference.drain(..399);
+        pool.rollback(long_txid(900)).unwrap();
+        reference.truncate(500);
+        assert_eq!(pool.states, reference);
+        for txid in &txids {
+            assert_eq!(pool.state_position(*txid), linear(&pool, *txid));
+        }
+    }
+
+    #[test]
+    fn txids_outside_the_chain_are_refused() {
+        let mut pool = chain(2);
+        assert!(matches!(pool.finalize(txid(3)), Err(ExchangeError::InvalidState(_))));
+        assert!(matches!(pool.rollback(txid(3)), Err(ExchangeError::InvalidState(_))));
+        let mut empty = token(PricingMode::FixedRate);
+        assert!(empty.finalize(txid(1)).is_err() && empty.rollback(txid(1)).is_err());
+        assert_eq!(nonces(&pool), vec![1, 2]);
+    }
+
+    #[test]
+    fn rolled_back_and_finalized_txids_leave_the_chain() {
+        let mut pool = token(PricingMode::FixedRate);
+        for n in 1..=3 {
+            pool.commit(state(n)).unwrap();
+        }
+        assert!((1..=3).all(|n| pool.holds_txid(txid(n))));
+
+        pool.rollback(txid(3)).unwrap();
+        assert!(!pool.holds_txid(txid(3)));
+        pool.finalize(txid(2)).unwrap();
+        assert!(!pool.holds_txid(txid(1)));
+        assert!(pool.holds_txid(txid(2)));
+        assert!(pool.finalize(txid(3)).is_err());
+    }
+
+    // Rate 10 (the meta rate) from t=100, 20 from t=200, 40 from t=400
+    fn rate_timeline() -> CanvasToken {
+        let mut pool = token(PricingMode::FixedRate);
+        for (n, timestamp, exchange_rate) in [(1, 100, None), (2, 200, Some(20)), (3, 400, Some(40))] {
+            pool.commit(TokenState {
+                timestamp,
+                exchange_rate,
+                ..state(n)
+            })
+            .unwrap();
+        }
+        pool
+    }
+
+    #[test]
+    fn commit_accumulates_the_previous_rate_over_its_lifetime() {
+        let pool = rate_timeline();
+        let accumulated: Vec<u128> = pool.states.iter().map(|s| s.cumulative_rate_time).collect();
+        assert_eq!(accumulated, vec![0, 10 * 100, 10 * 100 + 20 * 200]);
+    }
+
+    #[test]
+    fn twap_weighs_each_rate_by_its_time_in_the_window() {
+        let pool = rate_timeline();
+        assert_eq!(pool.twap(500, 100).unwrap(), 40);
+        // (20 * 150 + 40 * 100) / 250, starting halfway through a state
+        assert_eq!(pool.twap(500, 250).unwrap(), 28);
+        assert_eq!(pool.twap(500, 400).unwrap(), (10 * 100 + 20 * 200 + 40 * 100) / 400);
+        // The last rate is extrapolated up to `now`
+        assert_eq!(pool.twap(1_000, 500).unwrap(), 40);
+        assert_eq!(pool.twap(300, 100).unwrap(), 20);
+    }
+
+    #[test]
+    fn twap_refuses_windows_beyond_the_history() {
+        let pool = rate_timeline();
+        for (now, window_ns) in [(500, 0), (500, 401), (500, 600)] {
+            assert!(pool.twap(now, window_ns).is_err(), "{} {}", now, window_ns);
+        }
+        assert!(matches!(token(PricingMode::FixedRate).twap(500, 100), Err(ExchangeError::EmptyToken)));
+    }
+
+    #[test]
+    fn a_rate_change_after_the_quote_trips_the_slippage_floor() {
+        let mut pool = funded(PricingMode::FixedRate);
+        let quoted_tokens = pool.calculate_buy_amount(1_000).unwrap();
+        let quoted_btc = pool.calculate_sell_amount(1_000);
+        assert_eq!((quoted_tokens, quoted_btc), (10_000, 100));
+
+        // Buys get fewer tokens at a lower rate, sells less BTC at a higher one
+        let buy_at = |pool: &CanvasToken, token_amount, min_token_out| {
+            pool.validate_buy_token(
+                txid(2),
+                1,
+                vec![],
+                vec![utxo(txid(2), 1_000)],
+                input(CoinId::btc(), 1_000),
+                vec![output(pool.token_id(), token_amount)],
+                pool.get_current_exchange_rate(),
+                min_token_out,
+            )
+        };
+        pool.operator_rate = Some(5);
+        assert!(matches!(buy_at(&pool, 5_000, Some(quoted_tokens)), Err(ExchangeError::SlippageExceeded)));
+        assert!(buy_at(&pool, 5_000, Some(5_000)).is_ok());
+        assert!(buy_at(&pool, 5_000, None).is_ok());
+
+        pool.operator_rate = Some(20);
+        let sell_at = |min_btc_out| {
+            pool.validate_sell_token(
+                txid(2),
+                1,
+                vec![utxo_outpoint(&pool.current_state().utxos[0])],
+                vec![utxo(txid(2), 99_950)],
+                input(pool.token_id(), 1_000),
+                vec![output(CoinId::btc(), 50)],
+                pool.get_current_exchange_rate(),
+                min_btc_out,
+                0,
+            )
+        };
+        assert!(matches!(sell_at(Some(quoted_btc)), Err(ExchangeError::SlippageExceeded)));
+        assert!(sell_at(Some(50)).is_ok());
+    }
+
+    #[test]
+    fn the_fee_is_taken_in_btc_and_accrues_to_the_pool() {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.meta.fee_bps = 100;
+        assert_eq!(pool.calculate_fee(1_000), 10);
+        assert_eq!(pool.calculate_fee(99), 0);
+
+        // 1% of the 1_000 sats paid is kept, the other 990 buy tokens
+        let (state, minted) = buy(&pool, 1_000, 9_900).unwrap();
+        assert_eq!(minted, 9_900);
+        assert_eq!((state.btc_balance, state.fees_accrued), (101_000, 10));
+        assert!(matches!(buy(&pool, 1_000, 10_000), Err(ExchangeError::InvalidSignPsbtArgs(_))));
+
+        // 1_000 tokens are worth 100 sats, 1 of which is kept
+        let (state, paid, _) = sell(&pool, 1_000, 99).unwrap();
+        assert_eq!(paid, 99);
+        assert_eq!((state.btc_balance, state.fees_accrued), (99_901, 1));
+
+        pool.commit(state).unwrap();
+        assert!(pool.attrs().contains("fee_bps:100,fees_accrued:1,"));
+
+        // Without a fee every amount is as before fees existed
+        let pool = funded(PricingMode::FixedRate);
+        let (state, minted) = buy(&pool, 1_000, 10_000).unwrap();
+        assert_eq!((minted, state.btc_balance, state.fees_accrued), (10_000, 101_000, 0));
+        let (state, paid, _) = sell(&pool, 1_000, 100).unwrap();
+        assert_eq!((paid, state.btc_balance, state.fees_accrued), (100, 99_900, 0));
+    }
+
+    #[test]
+    fn sells_round_down_to_whole_satoshis() {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.meta.min_tx_value = Some(100);
+
+        // Exact division leaves nothing over
+        assert_eq!((pool.calculate_sell_amount(1_000), pool.sell_remainder(1_000)), (100, 0));
+        assert_eq!(sell(&pool, 1_000, 100).unwrap().1, 100);
+
+        // The 9 tokens that buy no satoshi are reported, and validation pays what was quoted
+        assert_eq!((pool.calculate_sell_amount(1_009), pool.sell_remainder(1_009)), (100, 9));
+        assert_eq!(pool.calculate_sell_amount_with_rate(1_009, 10), 100);
+        assert_eq!(sell(&pool, 1_009, 100).unwrap().1, 100);
+        assert!(matches!(sell(&pool, 1_009, 101), Err(ExchangeError::InvalidSignPsbtArgs(_))));
+
+        // Rounding down drops the payout below the minimum
+        assert_eq!(pool.calculate_sell_amount(999), 99);
+        assert!(matches!(sell(&pool, 999, 99), Err(ExchangeError::TooSmallFunds)));
+    }
+
+    #[test]
+    fn buy_amounts_that_overflow_are_refused_not_wrapped() {
+        let mut pool = token(PricingMode::FixedRate);
+        pool.meta.exchange_rate = u64::MAX;
+        // u64::MAX * u64::MAX still fits in a u128
+        let max = u64::MAX as u128;
+        assert_eq!(pool.calculate_buy_amount(u64::MAX).ok(), Some(max * max));
+
+        pool.meta.decimals = 1;
+        assert!(matches!(pool.calculate_buy_amount(u64::MAX), Err(ExchangeError::Overflow)));
+        assert!(matches!(pool.calculate_buy_amount_with_rate(u64::MAX, u64::MAX), Err(ExchangeError::Overflow)));
+        assert!(matches!(buy(&pool, u64::MAX, 0), Err(ExchangeError::Overflow)));
+        assert_eq!(pool.calculate_buy_amount(1).ok(), Some(10 * max));
+    }
+
+    #[test]
+    fn buys_may_mint_up_to_the_supply_cap() {
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.meta.max_supply = Some(1_010_000);
+        assert_eq!(pool.remaining_supply(), Some(10_000));
+
+        let (state, minted) = buy(&pool, 1_000, 10_000).unwrap();
+        assert_eq!((minted, state.total_supply), (10_000, 1_010_000));
+        pool.commit(state).unwrap();
+        assert_eq!(pool.remaining_supply(), Some(0));
+        assert!(matches!(buy(&pool, 1, 10), Err(ExchangeError::SupplyCapExceeded)));
+
+        let mut pool = funded(PricingMode::FixedRate);
+        pool.meta.max_supply = Some(1_009_999);
+        assert!(matches!(buy(&pool, 1_000, 10_000), Err(ExchangeError::SupplyCapExceeded)));
+        pool.meta.max_supply = None;
+        assert_eq!(pool.remaining_supply(), None);
+    }
+
+    #[test]
+    fn decimals_scale_amounts_by_the_token_unit() {
+        for exchange_rate in [1, 10, 12_345] {
+            let mut whole = token(PricingMode::FixedRate);
+            whole.meta.exchange_rate = exchange_rate;
+            let scaled = CanvasToken {
+                meta: TokenMeta {
+                    decimals: 8,
+                    ..whole.meta.clone()
+                },
+                ..whole.clone()
+            };
+            let unit = 100_000_000u128;
+
+            let bought = whole.calculate_buy_amount(1_000).unwrap();
+            assert_eq!(bought, 1_000 * exchange_rate as u128);
+            assert_eq!(scaled.calculate_buy_amount(1_000).unwrap(), bought * unit);
+            assert_eq!(scaled.calculate_sell_amount(bought * unit), whole.calculate_sell_amount(bought));
+            assert_eq!(scaled.calculate_buy_cost(bought * unit).unwrap(), whole.calculate_buy_cost(bought).unwrap());
+            // A fraction of a satoshi's worth of units is dust either way
+            assert_eq!(scaled.sell_remainder(bought * unit + 1), 1);
+        }
+    }
+
+    #[test]
+    fn a_partial_sell_burns_only_what_the_change_does_not_return() {
+        let pool = funded(PricingMode::FixedRate);
+        let spent = pool.current_state().utxos[0].clone();
+        // 1_500 tokens go in; `change` comes back and the rest is sold for 100 sats
+        let sell_with_change = |change: OutputCoin| {
+            pool.validate_sell_token(
+                txid(2),
+                1,
+                vec![utxo_outpoint(&spent)],
+                vec![utxo(txid(2), spent.sats - 100)],
+                input(pool.token_id(), 1_500),
+                vec![output(CoinId::btc(), 100), change],
+                pool.get_current_exchange_rate(),
+                None,
+                0,
+            )
+        };
+
+        let (state, btc_amount, burned) = sell_with_change(output(pool.token_id(), 500)).unwrap();
+        assert_eq!((btc_amount, burned), (100, 1_000));
+        assert_eq!(state.total_supply, 999_000);
+
+        let elsewhere = OutputCoin { to: "someone else".to_string(), ..output(pool.token_id(), 500) };
+        assert!(matches!(sell_with_change(elsewhere), Err(ExchangeError::InvalidSignPsbtArgs(_))));
+        assert!(matches!(sell_with_change(output(pool.token_id(), 1_500)), Err(ExchangeError::InvalidSignPsbtArgs(_))));
+        assert!(matches!(sell_with_change(output(pool.token_id(), 2_000)), Err(ExchangeError::InvalidSignPsbtArgs(_))));
+
+        // Without a change output the whole input is sold
+        assert_eq!(sell(&pool, 1_000, 100).unwrap().2, 1_000);
+    }
+}
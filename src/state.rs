@@ -0,0 +1,14 @@
+use ic_stable_structures::{
+    DefaultMemoryImpl,
+    memory_manager::{MemoryManager, VirtualMemory},
+};
+use std::cell::RefCell;
+
+pub(crate) type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+  // MEMORY_MANAGER hands out the virtual memories shared by every module of the canister
+  // MemoryId 0..=2, 4..=13, 15 and 16 are used by the ree module, 3, 14 and 17 by the canvas module
+  pub(crate) static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+      RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}